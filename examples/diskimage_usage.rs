@@ -0,0 +1,38 @@
+//! Create a scratch ASIF image, attach it for the duration of a build step and
+//! let the handle detach it.
+//!
+//! cargo run --example diskimage_usage -- /tmp/scratch.asif
+
+use afpack::{AttachOptions, CreateBlankOptions, DiskImage, FileSystem, Format, MountedImage};
+use std::path::PathBuf;
+
+fn main() -> afpack::Result<()> {
+    let image = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("afpack-example.asif"));
+    let mount_point = image.with_extension("");
+
+    if !image.exists() {
+        let options = CreateBlankOptions::new("1G", FileSystem::APFS, Format::ASIF)
+            .with_volume_name("scratch");
+        DiskImage::create_blank(&image, options)?;
+    }
+
+    let volume = MountedImage::attach(
+        &image,
+        AttachOptions::new().hidden_at(mount_point.display().to_string()),
+    )?;
+    std::fs::write(volume.path().join("hello.txt"), "hello from afpack\n")?;
+    let usage = volume.usage()?;
+    println!(
+        "{} mounted at {}: {} of {} used",
+        image.display(),
+        volume.path().display(),
+        afpack::format_size(usage.used),
+        afpack::format_size(usage.total)
+    );
+
+    // Dropping `volume` would detach it too, `detach` reports failures
+    volume.detach()
+}
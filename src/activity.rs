@@ -0,0 +1,237 @@
+use crate::diskimage::{CommandRunner, DiskImageError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A process with files open on a volume
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OpenProcess {
+    pub pid: u32,
+    pub command: String,
+    /// First file (or working directory) the process has open on the volume
+    pub path: Option<PathBuf>,
+}
+
+impl std::fmt::Display for OpenProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.command, self.pid)?;
+        if let Some(path) = &self.path {
+            write!(f, " {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Processes with files open on the volume mounted at `mount_point`
+#[cfg(target_os = "linux")]
+pub fn processes_using<P: AsRef<Path>>(mount_point: P) -> Result<Vec<OpenProcess>> {
+    processes_using_in(Path::new("/proc"), mount_point)
+}
+
+/// Processes with files open on the volume mounted at `mount_point`
+#[cfg(not(target_os = "linux"))]
+pub fn processes_using<P: AsRef<Path>>(mount_point: P) -> Result<Vec<OpenProcess>> {
+    processes_using_with(&crate::diskimage::SystemRunner, mount_point)
+}
+
+/// Processes with an open file or working directory below `mount_point`, read from
+/// the procfs mounted at `proc`
+///
+/// Processes that cannot be inspected, e.g. other users', are skipped.
+pub fn processes_using_in<P: AsRef<Path>>(proc: &Path, mount_point: P) -> Result<Vec<OpenProcess>> {
+    let mount_point = mount_point.as_ref();
+    let mut processes = Vec::new();
+    for dir in std::fs::read_dir(proc)?.flatten() {
+        let Some(pid) = dir.file_name().to_str().and_then(|p| p.parse().ok()) else {
+            continue;
+        };
+        let fds = std::fs::read_dir(dir.path().join("fd"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|fd| fd.path());
+        let path = std::iter::once(dir.path().join("cwd"))
+            .chain(fds)
+            .filter_map(|link| std::fs::read_link(link).ok())
+            .find(|target| target.starts_with(mount_point));
+        if let Some(path) = path {
+            let command = std::fs::read_to_string(dir.path().join("comm")).unwrap_or_default();
+            processes.push(OpenProcess {
+                pid,
+                command: command.trim().to_string(),
+                path: Some(path),
+            });
+        }
+    }
+    processes.sort_by_key(|p| p.pid);
+    Ok(processes)
+}
+
+/// Processes using a volume, running lsof through `runner`
+///
+/// Given a mount point, lsof reports every open file on that filesystem.
+pub fn processes_using_with<P: AsRef<Path>>(
+    runner: &dyn CommandRunner,
+    mount_point: P,
+) -> Result<Vec<OpenProcess>> {
+    let args = vec![
+        "-F".to_string(),
+        "pcn".to_string(),
+        "--".to_string(),
+        mount_point.as_ref().display().to_string(),
+    ];
+    match runner.run("lsof", &args, None) {
+        Ok(output) => Ok(parse_lsof(&output)),
+        // lsof exits 1 without output when nothing is open
+        Err(DiskImageError::Command(e)) if e.status == Some(1) && e.stderr.trim().is_empty() => {
+            Ok(Vec::new())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse `lsof -F pcn` output, a `p<pid>` line followed by `c<command>` and an
+/// `f<fd>`, `n<path>` pair per open file for each process
+fn parse_lsof(output: &str) -> Vec<OpenProcess> {
+    let mut processes: Vec<OpenProcess> = Vec::new();
+    for line in output.lines() {
+        if let Some(pid) = line.strip_prefix('p').and_then(|p| p.parse().ok()) {
+            processes.push(OpenProcess {
+                pid,
+                command: String::new(),
+                path: None,
+            });
+            continue;
+        }
+        let Some(process) = processes.last_mut() else {
+            continue;
+        };
+        if let Some(command) = line.strip_prefix('c') {
+            process.command = command.to_string();
+        } else if let Some(path) = line.strip_prefix('n') {
+            process.path.get_or_insert_with(|| PathBuf::from(path));
+        }
+    }
+    processes
+}
+
+/// Signal sent to the processes keeping a volume busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Signal {
+    Hup,
+    Int,
+    Term,
+    Kill,
+}
+
+impl std::fmt::Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Signal::Hup => write!(f, "HUP"),
+            Signal::Int => write!(f, "INT"),
+            Signal::Term => write!(f, "TERM"),
+            Signal::Kill => write!(f, "KILL"),
+        }
+    }
+}
+
+/// Send `signal` to `pid` with kill(1), running it through `runner`
+pub fn signal_with(runner: &dyn CommandRunner, pid: u32, signal: Signal) -> Result<()> {
+    let args = vec!["-s".to_string(), signal.to_string(), pid.to_string()];
+    runner.run("kill", &args, None).map(|_| ())
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diskimage::CommandError;
+    use crate::testutil::{FakeRunner, TempDir};
+
+    /// lsof printing `output`, or exiting 1 with it on stderr
+    fn lsof(output: std::result::Result<&'static str, &'static str>) -> FakeRunner {
+        FakeRunner::replying(move |program, args, _| {
+            output
+                .map(str::to_string)
+                .map_err(|e| CommandError::new(program, args, Some(1), "", e).into())
+        })
+    }
+
+    #[test]
+    fn test_processes_using() {
+        let runner = lsof(Ok("p812\ncnode\nfcwd\nn/p/node_modules/.bin\nf23\n\
+             n/p/node_modules/vite/dist/index.js\np907\ncVisual Studio Code\n"));
+        let processes = processes_using_with(&runner, "/p/node_modules").unwrap();
+        assert_eq!(runner.last().unwrap(), "lsof -F pcn -- /p/node_modules");
+        assert_eq!(
+            processes,
+            vec![
+                OpenProcess {
+                    pid: 812,
+                    command: "node".into(),
+                    path: Some("/p/node_modules/.bin".into()),
+                },
+                OpenProcess {
+                    pid: 907,
+                    command: "Visual Studio Code".into(),
+                    path: None,
+                }
+            ]
+        );
+        assert_eq!(processes[0].to_string(), "node (812) /p/node_modules/.bin");
+        assert_eq!(processes[1].to_string(), "Visual Studio Code (907)");
+
+        let idle = lsof(Err(""));
+        assert!(processes_using_with(&idle, "/p/node_modules")
+            .unwrap()
+            .is_empty());
+        let broken = lsof(Err("lsof: status error"));
+        assert!(processes_using_with(&broken, "/p/node_modules").is_err());
+    }
+
+    #[test]
+    fn test_processes_using_proc() {
+        use std::os::unix::fs::symlink;
+
+        let proc = TempDir::new("proc");
+        let process = |pid: u32, comm: &str, cwd: &str, fds: &[&str]| {
+            let dir = proc.join(pid.to_string());
+            std::fs::create_dir_all(dir.join("fd")).unwrap();
+            std::fs::write(dir.join("comm"), format!("{}\n", comm)).unwrap();
+            symlink(cwd, dir.join("cwd")).unwrap();
+            for (fd, target) in fds.iter().enumerate() {
+                symlink(target, dir.join("fd").join(fd.to_string())).unwrap();
+            }
+        };
+        process(
+            907,
+            "rust-analyzer",
+            "/p",
+            &["/dev/null", "/p/target/debug/.fingerprint"],
+        );
+        process(812, "node", "/p/node_modules/.bin", &[]);
+        process(640, "zsh", "/Users/dev", &["/dev/ttys001"]);
+        std::fs::create_dir_all(proc.join("self")).unwrap();
+
+        let processes = processes_using_in(&proc, "/p/target").unwrap();
+        assert_eq!(
+            processes,
+            vec![OpenProcess {
+                pid: 907,
+                command: "rust-analyzer".into(),
+                path: Some("/p/target/debug/.fingerprint".into()),
+            }]
+        );
+        let processes = processes_using_in(&proc, "/p/node_modules").unwrap();
+        assert_eq!(processes[0].to_string(), "node (812) /p/node_modules/.bin");
+        assert!(processes_using_in(&proc, "/p/.build").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+//! afpackd - keeps managed images attached and serves the afpack control socket
+
+use afpack::daemon::{self, Daemon, DiskImageBackend};
+use afpack::registry::Registry;
+use clap::Parser;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(name = "afpackd")]
+#[command(about = "Background daemon monitoring afpack images")]
+#[command(version = "0.1.0")]
+struct Cli {
+    /// Control socket [default: <state dir>/afpackd.sock]
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// Seconds between checks of the attached volumes
+    #[arg(long, default_value_t = 30)]
+    interval: u64,
+
+    /// Only report volumes that vanished instead of reattaching them
+    #[arg(long)]
+    no_remount: bool,
+}
+
+fn main() {
+    afpack::log::init(false);
+    let cli = Cli::parse();
+    let socket = cli.socket.unwrap_or_else(daemon::socket_path);
+
+    let daemon = Arc::new(
+        Daemon::new(Registry::default_path(), Box::new(DiskImageBackend))
+            .with_remount(!cli.no_remount),
+    );
+    let listener = Daemon::bind(&socket).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        exit(1);
+    });
+    if let Err(e) = daemon.check() {
+        eprintln!("afpackd: {}", e);
+    }
+    daemon.spawn_monitor(Duration::from_secs(cli.interval.max(1)));
+    eprintln!("afpackd listening on {}", socket.display());
+    if let Err(e) = daemon.serve(listener) {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,791 @@
+use crate::output::{self, Event};
+use applesauce::compressor::Kind;
+use applesauce::progress::{Progress, SkipReason, Task};
+use applesauce::FileCompressor;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Transparent compression algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    #[default]
+    None,
+    Lzfse,
+    Lzvn,
+    Zlib,
+}
+
+impl Algorithm {
+    /// applesauce compressor kind, `None` when compression is disabled
+    pub fn kind(&self) -> Option<Kind> {
+        match self {
+            Algorithm::None => None,
+            Algorithm::Lzfse => Some(Kind::Lzfse),
+            Algorithm::Lzvn => Some(Kind::Lzvn),
+            Algorithm::Zlib => Some(Kind::Zlib),
+        }
+    }
+
+    pub fn from_kind(kind: Kind) -> Self {
+        match kind {
+            Kind::Lzfse => Algorithm::Lzfse,
+            Kind::Lzvn => Algorithm::Lzvn,
+            Kind::Zlib => Algorithm::Zlib,
+        }
+    }
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Algorithm::None => write!(f, "none"),
+            Algorithm::Lzfse => write!(f, "lzfse"),
+            Algorithm::Lzvn => write!(f, "lzvn"),
+            Algorithm::Zlib => write!(f, "zlib"),
+        }
+    }
+}
+
+/// What gets compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// The image file itself
+    #[default]
+    Image,
+    /// The files inside the mounted volume
+    Source,
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Image => write!(f, "image"),
+            Target::Source => write!(f, "source"),
+        }
+    }
+}
+
+/// When the image file gets compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Schedule {
+    Never,
+    /// Right after attaching, while the volume is mounted
+    #[default]
+    OnPack,
+    /// Once the image is detached
+    OnDetach,
+    /// Once the detached image has not been written for `idle_after` seconds
+    Idle,
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Never => write!(f, "never"),
+            Schedule::OnPack => write!(f, "on-pack"),
+            Schedule::OnDetach => write!(f, "on-detach"),
+            Schedule::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionPolicy {
+    pub algorithm: Algorithm,
+    /// Compression level, only meaningful for zlib (1-9)
+    pub level: u32,
+    /// Files saving less than this fraction of their size are left uncompressed
+    pub min_savings: f64,
+    /// Files compressed at once, `None` for applesauce's one per CPU
+    ///
+    /// applesauce still spreads the blocks of a large file over its pool.
+    pub threads: Option<usize>,
+    pub target: Target,
+    pub schedule: Schedule,
+    /// Seconds without writes before an idle image is compressed
+    pub idle_after: u64,
+    /// Decompress a compressed image before attaching it read-write
+    ///
+    /// Writing to a compressed file makes the kernel decompress all of it on the
+    /// first write; doing it up front avoids that stall inside a build.
+    pub decompress_on_attach: bool,
+}
+
+impl CompressionPolicy {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self {
+            algorithm,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_min_savings(mut self, min_savings: f64) -> Self {
+        self.min_savings = min_savings;
+        self
+    }
+
+    pub fn with_threads(mut self, threads: Option<usize>) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn with_target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    pub fn with_idle_after(mut self, idle_after: u64) -> Self {
+        self.idle_after = idle_after;
+        self
+    }
+
+    pub fn with_decompress_on_attach(mut self, decompress_on_attach: bool) -> Self {
+        self.decompress_on_attach = decompress_on_attach;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.algorithm != Algorithm::None
+    }
+
+    /// Whether compression runs at `schedule`
+    pub fn runs_on(&self, schedule: Schedule) -> bool {
+        self.is_enabled() && self.schedule == schedule
+    }
+
+    /// Whether `path` has gone unmodified long enough for the idle schedule
+    pub fn is_idle(&self, path: &Path) -> bool {
+        std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|elapsed| elapsed.as_secs() >= self.idle_after)
+    }
+
+    /// applesauce's minimum compression ratio (compressed / original size)
+    pub fn minimum_compression_ratio(&self) -> f64 {
+        (1.0 - self.min_savings).clamp(0.0, 1.0)
+    }
+
+    /// Path to compress for an image attached at `afdir`
+    pub fn target_path<'a>(&self, image: &'a Path, afdir: &'a Path) -> &'a Path {
+        match self.target {
+            Target::Image => image,
+            Target::Source => afdir,
+        }
+    }
+
+    /// Compress `paths` according to this policy
+    pub fn compress<'a, P>(
+        &self,
+        paths: impl IntoIterator<Item = &'a Path>,
+        progress: &P,
+    ) -> CompressionStats
+    where
+        P: Progress + Send + Sync,
+        P::Task: Send + Sync + 'static,
+    {
+        let Some(kind) = self.algorithm.kind() else {
+            return CompressionStats::default();
+        };
+        let ratio = self.minimum_compression_ratio();
+        let mut compressor = FileCompressor::new();
+        let stats = match self.threads {
+            Some(threads) => {
+                let progress = Limited::new(progress, threads);
+                compressor.recursive_compress(paths, kind, ratio, self.level, &progress, true)
+            }
+            None => compressor.recursive_compress(paths, kind, ratio, self.level, progress, true),
+        };
+        CompressionStats::from(&stats)
+    }
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::None,
+            level: 5,
+            min_savings: 0.05,
+            threads: None,
+            target: Target::Image,
+            schedule: Schedule::OnPack,
+            idle_after: 30 * 60,
+            decompress_on_attach: false,
+        }
+    }
+}
+
+/// Outcome of a compression run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct CompressionStats {
+    pub files: u64,
+    /// Logical size of all files
+    pub total_size: u64,
+    /// Allocated size before compressing
+    pub size_before: u64,
+    /// Allocated size after compressing
+    pub size_after: u64,
+    pub compressed_files: u64,
+    pub incompressible_files: u64,
+}
+
+impl CompressionStats {
+    /// Fraction of the logical size saved on disk
+    pub fn savings(&self) -> f64 {
+        if self.total_size == 0 {
+            return 0.0;
+        }
+        1.0 - self.size_after as f64 / self.total_size as f64
+    }
+}
+
+impl From<&applesauce::Stats> for CompressionStats {
+    fn from(stats: &applesauce::Stats) -> Self {
+        Self {
+            files: stats.files.load(Ordering::Relaxed),
+            total_size: stats.total_file_sizes.load(Ordering::Relaxed),
+            size_before: stats.compressed_size_start.load(Ordering::Relaxed),
+            size_after: stats.compressed_size_final.load(Ordering::Relaxed),
+            compressed_files: stats.compressed_file_count_final.load(Ordering::Relaxed),
+            incompressible_files: stats.incompressible_file_count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Undo transparent compression of `paths`
+pub fn decompress<'a, P>(
+    paths: impl IntoIterator<Item = &'a Path>,
+    progress: &P,
+) -> CompressionStats
+where
+    P: Progress + Send + Sync,
+    P::Task: Send + Sync + 'static,
+{
+    let stats = FileCompressor::new().recursive_decompress(paths, false, progress, true);
+    CompressionStats::from(&stats)
+}
+
+/// Transparent compression state of a file or directory tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct CompressionState {
+    /// Compressed files, 1 for a compressed image file
+    pub compressed_files: u64,
+    pub files: u64,
+    /// Algorithm recorded in the decmpfs header, only known for single files
+    pub algorithm: Option<Algorithm>,
+    pub logical: u64,
+    pub on_disk: u64,
+}
+
+impl CompressionState {
+    /// Inspect the decmpfs state of `path`
+    pub fn inspect(path: &Path) -> std::io::Result<Self> {
+        if path.is_dir() {
+            let info = applesauce::info::get_recursive(path)?;
+            return Ok(Self {
+                compressed_files: u64::from(info.num_compressed_files),
+                files: u64::from(info.num_files),
+                algorithm: None,
+                logical: info.total_uncompressed_size,
+                on_disk: info.total_compressed_size,
+            });
+        }
+
+        let info = applesauce::info::get(path)?;
+        let algorithm = match &info.decmpfs_info {
+            Some(Ok(decmpfs)) => decmpfs
+                .compression_type
+                .compression_storage()
+                .map(|(kind, _)| Algorithm::from_kind(kind)),
+            _ => None,
+        };
+        Ok(Self {
+            compressed_files: u64::from(info.is_compressed),
+            files: 1,
+            algorithm,
+            logical: info.stat_size,
+            on_disk: info.on_disk_size,
+        })
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed_files > 0
+    }
+
+    /// On-disk size as a fraction of the logical size
+    pub fn ratio(&self) -> f64 {
+        if self.logical == 0 {
+            return 1.0;
+        }
+        self.on_disk as f64 / self.logical as f64
+    }
+}
+
+impl std::fmt::Display for CompressionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.is_compressed() {
+            return write!(f, "uncompressed");
+        }
+        match self.algorithm {
+            Some(algorithm) => write!(f, "{}", algorithm)?,
+            None => write!(f, "compressed")?,
+        }
+        if self.files > 1 {
+            write!(f, " ({}/{} files)", self.compressed_files, self.files)?;
+        }
+        write!(
+            f,
+            ", {:.0}% of {}",
+            self.ratio() * 100.0,
+            crate::diskimage::format_size(self.logical)
+        )
+    }
+}
+
+/// Terminal progress bar fed by applesauce
+pub struct ProgressReporter {
+    bar: ProgressBar,
+    skipped: AtomicU64,
+    /// Files applesauce could not handle, with why
+    errors: Arc<Mutex<Vec<String>>>,
+    /// Progress events replacing the bar in the machine output modes
+    events: Option<Arc<ProgressEvents>>,
+}
+
+impl ProgressReporter {
+    /// Progress bar sized to the total logical size of `paths`
+    pub fn new<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Self {
+        let paths: Vec<&Path> = paths.into_iter().collect();
+        let total = paths.iter().map(|path| total_size(path)).sum();
+        if output::mode().is_machine() {
+            let bar = ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::hidden());
+            return Self {
+                bar,
+                skipped: AtomicU64::new(0),
+                errors: Arc::default(),
+                events: Some(Arc::new(ProgressEvents {
+                    operation: "compress".to_string(),
+                    path: paths.first().map(|p| p.to_path_buf()).unwrap_or_default(),
+                    total,
+                    last: Mutex::new(Instant::now()),
+                })),
+            };
+        }
+        let bar = ProgressBar::new(total);
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({eta}) {msg}",
+            )
+            .unwrap_or_else(|_| ProgressStyle::default_bar()),
+        );
+        Self {
+            bar,
+            skipped: AtomicU64::new(0),
+            errors: Arc::default(),
+            events: None,
+        }
+    }
+
+    /// Progress reporter that draws nothing
+    pub fn hidden() -> Self {
+        Self {
+            bar: ProgressBar::hidden(),
+            skipped: AtomicU64::new(0),
+            errors: Arc::default(),
+            events: None,
+        }
+    }
+
+    /// Name the operation in progress events, `compress` by default
+    pub fn with_operation(mut self, operation: &str) -> Self {
+        if let Some(events) = self.events.as_mut().and_then(Arc::get_mut) {
+            events.operation = operation.to_string();
+        }
+        self
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// Errors reported so far, as `<path>: <message>`
+    pub fn errors(&self) -> Vec<String> {
+        self.errors.lock().map(|e| e.clone()).unwrap_or_default()
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+        if let Some(events) = &self.events {
+            events.emit(self.bar.position());
+        }
+    }
+}
+
+/// Progress of one operation reported as events, at most twice a second
+struct ProgressEvents {
+    operation: String,
+    path: PathBuf,
+    total: u64,
+    last: Mutex<Instant>,
+}
+
+impl ProgressEvents {
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    fn tick(&self, done: u64) {
+        let Ok(mut last) = self.last.lock() else {
+            return;
+        };
+        if last.elapsed() >= Self::INTERVAL {
+            *last = Instant::now();
+            self.emit(done);
+        }
+    }
+
+    fn emit(&self, done: u64) {
+        output::emit(Event::Progress {
+            operation: self.operation.clone(),
+            path: self.path.clone(),
+            done,
+            total: self.total,
+        });
+    }
+}
+
+impl Progress for ProgressReporter {
+    type Task = ProgressTask;
+
+    fn error(&self, path: &Path, message: &str) {
+        self.bar
+            .println(format!("Error at {}: {}", path.display(), message));
+        record_error(&self.errors, path, message);
+    }
+
+    fn file_skipped(&self, _path: &Path, _why: SkipReason) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn file_task(&self, path: &Path, _size: u64) -> Self::Task {
+        if let Some(name) = path.file_name() {
+            self.bar.set_message(name.to_string_lossy().to_string());
+        }
+        ProgressTask {
+            bar: self.bar.clone(),
+            path: path.to_path_buf(),
+            errors: Arc::clone(&self.errors),
+            events: self.events.clone(),
+        }
+    }
+}
+
+pub struct ProgressTask {
+    bar: ProgressBar,
+    path: PathBuf,
+    errors: Arc<Mutex<Vec<String>>>,
+    events: Option<Arc<ProgressEvents>>,
+}
+
+impl Task for ProgressTask {
+    fn increment(&self, amt: u64) {
+        self.bar.inc(amt);
+        if let Some(events) = &self.events {
+            events.tick(self.bar.position());
+        }
+    }
+
+    fn error(&self, message: &str) {
+        self.bar
+            .println(format!("Error at {}: {}", self.path.display(), message));
+        record_error(&self.errors, &self.path, message);
+    }
+}
+
+/// Counting semaphore of the files in flight
+struct FileLimit {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+impl FileLimit {
+    fn acquire(&self) {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.released.wait(free).unwrap();
+        }
+        *free -= 1;
+    }
+
+    fn release(&self) {
+        *self.free.lock().unwrap() += 1;
+        self.released.notify_one();
+    }
+}
+
+/// Progress wrapper holding applesauce's scan back until fewer than `limit`
+/// files are in flight
+///
+/// applesauce drops a file's task once the file is written.
+struct Limited<'p, P> {
+    progress: &'p P,
+    limit: Arc<FileLimit>,
+}
+
+impl<'p, P> Limited<'p, P> {
+    fn new(progress: &'p P, limit: usize) -> Self {
+        Self {
+            progress,
+            limit: Arc::new(FileLimit {
+                free: Mutex::new(limit.max(1)),
+                released: Condvar::new(),
+            }),
+        }
+    }
+}
+
+impl<P: Progress> Progress for Limited<'_, P> {
+    type Task = LimitedTask<P::Task>;
+
+    fn error(&self, path: &Path, message: &str) {
+        self.progress.error(path, message);
+    }
+
+    fn file_skipped(&self, path: &Path, why: SkipReason) {
+        self.progress.file_skipped(path, why);
+    }
+
+    fn file_task(&self, path: &Path, size: u64) -> Self::Task {
+        self.limit.acquire();
+        LimitedTask {
+            task: self.progress.file_task(path, size),
+            limit: Arc::clone(&self.limit),
+        }
+    }
+}
+
+struct LimitedTask<T> {
+    task: T,
+    limit: Arc<FileLimit>,
+}
+
+impl<T: Task> Task for LimitedTask<T> {
+    fn increment(&self, amt: u64) {
+        self.task.increment(amt);
+    }
+
+    fn error(&self, message: &str) {
+        self.task.error(message);
+    }
+
+    fn not_compressible_enough(&self, path: &Path) {
+        self.task.not_compressible_enough(path);
+    }
+}
+
+impl<T> Drop for LimitedTask<T> {
+    fn drop(&mut self) {
+        self.limit.release();
+    }
+}
+
+fn record_error(errors: &Mutex<Vec<String>>, path: &Path, message: &str) {
+    if let Ok(mut errors) = errors.lock() {
+        errors.push(format!("{}: {}", path.display(), message));
+    }
+}
+
+/// Logical size of a file, or of all files below a directory
+fn total_size(path: &Path) -> u64 {
+    disk_usage(path).logical
+}
+
+/// Logical and allocated size of a file or directory tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DiskUsage {
+    pub logical: u64,
+    /// Bytes actually allocated on disk, smaller than `logical` once compressed
+    pub allocated: u64,
+}
+
+impl std::ops::Add for DiskUsage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            logical: self.logical + other.logical,
+            allocated: self.allocated + other.allocated,
+        }
+    }
+}
+
+/// Measure `path` the way `du` does, counting allocated 512-byte blocks
+pub fn disk_usage(path: &Path) -> DiskUsage {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return DiskUsage::default();
+    };
+    if !metadata.is_dir() {
+        return DiskUsage {
+            logical: metadata.len(),
+            allocated: metadata.blocks() * 512,
+        };
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| disk_usage(&e.path()))
+                .fold(DiskUsage::default(), |a, b| a + b)
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_algorithm_kind() {
+        assert_eq!(Algorithm::None.kind(), None);
+        assert_eq!(Algorithm::Lzfse.kind(), Some(Kind::Lzfse));
+        assert_eq!(Algorithm::Zlib.kind(), Some(Kind::Zlib));
+        assert_eq!(Algorithm::from_kind(Kind::Lzvn), Algorithm::Lzvn);
+    }
+
+    #[test]
+    fn test_policy_defaults() {
+        let policy = CompressionPolicy::default();
+        assert!(!policy.is_enabled());
+        assert_eq!(policy.level, 5);
+        assert!((policy.minimum_compression_ratio() - 0.95).abs() < f64::EPSILON);
+
+        let policy = CompressionPolicy::new(Algorithm::Lzvn)
+            .with_min_savings(0.2)
+            .with_target(Target::Source);
+        assert!(policy.is_enabled());
+        assert!((policy.minimum_compression_ratio() - 0.8).abs() < f64::EPSILON);
+        assert_eq!(
+            policy.target_path(Path::new("a.asif"), Path::new("a")),
+            Path::new("a")
+        );
+    }
+
+    #[test]
+    fn test_schedule() {
+        let policy = CompressionPolicy::new(Algorithm::Lzfse).with_schedule(Schedule::OnDetach);
+        assert!(policy.runs_on(Schedule::OnDetach));
+        assert!(!policy.runs_on(Schedule::OnPack));
+        assert!(!CompressionPolicy::default().runs_on(Schedule::OnPack));
+
+        let dir = TempDir::new("idle");
+        let file = dir.join("node_modules.asif");
+        std::fs::write(&file, b"").unwrap();
+        assert!(policy.clone().with_idle_after(0).is_idle(&file));
+        assert!(!policy.with_idle_after(3600).is_idle(&file));
+
+        let policy: CompressionPolicy =
+            serde_json::from_str(r#"{"algorithm": "zlib", "schedule": "on-detach"}"#).unwrap();
+        assert_eq!(policy.schedule, Schedule::OnDetach);
+        assert_eq!(policy.level, 5);
+    }
+
+    #[test]
+    fn test_stats_savings() {
+        let stats = CompressionStats {
+            total_size: 1000,
+            size_after: 250,
+            ..Default::default()
+        };
+        assert!((stats.savings() - 0.75).abs() < f64::EPSILON);
+        assert_eq!(CompressionStats::default().savings(), 0.0);
+    }
+
+    #[test]
+    fn test_progress_records_errors() {
+        let progress = ProgressReporter::hidden();
+        progress.error(Path::new("/p/a.js"), "Permission denied");
+        progress
+            .file_task(Path::new("/p/b.js"), 10)
+            .error("No space left on device");
+        assert_eq!(
+            progress.errors(),
+            [
+                "/p/a.js: Permission denied",
+                "/p/b.js: No space left on device"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limited_files_in_flight() {
+        let progress = ProgressReporter::hidden();
+        let limited = Limited::new(&progress, 2);
+        let first = limited.file_task(Path::new("/p/a.js"), 10);
+        let _second = limited.file_task(Path::new("/p/b.js"), 10);
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let limited = &limited;
+            scope.spawn(move || {
+                let _third = limited.file_task(Path::new("/p/c.js"), 10);
+                tx.send(()).unwrap();
+            });
+            // The third file waits until one of the first two is written
+            assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+            drop(first);
+            assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_compression_state_display() {
+        let state = CompressionState {
+            compressed_files: 1,
+            files: 1,
+            algorithm: Some(Algorithm::Lzfse),
+            logical: 4 * 1024 * 1024,
+            on_disk: 1024 * 1024,
+        };
+        assert_eq!(state.to_string(), "lzfse, 25% of 4.0 MB");
+        let state = CompressionState {
+            compressed_files: 3,
+            files: 4,
+            algorithm: None,
+            ..state
+        };
+        assert_eq!(state.to_string(), "compressed (3/4 files), 25% of 4.0 MB");
+        assert_eq!(CompressionState::default().to_string(), "uncompressed");
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let dir = TempDir::new("usage");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), vec![7u8; 10_000]).unwrap();
+        std::fs::write(dir.join("sub/b"), b"hello").unwrap();
+
+        let usage = disk_usage(&dir);
+        assert_eq!(usage.logical, 10_005);
+        assert!(usage.allocated >= 10_000);
+        assert_eq!(disk_usage(&dir.join("missing")), DiskUsage::default());
+    }
+}
@@ -0,0 +1,510 @@
+use crate::compression::CompressionPolicy;
+use crate::diskimage::{DiskImageError, Encryption, FileSystem, Format, Result};
+use crate::policy::{self, Policy};
+use crate::secret::PassphraseSource;
+use crate::variant::VariantBy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use toml::{Table, Value};
+
+/// Project configuration file, looked up from the working directory upwards
+pub const PROJECT_FILE: &str = ".afpack.toml";
+
+/// Prefix of environment variables overriding config keys
+pub const ENV_PREFIX: &str = "AFPACK_";
+
+/// What to do with an artifact directory once its contents are in an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposal {
+    /// Move it to the trash
+    #[default]
+    Trash,
+    /// Delete it permanently
+    Delete,
+    /// Rename it to `<afdir>.orig`
+    Keep,
+}
+
+impl std::fmt::Display for Disposal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Disposal::Trash => write!(f, "trash"),
+            Disposal::Delete => write!(f, "delete"),
+            Disposal::Keep => write!(f, "keep"),
+        }
+    }
+}
+
+/// A managed artifact directory, either `"node_modules"` or `{ path = "target", maxsize = "40G" }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DirConfig {
+    Path(String),
+    Table {
+        path: String,
+        #[serde(default)]
+        maxsize: Option<String>,
+    },
+}
+
+impl DirConfig {
+    pub fn path(&self) -> &str {
+        match self {
+            DirConfig::Path(path) | DirConfig::Table { path, .. } => path,
+        }
+    }
+
+    pub fn maxsize(&self) -> Option<&str> {
+        match self {
+            DirConfig::Path(_) => None,
+            DirConfig::Table { maxsize, .. } => maxsize.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Hooks {
+    /// Install the git post-checkout hook when packing
+    pub post_checkout: bool,
+    /// What selects the image on checkout
+    pub variant_by: VariantBy,
+}
+
+/// Effective settings after merging every config layer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub maxsize: String,
+    pub format: Format,
+    pub filesystem: FileSystem,
+    /// Volume name of new blank images
+    pub volume_name: Option<String>,
+    /// Honour file ownership on new volumes
+    pub owners: bool,
+    /// Encrypt new images
+    pub encryption: Option<Encryption>,
+    /// Where the passphrase of encrypted images comes from
+    pub passphrase_from: PassphraseSource,
+    /// Seconds without open files after which images are detached
+    pub detach_after: Option<u64>,
+    pub disposal: Disposal,
+    pub dirs: Vec<DirConfig>,
+    pub compression: CompressionPolicy,
+    pub hooks: Hooks,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            maxsize: "10G".to_string(),
+            format: Format::ASIF,
+            filesystem: FileSystem::APFS,
+            volume_name: None,
+            owners: false,
+            encryption: None,
+            passphrase_from: PassphraseSource::Keychain,
+            detach_after: None,
+            disposal: Disposal::Trash,
+            dirs: Vec::new(),
+            compression: CompressionPolicy::default(),
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Maximum size for `afdir`, its own `maxsize` if configured
+    pub fn maxsize_for(&self, afdir: &str) -> &str {
+        self.dirs
+            .iter()
+            .find(|d| same_dir(d.path(), afdir))
+            .and_then(DirConfig::maxsize)
+            .unwrap_or(&self.maxsize)
+    }
+}
+
+/// Whether two relative directory paths name the same directory, e.g.
+/// `./node_modules` and `node_modules/`
+fn same_dir(a: &str, b: &str) -> bool {
+    fn components(path: &str) -> impl Iterator<Item = Component<'_>> {
+        Path::new(path)
+            .components()
+            .filter(|c| *c != Component::CurDir)
+    }
+    components(a).eq(components(b))
+}
+
+/// Where a config value came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    User(PathBuf),
+    Project(PathBuf),
+    Env(String),
+    Cli,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::User(path) | Source::Project(path) => write!(f, "{}", path.display()),
+            Source::Env(var) => write!(f, "${}", var),
+            Source::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// Layered configuration keyed by dotted path (`compression.algorithm`)
+///
+/// Layers are merged key by key in order: built-in defaults, the user config,
+/// the project `.afpack.toml`, `AFPACK_*` environment variables, then flags.
+/// Arrays such as `dirs` are replaced as a whole.
+#[derive(Debug, Clone)]
+pub struct Config {
+    values: BTreeMap<String, (Value, Source)>,
+    /// Every assignment in merge order, including overridden ones
+    history: Vec<(String, Value, Source)>,
+    policy: Option<Policy>,
+}
+
+impl Config {
+    /// Built-in defaults only
+    pub fn new() -> Self {
+        let mut config = Self {
+            values: BTreeMap::new(),
+            history: Vec::new(),
+            policy: None,
+        };
+        if let Ok(Value::Table(table)) = Value::try_from(Settings::default()) {
+            config.assign_table(&table, &Source::Default);
+        }
+        config
+    }
+
+    /// Defaults, user config, project config found from `cwd`, and environment
+    pub fn load<P: AsRef<Path>>(cwd: P) -> Result<Self> {
+        let mut config = Self::new();
+        let user = user_config_path();
+        if user.exists() {
+            config.merge_file(&user, Source::User(user.clone()))?;
+        }
+        if let Some(project) = find_project_config(cwd) {
+            config.merge_file(&project, Source::Project(project.clone()))?;
+        }
+        config.merge_env(std::env::vars());
+        Ok(config)
+    }
+
+    pub fn merge_file(&mut self, path: &Path, source: Source) -> Result<()> {
+        let data = std::fs::read_to_string(path)?;
+        let table: Table = toml::from_str(&data)
+            .map_err(|e| DiskImageError::Config(format!("{}: {}", path.display(), e)))?;
+        self.merge_table(table, &source)
+    }
+
+    /// Merge a parsed config file, taking its `[policy]` if it is the project's
+    pub fn merge_table(&mut self, mut table: Table, source: &Source) -> Result<()> {
+        if let Some(section) = table.remove("policy") {
+            if !matches!(source, Source::Project(_)) {
+                return Err(DiskImageError::Config(format!(
+                    "{}: [policy] is only honoured in {}",
+                    source, PROJECT_FILE
+                )));
+            }
+            let policy: Policy = section
+                .try_into()
+                .map_err(|e: toml::de::Error| DiskImageError::Config(format!("[policy]: {}", e)))?;
+            let keys = known_keys();
+            if let Some(key) = policy.locked.iter().find(|key| !keys.contains(key)) {
+                return Err(DiskImageError::Config(format!(
+                    "[policy]: locked key {:?} is not a config key",
+                    key
+                )));
+            }
+            self.policy = Some(policy);
+        }
+        self.assign_table(&table, source);
+        Ok(())
+    }
+
+    fn assign_table(&mut self, table: &Table, source: &Source) {
+        let mut flat = Vec::new();
+        flatten("", table, &mut flat);
+        for (key, value) in flat {
+            self.assign(key, value, source.clone());
+        }
+    }
+
+    fn assign(&mut self, key: String, value: Value, source: Source) {
+        self.history
+            .push((key.clone(), value.clone(), source.clone()));
+        self.values.insert(key, (value, source));
+    }
+
+    /// Apply `AFPACK_<KEY>` variables, e.g. `AFPACK_COMPRESSION_MIN_SAVINGS`
+    ///
+    /// Values are parsed as TOML, falling back to a plain string. Variables that
+    /// do not name a config key (`AFPACK_STATE_DIR`) are ignored.
+    pub fn merge_env(&mut self, vars: impl IntoIterator<Item = (String, String)>) {
+        let keys = known_keys();
+        for (var, raw) in vars {
+            let Some(name) = var.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let name = name.to_ascii_lowercase();
+            let Some(key) = keys.iter().find(|k| k.replace('.', "_") == name) else {
+                continue;
+            };
+            let value = toml::from_str::<Table>(&format!("v = {}", raw))
+                .ok()
+                .and_then(|mut t| t.remove("v"))
+                .unwrap_or(Value::String(raw));
+            self.assign(key.clone(), value, Source::Env(var));
+        }
+    }
+
+    /// Override one key, typically from a command line flag
+    pub fn set(&mut self, key: &str, value: impl Into<Value>, source: Source) {
+        self.assign(key.to_string(), value.into(), source);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key).map(|(value, _)| value)
+    }
+
+    pub fn source(&self, key: &str) -> Option<&Source> {
+        self.values.get(key).map(|(_, source)| source)
+    }
+
+    /// Every value assigned to `key`, in merge order
+    pub fn assignments<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> impl Iterator<Item = (&'a Value, &'a Source)> {
+        self.history
+            .iter()
+            .filter(move |(k, _, _)| k == key)
+            .map(|(_, value, source)| (value, source))
+    }
+
+    /// Policy declared by the project file
+    pub fn policy(&self) -> Option<&Policy> {
+        self.policy.as_ref()
+    }
+
+    /// Deserialize the merged values, refusing overrides the policy forbids
+    pub fn settings(&self) -> Result<Settings> {
+        policy::enforce(self)?;
+        self.settings_unchecked()
+    }
+
+    /// Deserialize the merged values without enforcing the policy
+    pub fn settings_unchecked(&self) -> Result<Settings> {
+        let mut root = Table::new();
+        for (key, (value, _)) in &self.values {
+            let mut parts: Vec<&str> = key.split('.').collect();
+            let last = parts.pop().unwrap_or_default();
+            let mut table = &mut root;
+            for part in parts {
+                let entry = table
+                    .entry(part.to_string())
+                    .or_insert_with(|| Value::Table(Table::new()));
+                let Value::Table(inner) = entry else {
+                    return Err(DiskImageError::Config(format!("{} is not a table", part)));
+                };
+                table = inner;
+            }
+            table.insert(last.to_string(), value.clone());
+        }
+        Value::Table(root)
+            .try_into()
+            .map_err(|e: toml::de::Error| DiskImageError::Config(e.to_string()))
+    }
+
+    /// Every key with its value and where it came from, sorted by key
+    pub fn values(&self) -> impl Iterator<Item = (&str, &Value, &Source)> {
+        self.values
+            .iter()
+            .map(|(key, (value, source))| (key.as_str(), value, source))
+    }
+
+    /// Every key as `key = value  # source`
+    pub fn render(&self) -> String {
+        let lines: Vec<(String, &Source)> = self
+            .values
+            .iter()
+            .map(|(key, (value, source))| (format!("{} = {}", key, value), source))
+            .collect();
+        let width = lines.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+        lines
+            .iter()
+            .map(|(line, source)| format!("{:<width$}  # {}\n", line, source, width = width))
+            .collect()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `$XDG_CONFIG_HOME/afpack/config.toml`, by default `~/.config/afpack/config.toml`
+pub fn user_config_path() -> PathBuf {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            std::env::var_os("HOME")
+                .map(PathBuf::from)
+                .unwrap_or_else(std::env::temp_dir)
+                .join(".config")
+        });
+    dir.join("afpack").join("config.toml")
+}
+
+/// Nearest `.afpack.toml` in `start` or one of its ancestors
+pub fn find_project_config<P: AsRef<Path>>(start: P) -> Option<PathBuf> {
+    let start = std::path::absolute(start.as_ref()).ok()?;
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Keys settable from the environment or lockable by a policy: those with a
+/// default plus optional ones
+fn known_keys() -> Vec<String> {
+    let mut keys: Vec<String> = Config::new().values.into_keys().collect();
+    keys.push("volume_name".to_string());
+    keys.push("encryption".to_string());
+    keys.push("detach_after".to_string());
+    keys.push("compression.threads".to_string());
+    keys
+}
+
+fn flatten(prefix: &str, table: &Table, out: &mut Vec<(String, Value)>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            Value::Table(inner) => flatten(&key, inner, out),
+            _ => out.push((key, value.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{Algorithm, Schedule};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::new();
+        assert_eq!(config.settings().unwrap(), Settings::default());
+        assert_eq!(config.source("maxsize"), Some(&Source::Default));
+        assert_eq!(
+            config.get("compression.algorithm"),
+            Some(&Value::String("none".into()))
+        );
+    }
+
+    #[test]
+    fn test_encryption_from_env() {
+        let mut config = Config::new();
+        config.merge_env([
+            ("AFPACK_ENCRYPTION".to_string(), "aes-256".to_string()),
+            (
+                "AFPACK_PASSPHRASE_FROM".to_string(),
+                "env:SDK_KEY".to_string(),
+            ),
+        ]);
+        let settings = config.settings().unwrap();
+        assert_eq!(settings.encryption, Some(Encryption::AES256));
+        assert_eq!(
+            settings.passphrase_from,
+            PassphraseSource::Env("SDK_KEY".into())
+        );
+
+        config.set("passphrase_from", "gpg", Source::Cli);
+        assert!(config.settings().is_err());
+    }
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let dir = TempDir::new("config");
+        std::fs::create_dir_all(dir.join("app/src")).unwrap();
+        let project = dir.join("app").join(PROJECT_FILE);
+        std::fs::write(
+            &project,
+            r#"
+maxsize = "20G"
+dirs = ["node_modules", { path = "target", maxsize = "40G" }]
+
+[compression]
+algorithm = "lzfse"
+schedule = "on-detach"
+"#,
+        )
+        .unwrap();
+
+        let mut config = Config::new();
+        let found = find_project_config(dir.join("app/src")).unwrap();
+        assert_eq!(found, std::path::absolute(&project).unwrap());
+        config
+            .merge_file(&found, Source::Project(found.clone()))
+            .unwrap();
+        config.merge_env([
+            (
+                "AFPACK_COMPRESSION_MIN_SAVINGS".to_string(),
+                "0.2".to_string(),
+            ),
+            ("AFPACK_DISPOSAL".to_string(), "keep".to_string()),
+            ("AFPACK_STATE_DIR".to_string(), "/tmp".to_string()),
+        ]);
+        config.set("maxsize", "30G", Source::Cli);
+
+        let settings = config.settings().unwrap();
+        assert_eq!(settings.maxsize, "30G");
+        assert_eq!(settings.maxsize_for("target"), "40G");
+        assert_eq!(settings.maxsize_for("node_modules"), "30G");
+        assert_eq!(settings.maxsize_for("./target/"), "40G");
+        assert_eq!(settings.disposal, Disposal::Keep);
+        assert_eq!(settings.compression.algorithm, Algorithm::Lzfse);
+        assert_eq!(settings.compression.schedule, Schedule::OnDetach);
+        assert_eq!(settings.compression.min_savings, 0.2);
+        assert_eq!(settings.compression.level, 5);
+
+        assert_eq!(config.source("maxsize"), Some(&Source::Cli));
+        assert_eq!(
+            config.source("compression.algorithm"),
+            Some(&Source::Project(found))
+        );
+        assert_eq!(
+            config.source("disposal"),
+            Some(&Source::Env("AFPACK_DISPOSAL".into()))
+        );
+        assert!(config.get("state_dir").is_none());
+        let rendered = config.render();
+        let line = rendered
+            .lines()
+            .find(|l| l.starts_with("compression.min_savings = 0.2 "))
+            .unwrap();
+        assert!(line.ends_with("  # $AFPACK_COMPRESSION_MIN_SAVINGS"));
+    }
+
+    #[test]
+    fn test_invalid_value() {
+        let mut config = Config::new();
+        config.set("compression.algorithm", "brotli", Source::Cli);
+        assert!(matches!(config.settings(), Err(DiskImageError::Config(_))));
+    }
+}
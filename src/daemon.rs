@@ -0,0 +1,543 @@
+use crate::activity;
+use crate::diskimage::{self, AttachOptions, DetachOptions, DiskImage, DiskImageError, Result};
+use crate::registry::{self, Entry, Registry};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Control socket of afpackd inside the state directory
+pub fn socket_path() -> PathBuf {
+    registry::state_dir().join("afpackd.sock")
+}
+
+/// JSON-RPC error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// An afpack operation failed
+pub const OPERATION_FAILED: i64 = -32000;
+
+/// Mounts and unmounts images, replaced by a fake in tests
+pub trait Backend: Send + Sync {
+    fn attach(&self, entry: &Entry) -> Result<()>;
+    fn detach(&self, entry: &Entry) -> Result<()>;
+    fn is_mounted(&self, afdir: &Path) -> bool;
+    /// Whether any process has files open on the volume
+    fn is_busy(&self, afdir: &Path) -> Result<bool>;
+}
+
+/// Backend attaching with diskutil and hdiutil
+pub struct DiskImageBackend;
+
+impl Backend for DiskImageBackend {
+    fn attach(&self, entry: &Entry) -> Result<()> {
+        let mut options = AttachOptions::new().with_mount_point(entry.afdir.display().to_string());
+        if let Some(shadow) = &entry.shadow {
+            options = options.with_shadow(shadow.display().to_string());
+        }
+        if let Some(source) = &entry.passphrase_from {
+            options = options.with_passphrase(source.fetch(&entry.afdir)?);
+        }
+        DiskImage::attach(&entry.image, options).map(|_| ())
+    }
+
+    fn detach(&self, entry: &Entry) -> Result<()> {
+        DiskImage::detach(&entry.afdir, DetachOptions::new()).map(|_| ())
+    }
+
+    fn is_mounted(&self, afdir: &Path) -> bool {
+        diskimage::is_mount_point(afdir)
+    }
+
+    fn is_busy(&self, afdir: &Path) -> Result<bool> {
+        Ok(!activity::processes_using(afdir)?.is_empty())
+    }
+}
+
+/// Change to a managed directory, sent to subscribers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    Attached {
+        afdir: PathBuf,
+    },
+    Detached {
+        afdir: PathBuf,
+    },
+    /// Recorded as attached but no longer mounted, e.g. after sleep
+    Vanished {
+        afdir: PathBuf,
+    },
+    Remounted {
+        afdir: PathBuf,
+    },
+    /// Detached after `detach_after` seconds without open files
+    IdleDetached {
+        afdir: PathBuf,
+    },
+    Failed {
+        afdir: PathBuf,
+        message: String,
+    },
+}
+
+/// State of one managed directory as reported by `status`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub afdir: PathBuf,
+    pub image: PathBuf,
+    /// Whether the registry records it as attached
+    pub attached: bool,
+    /// Whether a volume is actually mounted there
+    pub mounted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    /// Absent for notifications
+    #[serde(default)]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl Response {
+    fn ok(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(id: Value, code: i64, message: impl Into<String>) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            id,
+            result: None,
+            error: Some(RpcError {
+                code,
+                message: message.into(),
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AfdirParams {
+    afdir: PathBuf,
+}
+
+/// Write half of a connection, shared by its reader thread and `emit`
+///
+/// Responses and events are written under the lock so their lines never
+/// interleave.
+pub type Writer = Arc<Mutex<UnixStream>>;
+
+/// Long-running owner of the registry serving the control API
+///
+/// Methods: `attach` and `detach` (`{"afdir": ...}`), `status` (optional
+/// `afdir`) and `subscribe`, after which the connection receives `event`
+/// notifications. The registry is reloaded for every operation, so CLI runs
+/// that bypass the daemon are picked up.
+pub struct Daemon {
+    registry_path: PathBuf,
+    backend: Box<dyn Backend>,
+    /// Reattach volumes that vanished while recorded as attached
+    remount: bool,
+    lock: Mutex<()>,
+    event_timeout: Duration,
+    subscribers: Mutex<Vec<Writer>>,
+    /// Vanished volumes already reported, so each is announced once
+    vanished: Mutex<HashSet<PathBuf>>,
+}
+
+impl Daemon {
+    pub fn new(registry_path: impl Into<PathBuf>, backend: Box<dyn Backend>) -> Self {
+        Self {
+            registry_path: registry_path.into(),
+            backend,
+            remount: true,
+            lock: Mutex::new(()),
+            event_timeout: Duration::from_secs(5),
+            subscribers: Mutex::new(Vec::new()),
+            vanished: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn with_remount(mut self, remount: bool) -> Self {
+        self.remount = remount;
+        self
+    }
+
+    /// Listen on `socket`, replacing a stale socket file but not a live daemon
+    /// How long a subscriber may stall an event before it is dropped
+    pub fn with_event_timeout(mut self, timeout: Duration) -> Self {
+        self.event_timeout = timeout;
+        self
+    }
+
+    pub fn bind(socket: &Path) -> Result<UnixListener> {
+        if socket.exists() {
+            if UnixStream::connect(socket).is_ok() {
+                return Err(DiskImageError::Daemon(format!(
+                    "afpackd is already listening on {}",
+                    socket.display()
+                )));
+            }
+            std::fs::remove_file(socket)?;
+        }
+        if let Some(parent) = socket.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(UnixListener::bind(socket)?)
+    }
+
+    /// Serve connections until the listener fails, one thread each
+    pub fn serve(self: &Arc<Self>, listener: UnixListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let daemon = Arc::clone(self);
+            std::thread::spawn(move || daemon.handle_connection(stream));
+        }
+        Ok(())
+    }
+
+    /// Check the attached volumes every `interval` on a background thread
+    pub fn spawn_monitor(self: &Arc<Self>, interval: Duration) {
+        let daemon = Arc::clone(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if let Err(e) = daemon.check() {
+                eprintln!("afpackd: {}", e);
+            }
+        });
+    }
+
+    /// Detach idle volumes and report, with remount reattach, vanished ones
+    ///
+    /// A failing entry is reported with a `failed` event and does not stop the
+    /// others; whatever changed is saved either way. Events go out once the
+    /// registry lock is released.
+    pub fn check(&self) -> Result<()> {
+        let mut events = Vec::new();
+        let result = self.check_entries(&mut events);
+        for event in &events {
+            self.emit(event);
+        }
+        result
+    }
+
+    fn check_entries(&self, events: &mut Vec<Event>) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut registry = Registry::load_from(&self.registry_path)?;
+        let mut vanished = self.vanished.lock().unwrap();
+        let mut changed = false;
+        let now = activity::now();
+        for entry in &mut registry.entries {
+            if !entry.attached {
+                continue;
+            }
+            let before = entry.clone();
+            if let Err(e) = self.check_entry(entry, &mut vanished, now, events) {
+                events.push(Event::Failed {
+                    afdir: entry.afdir.clone(),
+                    message: e.to_string(),
+                });
+            }
+            changed |= *entry != before;
+        }
+        if changed {
+            registry.save_to(&self.registry_path)?;
+        }
+        Ok(())
+    }
+
+    fn check_entry(
+        &self,
+        entry: &mut Entry,
+        vanished: &mut HashSet<PathBuf>,
+        now: u64,
+        events: &mut Vec<Event>,
+    ) -> Result<()> {
+        if self.backend.is_mounted(&entry.afdir) {
+            vanished.remove(&entry.afdir);
+            if entry.detach_after.is_some() {
+                let busy = self.backend.is_busy(&entry.afdir)?;
+                if entry.idle_detach_due(busy, now) {
+                    self.backend.detach(entry)?;
+                    entry.attached = false;
+                    entry.idle_detached = true;
+                    events.push(Event::IdleDetached {
+                        afdir: entry.afdir.clone(),
+                    });
+                }
+            }
+            return Ok(());
+        }
+        let afdir = entry.afdir.clone();
+        if vanished.insert(afdir.clone()) {
+            events.push(Event::Vanished {
+                afdir: afdir.clone(),
+            });
+        }
+        if !self.remount {
+            return Ok(());
+        }
+        self.backend.attach(entry)?;
+        entry.mark_attached(now);
+        vanished.remove(&afdir);
+        events.push(Event::Remounted { afdir });
+        Ok(())
+    }
+
+    /// Answer one request, `None` for notifications
+    pub fn handle(&self, request: Request, writer: &Writer) -> Option<Response> {
+        let id = request.id.clone()?;
+        let result = match request.method.as_str() {
+            "attach" | "detach" => match serde_json::from_value::<AfdirParams>(request.params) {
+                Ok(params) => self.set_attached(&params.afdir, request.method == "attach"),
+                Err(e) => return Some(Response::error(id, INVALID_PARAMS, e.to_string())),
+            },
+            "status" => {
+                let afdir = request
+                    .params
+                    .get("afdir")
+                    .and_then(Value::as_str)
+                    .map(PathBuf::from);
+                self.status(afdir.as_deref()).map(|status| json!(status))
+            }
+            "subscribe" => writer
+                .lock()
+                .unwrap()
+                .set_write_timeout(Some(self.event_timeout))
+                .map_err(DiskImageError::from)
+                .map(|_| {
+                    self.subscribers.lock().unwrap().push(Arc::clone(writer));
+                    json!({ "subscribed": true })
+                }),
+            method => {
+                return Some(Response::error(
+                    id,
+                    METHOD_NOT_FOUND,
+                    format!("unknown method {}", method),
+                ))
+            }
+        };
+        Some(match result {
+            Ok(result) => Response::ok(id, result),
+            Err(e) => Response::error(id, OPERATION_FAILED, e.to_string()),
+        })
+    }
+
+    fn handle_connection(&self, stream: UnixStream) {
+        let Ok(reader) = stream.try_clone() else {
+            return;
+        };
+        let writer = Arc::new(Mutex::new(stream));
+        for line in BufReader::new(reader).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => self.handle(request, &writer),
+                Err(e) => Some(Response::error(Value::Null, PARSE_ERROR, e.to_string())),
+            };
+            if let Some(response) = response {
+                if write_line(&mut *writer.lock().unwrap(), &response).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn set_attached(&self, afdir: &Path, attach: bool) -> Result<Value> {
+        self.update_attached(afdir, attach)?;
+        let afdir = afdir.to_path_buf();
+        self.emit(&if attach {
+            Event::Attached { afdir }
+        } else {
+            Event::Detached { afdir }
+        });
+        Ok(json!({ "attached": attach }))
+    }
+
+    fn update_attached(&self, afdir: &Path, attach: bool) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut registry = Registry::load_from(&self.registry_path)?;
+        let entry = registry.get_mut(afdir).ok_or_else(|| {
+            DiskImageError::InvalidPath(format!("{} is not managed by afpack", afdir.display()))
+        })?;
+        let mounted = self.backend.is_mounted(afdir);
+        if attach && !mounted {
+            self.backend.attach(entry)?;
+        } else if !attach && mounted {
+            self.backend.detach(entry)?;
+        }
+        if attach {
+            entry.mark_attached(activity::now());
+        } else {
+            entry.attached = false;
+            entry.idle_detached = false;
+        }
+        registry.save_to(&self.registry_path)
+    }
+
+    fn status(&self, afdir: Option<&Path>) -> Result<Vec<Status>> {
+        let registry = Registry::load_from(&self.registry_path)?;
+        Ok(registry
+            .entries
+            .iter()
+            .filter(|e| afdir.is_none_or(|afdir| e.afdir == afdir))
+            .map(|e| Status {
+                afdir: e.afdir.clone(),
+                image: e.image.clone(),
+                attached: e.attached,
+                mounted: self.backend.is_mounted(&e.afdir),
+            })
+            .collect())
+    }
+
+    /// Send an event to every subscriber, dropping closed or stalled connections
+    fn emit(&self, event: &Event) {
+        let notification = json!({ "jsonrpc": "2.0", "method": "event", "params": event });
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|writer| write_line(&mut *writer.lock().unwrap(), &notification).is_ok());
+    }
+}
+
+fn write_line<W: Write, T: Serialize>(writer: &mut W, value: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+/// Connection to a running afpackd
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl Client {
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let writer = UnixStream::connect(socket).map_err(|e| {
+            DiskImageError::Daemon(format!(
+                "cannot reach afpackd at {}: {}",
+                socket.display(),
+                e
+            ))
+        })?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+            next_id: 1,
+        })
+    }
+
+    /// Call `method` and wait for its result, skipping event notifications
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = Request {
+            jsonrpc: "2.0".into(),
+            id: Some(json!(id)),
+            method: method.into(),
+            params,
+        };
+        write_line(&mut self.writer, &request)?;
+        loop {
+            let line = self.read_line()?;
+            let Ok(response) = serde_json::from_str::<Response>(&line) else {
+                continue;
+            };
+            if response.id != json!(id) {
+                continue;
+            }
+            if let Some(error) = response.error {
+                return Err(DiskImageError::Daemon(error.message));
+            }
+            return Ok(response.result.unwrap_or(Value::Null));
+        }
+    }
+
+    pub fn status(&mut self, afdir: Option<&Path>) -> Result<Vec<Status>> {
+        let params = match afdir {
+            Some(afdir) => json!({ "afdir": afdir }),
+            None => json!({}),
+        };
+        serde_json::from_value(self.call("status", params)?)
+            .map_err(|e| DiskImageError::Daemon(e.to_string()))
+    }
+
+    pub fn attach(&mut self, afdir: &Path) -> Result<()> {
+        self.call("attach", json!({ "afdir": afdir })).map(|_| ())
+    }
+
+    pub fn detach(&mut self, afdir: &Path) -> Result<()> {
+        self.call("detach", json!({ "afdir": afdir })).map(|_| ())
+    }
+
+    /// Receive events on this connection, read them with `next_event`
+    pub fn subscribe(&mut self) -> Result<()> {
+        self.call("subscribe", Value::Null).map(|_| ())
+    }
+
+    /// Block until the daemon sends the next event
+    pub fn next_event(&mut self) -> Result<Event> {
+        loop {
+            let line = self.read_line()?;
+            let Ok(notification) = serde_json::from_str::<Value>(&line) else {
+                continue;
+            };
+            if notification["method"] == "event" {
+                return serde_json::from_value(notification["params"].clone())
+                    .map_err(|e| DiskImageError::Daemon(e.to_string()));
+            }
+        }
+    }
+
+    /// Give up on reads after `timeout`
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.writer.set_read_timeout(timeout)?)
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(DiskImageError::Daemon(
+                "afpackd closed the connection".into(),
+            ));
+        }
+        Ok(line)
+    }
+}
@@ -0,0 +1,319 @@
+use crate::diskimage::Result;
+use crate::ecosystem::Ecosystem;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Files changed inside one top-level package
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PackageDiff {
+    pub package: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+/// Entries macOS keeps at the root of a volume, not part of the artifacts
+const VOLUME_METADATA: &[&str] = &[
+    ".DocumentRevisions-V100",
+    ".DS_Store",
+    ".Spotlight-V100",
+    ".TemporaryItems",
+    ".Trashes",
+    ".fseventsd",
+];
+
+/// File-level difference between two artifact directory trees
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DiffReport {
+    pub ecosystem: String,
+    pub packages: Vec<PackageDiff>,
+    /// Directories that could not be read on either side, so are not compared
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unreadable: Vec<PathBuf>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    /// Human readable report, one block per package
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for path in &self.unreadable {
+            out.push_str(&format!(
+                "! {} (unreadable, not compared)\n",
+                path.display()
+            ));
+        }
+        for package in &self.packages {
+            let marker = if package.modified.is_empty() && package.removed.is_empty() {
+                '+'
+            } else if package.modified.is_empty() && package.added.is_empty() {
+                '-'
+            } else {
+                '~'
+            };
+            out.push_str(&format!(
+                "{} {} (+{} -{} ~{})\n",
+                marker,
+                package.package,
+                package.added.len(),
+                package.removed.len(),
+                package.modified.len()
+            ));
+            for file in &package.added {
+                out.push_str(&format!("    + {}\n", file));
+            }
+            for file in &package.removed {
+                out.push_str(&format!("    - {}\n", file));
+            }
+            for file in &package.modified {
+                out.push_str(&format!("    ~ {}\n", file));
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    File {
+        len: u64,
+        modified: Option<SystemTime>,
+    },
+    Symlink(PathBuf),
+}
+
+/// Compare two directory trees, grouping changes by package
+pub fn diff_trees<A: AsRef<Path>, B: AsRef<Path>>(
+    a: A,
+    b: B,
+    ecosystem: Ecosystem,
+) -> Result<DiffReport> {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    let mut unreadable = Vec::new();
+    let before = walk(a, &mut unreadable)?;
+    let after = walk(b, &mut unreadable)?;
+
+    let mut packages: BTreeMap<String, PackageDiff> = BTreeMap::new();
+    for (relative, node) in &before {
+        let name = relative.display().to_string();
+        match after.get(relative) {
+            None => group(&mut packages, ecosystem, relative).removed.push(name),
+            Some(other) if changed(node, other, &a.join(relative), &b.join(relative))? => {
+                group(&mut packages, ecosystem, relative)
+                    .modified
+                    .push(name)
+            }
+            Some(_) => {}
+        }
+    }
+    for relative in after.keys().filter(|r| !before.contains_key(*r)) {
+        let name = relative.display().to_string();
+        group(&mut packages, ecosystem, relative).added.push(name);
+    }
+
+    Ok(DiffReport {
+        ecosystem: ecosystem.to_string(),
+        packages: packages.into_values().collect(),
+        unreadable,
+    })
+}
+
+fn group<'a>(
+    packages: &'a mut BTreeMap<String, PackageDiff>,
+    ecosystem: Ecosystem,
+    relative: &Path,
+) -> &'a mut PackageDiff {
+    let package = ecosystem.package_of(relative);
+    packages.entry(package.clone()).or_insert(PackageDiff {
+        package,
+        ..Default::default()
+    })
+}
+
+/// Files and symlinks below `root`, keyed by path relative to `root`
+///
+/// Volume metadata at the root is skipped. Directories below the root that
+/// cannot be read are added to `unreadable` instead of failing the walk.
+fn walk(root: &Path, unreadable: &mut Vec<PathBuf>) -> Result<BTreeMap<PathBuf, Node>> {
+    let mut nodes = BTreeMap::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let relative_dir = dir.strip_prefix(root).unwrap_or(&dir).to_path_buf();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if dir == root => return Err(e.into()),
+            Err(_) => {
+                unreadable.push(relative_dir);
+                continue;
+            }
+        };
+        for dir_entry in entries {
+            let Ok(dir_entry) = dir_entry else {
+                unreadable.push(relative_dir.clone());
+                break;
+            };
+            let path = dir_entry.path();
+            if dir == root
+                && VOLUME_METADATA
+                    .iter()
+                    .any(|name| dir_entry.file_name() == *name)
+            {
+                continue;
+            }
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+                unreadable.push(relative);
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(path);
+            } else if metadata.file_type().is_symlink() {
+                match std::fs::read_link(&path) {
+                    Ok(target) => {
+                        nodes.insert(relative, Node::Symlink(target));
+                    }
+                    Err(_) => unreadable.push(relative),
+                }
+            } else {
+                nodes.insert(
+                    relative,
+                    Node::File {
+                        len: metadata.len(),
+                        modified: metadata.modified().ok(),
+                    },
+                );
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+/// Whether a file differs; contents are only read when size matches but mtime does not
+fn changed(a: &Node, b: &Node, a_path: &Path, b_path: &Path) -> Result<bool> {
+    match (a, b) {
+        (
+            Node::File {
+                len: a_len,
+                modified: a_mtime,
+            },
+            Node::File {
+                len: b_len,
+                modified: b_mtime,
+            },
+        ) => {
+            if a_len != b_len {
+                return Ok(true);
+            }
+            if a_mtime == b_mtime {
+                return Ok(false);
+            }
+            Ok(!same_contents(a_path, b_path)?)
+        }
+        _ => Ok(a != b),
+    }
+}
+
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let mut a_buf = [0u8; 64 * 1024];
+    let mut b_buf = [0u8; 64 * 1024];
+    loop {
+        let n = a.read(&mut a_buf)?;
+        if n == 0 {
+            return Ok(b.read(&mut b_buf)? == 0);
+        }
+        b.read_exact(&mut b_buf[..n])?;
+        if a_buf[..n] != b_buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn write(root: &Path, relative: &str, data: &[u8]) {
+        let path = root.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_diff_trees_groups_by_package() {
+        let dir = TempDir::new("diff");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        write(&a, "lodash/package.json", b"4.17.20");
+        write(&a, "lodash/map.js", b"map");
+        write(&a, "left-pad/index.js", b"pad");
+        write(&b, "lodash/package.json", b"4.18.0-beta");
+        write(&b, "lodash/map.js", b"map");
+        write(&b, "@types/node/index.d.ts", b"types");
+
+        let report = diff_trees(&a, &b, Ecosystem::Node).unwrap();
+        assert_eq!(
+            report.packages,
+            vec![
+                PackageDiff {
+                    package: "@types/node".into(),
+                    added: vec!["@types/node/index.d.ts".into()],
+                    ..Default::default()
+                },
+                PackageDiff {
+                    package: "left-pad".into(),
+                    removed: vec!["left-pad/index.js".into()],
+                    ..Default::default()
+                },
+                PackageDiff {
+                    package: "lodash".into(),
+                    modified: vec!["lodash/package.json".into()],
+                    ..Default::default()
+                },
+            ]
+        );
+        assert!(report.render().starts_with("+ @types/node (+1 -0 ~0)\n"));
+        assert!(diff_trees(&a, &a, Ecosystem::Node).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_skips_volume_metadata_and_unreadable_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("diff-meta");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        write(&a, "lodash/map.js", b"map");
+        write(&b, "lodash/map.js", b"map");
+        write(&b, ".fseventsd/0000001", b"events");
+        write(&b, ".Trashes/501/x", b"trash");
+        write(&b, "lodash/.DS_Store", b"finder");
+        write(&b, "locked/index.js", b"locked");
+        let locked = b.join("locked");
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let report = diff_trees(&a, &b, Ecosystem::Node);
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let report = report.unwrap();
+        // Only metadata at the volume root is skipped
+        assert!(!report.packages.iter().any(|p| p.package.starts_with('.')));
+        let lodash = report.packages.iter().find(|p| p.package == "lodash");
+        assert_eq!(lodash.unwrap().added, vec!["lodash/.DS_Store"]);
+        // Root can read anything, so the directory may have been compared
+        if report.unreadable.is_empty() {
+            assert!(std::fs::read_dir(&locked).is_ok());
+        } else {
+            assert_eq!(report.unreadable, vec![PathBuf::from("locked")]);
+            assert!(report
+                .render()
+                .starts_with("! locked (unreadable, not compared)\n"));
+        }
+        assert!(diff_trees(dir.join("missing"), &b, Ecosystem::Node).is_err());
+    }
+}
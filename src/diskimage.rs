@@ -1,19 +1,21 @@
-use std::path::Path;
-use std::process::Command;
-
-#[derive(Debug, Clone, PartialEq)]
+use crate::activity::{self, OpenProcess, Signal};
+use crate::output::{self, Event};
+use crate::secret::Passphrase;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Format {
     RAW,
+    #[default]
     ASIF,
     UDSB,
 }
 
-impl Default for Format {
-    fn default() -> Self {
-        Format::ASIF
-    }
-}
-
 impl std::fmt::Display for Format {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -24,17 +26,80 @@ impl std::fmt::Display for Format {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FileSystem {
+    #[default]
     APFS,
+    #[serde(rename = "apfs-case-sensitive")]
+    APFSCaseSensitive,
+    #[serde(rename = "hfs+")]
+    HFSPlus,
+    #[serde(rename = "hfs+-case-sensitive")]
+    HFSPlusCaseSensitive,
     ExFAT,
     MSDOS,
     None,
 }
 
-impl Default for FileSystem {
-    fn default() -> Self {
-        FileSystem::APFS
+impl FileSystem {
+    /// Name passed to `diskutil --fs`
+    pub fn diskutil_name(&self) -> &'static str {
+        match self {
+            FileSystem::APFS => "apfs",
+            FileSystem::APFSCaseSensitive => "Case-sensitive APFS",
+            FileSystem::HFSPlus => "JHFS+",
+            FileSystem::HFSPlusCaseSensitive => "Case-sensitive JHFS+",
+            FileSystem::ExFAT => "exfat",
+            FileSystem::MSDOS => "ms-dos",
+            FileSystem::None => "none",
+        }
+    }
+
+    /// Filesystem of a `File System Personality` shown by `diskutil info`
+    pub fn from_personality(personality: &str) -> Option<Self> {
+        let personality = personality.trim().to_lowercase();
+        let case_sensitive = personality.starts_with("case-sensitive");
+        if personality.ends_with("apfs") {
+            Some(if case_sensitive {
+                FileSystem::APFSCaseSensitive
+            } else {
+                FileSystem::APFS
+            })
+        } else if personality.ends_with("hfs+") {
+            Some(if case_sensitive {
+                FileSystem::HFSPlusCaseSensitive
+            } else {
+                FileSystem::HFSPlus
+            })
+        } else if personality == "exfat" {
+            Some(FileSystem::ExFAT)
+        } else if personality.starts_with("ms-dos") {
+            Some(FileSystem::MSDOS)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the volume can honour file ownership
+    pub fn supports_ownership(&self) -> bool {
+        matches!(
+            self,
+            FileSystem::APFS
+                | FileSystem::APFSCaseSensitive
+                | FileSystem::HFSPlus
+                | FileSystem::HFSPlusCaseSensitive
+        )
+    }
+
+    /// Longest volume name the filesystem accepts, in bytes
+    pub fn max_volume_name_len(&self) -> usize {
+        match self {
+            FileSystem::MSDOS => 11,
+            FileSystem::ExFAT => 15,
+            FileSystem::None => 0,
+            _ => 255,
+        }
     }
 }
 
@@ -42,6 +107,9 @@ impl std::fmt::Display for FileSystem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             FileSystem::APFS => write!(f, "APFS"),
+            FileSystem::APFSCaseSensitive => write!(f, "Case-sensitive APFS"),
+            FileSystem::HFSPlus => write!(f, "HFS+"),
+            FileSystem::HFSPlusCaseSensitive => write!(f, "Case-sensitive HFS+"),
             FileSystem::ExFAT => write!(f, "ExFAT"),
             FileSystem::MSDOS => write!(f, "MS-DOS"),
             FileSystem::None => write!(f, "None"),
@@ -49,11 +117,41 @@ impl std::fmt::Display for FileSystem {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Encryption of a new image, the passphrase is passed on stdin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Encryption {
+    #[serde(rename = "aes-128", alias = "AES-128")]
+    #[value(name = "aes-128")]
+    AES128,
+    #[serde(rename = "aes-256", alias = "AES-256")]
+    #[value(name = "aes-256")]
+    AES256,
+}
+
+impl std::fmt::Display for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encryption::AES128 => write!(f, "AES-128"),
+            Encryption::AES256 => write!(f, "AES-256"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct AttachOptions {
     pub mount_point: Option<String>,
     pub readonly: bool,
+    /// Keep the volume out of Finder and off the Desktop
     pub nobrowse: bool,
+    pub shadow: Option<String>,
+    /// Do not update access times
+    pub noatime: bool,
+    /// Ignore setuid and setgid bits
+    pub nosuid: bool,
+    /// Honour (`Some(true)`) or ignore (`Some(false)`) file ownership
+    pub owners: Option<bool>,
+    /// Passphrase of an encrypted image
+    pub passphrase: Option<Passphrase>,
     pub verbose: bool,
     pub dry_run: bool,
 }
@@ -78,6 +176,56 @@ impl AttachOptions {
         self
     }
 
+    /// Mount at `mount_point` without showing the volume in Finder or on the Desktop
+    pub fn hidden_at(self, mount_point: impl Into<String>) -> Self {
+        self.with_mount_point(mount_point).nobrowse()
+    }
+
+    pub fn noatime(mut self) -> Self {
+        self.noatime = true;
+        self
+    }
+
+    pub fn nosuid(mut self) -> Self {
+        self.nosuid = true;
+        self
+    }
+
+    pub fn with_owners(mut self, owners: bool) -> Self {
+        self.owners = Some(owners);
+        self
+    }
+
+    pub fn with_passphrase(mut self, passphrase: Passphrase) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    /// Program attaching the image, hdiutil when diskutil cannot honour the options
+    pub fn program(&self) -> &'static str {
+        if self.needs_hdiutil() {
+            "hdiutil"
+        } else {
+            "diskutil"
+        }
+    }
+
+    /// Whether hdiutil is needed, diskutil image attach only knows mount point and read-only
+    fn needs_hdiutil(&self) -> bool {
+        self.shadow.is_some()
+            || self.passphrase.is_some()
+            || self.nobrowse
+            || self.noatime
+            || self.nosuid
+            || self.owners.is_some()
+    }
+
+    /// Redirect writes to a shadow file, leaving the image itself untouched
+    pub fn with_shadow(mut self, shadow: impl Into<String>) -> Self {
+        self.shadow = Some(shadow.into());
+        self
+    }
+
     pub fn verbose(mut self) -> Self {
         self.verbose = true;
         self
@@ -94,23 +242,16 @@ impl AttachOptions {
     }
 }
 
-impl Default for AttachOptions {
-    fn default() -> Self {
-        Self {
-            mount_point: None,
-            readonly: false,
-            nobrowse: false,
-            verbose: false,
-            dry_run: false,
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct CreateBlankOptions {
     pub size: String,
     pub fs: FileSystem,
     pub format: Format,
+    pub volume_name: Option<String>,
+    /// Honour file ownership on the volume, applied with `DiskImage::enable_ownership`
+    /// once it is mounted
+    pub owners: bool,
+    pub encryption: Option<(Encryption, Passphrase)>,
     pub dry_run: bool,
     pub verbose: bool,
 }
@@ -121,11 +262,52 @@ impl CreateBlankOptions {
             size: size.into(),
             fs,
             format,
+            volume_name: None,
+            owners: false,
+            encryption: None,
             dry_run: false,
             verbose: false,
         }
     }
 
+    pub fn with_volume_name(mut self, volume_name: impl Into<String>) -> Self {
+        self.volume_name = Some(volume_name.into());
+        self
+    }
+
+    pub fn with_owners(mut self, owners: bool) -> Self {
+        self.owners = owners;
+        self
+    }
+
+    pub fn with_encryption(mut self, encryption: Encryption, passphrase: Passphrase) -> Self {
+        self.encryption = Some((encryption, passphrase));
+        self
+    }
+
+    /// Reject option combinations the filesystem cannot honour
+    pub fn validate(&self) -> std::result::Result<(), ValidationError> {
+        if let Some(name) = &self.volume_name {
+            if self.fs == FileSystem::None {
+                return Err(ValidationError::VolumeNameWithoutFileSystem);
+            }
+            if name.is_empty() || name.contains([':', '/']) {
+                return Err(ValidationError::InvalidVolumeName(name.clone()));
+            }
+            if name.len() > self.fs.max_volume_name_len() {
+                return Err(ValidationError::VolumeNameTooLong {
+                    name: name.clone(),
+                    fs: self.fs.clone(),
+                    max: self.fs.max_volume_name_len(),
+                });
+            }
+        }
+        if self.owners && !self.fs.supports_ownership() {
+            return Err(ValidationError::OwnershipNotSupported(self.fs.clone()));
+        }
+        Ok(())
+    }
+
     pub fn with_dry_run(mut self, dry_run: bool) -> Self {
         self.dry_run = dry_run;
         self
@@ -137,9 +319,10 @@ impl CreateBlankOptions {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CreateFromOptions {
     pub format: Format,
+    pub encryption: Option<(Encryption, Passphrase)>,
     pub dry_run: bool,
     pub verbose: bool,
 }
@@ -148,11 +331,17 @@ impl CreateFromOptions {
     pub fn new(format: Format) -> Self {
         Self {
             format,
+            encryption: None,
             dry_run: false,
             verbose: false,
         }
     }
 
+    pub fn with_encryption(mut self, encryption: Encryption, passphrase: Passphrase) -> Self {
+        self.encryption = Some((encryption, passphrase));
+        self
+    }
+
     pub fn with_dry_run(mut self, dry_run: bool) -> Self {
         self.dry_run = dry_run;
         self
@@ -170,16 +359,9 @@ impl Default for CreateBlankOptions {
             size: "1GB".to_string(),
             fs: FileSystem::None,
             format: Format::default(),
-            dry_run: false,
-            verbose: false,
-        }
-    }
-}
-
-impl Default for CreateFromOptions {
-    fn default() -> Self {
-        Self {
-            format: Format::default(),
+            volume_name: None,
+            owners: false,
+            encryption: None,
             dry_run: false,
             verbose: false,
         }
@@ -189,6 +371,8 @@ impl Default for CreateFromOptions {
 #[derive(Debug, Clone)]
 pub struct ResizeOptions {
     pub size: String,
+    /// Passphrase of an encrypted image
+    pub passphrase: Option<Passphrase>,
     pub dry_run: bool,
     pub verbose: bool,
 }
@@ -197,11 +381,17 @@ impl ResizeOptions {
     pub fn new(size: impl Into<String>) -> Self {
         Self {
             size: size.into(),
+            passphrase: None,
             dry_run: false,
             verbose: false,
         }
     }
 
+    pub fn with_passphrase(mut self, passphrase: Passphrase) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
     pub fn with_dry_run(mut self, dry_run: bool) -> Self {
         self.dry_run = dry_run;
         self
@@ -213,79 +403,681 @@ impl ResizeOptions {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct DetachOptions {
+    /// Attempts after the first while the volume is busy
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each further one
+    pub backoff: Duration,
+    /// Sent to the blocking processes before each retry
+    pub signal: Option<Signal>,
+    /// Force the unmount once the retries are used up
+    pub force: bool,
+    pub dry_run: bool,
+    pub verbose: bool,
+}
+
+impl DetachOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_signal(mut self, signal: Signal) -> Self {
+        self.signal = Some(signal);
+        self
+    }
+
+    pub fn force(mut self) -> Self {
+        self.force = true;
+        self
+    }
+
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+}
+
+impl Default for DetachOptions {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            signal: None,
+            force: false,
+            dry_run: false,
+            verbose: false,
+        }
+    }
+}
+
+/// Options that cannot be combined, caught before running diskutil
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    VolumeNameWithoutFileSystem,
+    InvalidVolumeName(String),
+    VolumeNameTooLong {
+        name: String,
+        fs: FileSystem,
+        max: usize,
+    },
+    OwnershipNotSupported(FileSystem),
+    /// Only diskutil encrypts images, mkfs formats them in the clear
+    EncryptionNeedsDiskutil,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::VolumeNameWithoutFileSystem => {
+                write!(f, "a volume name needs a filesystem")
+            }
+            ValidationError::InvalidVolumeName(name) => {
+                write!(f, "invalid volume name {:?}", name)
+            }
+            ValidationError::VolumeNameTooLong { name, fs, max } => write!(
+                f,
+                "volume name {:?} is longer than the {} bytes {} allows",
+                name, max, fs
+            ),
+            ValidationError::OwnershipNotSupported(fs) => {
+                write!(f, "{} does not support file ownership", fs)
+            }
+            ValidationError::EncryptionNeedsDiskutil => {
+                write!(f, "encrypted images can only be created with diskutil")
+            }
+        }
+    }
+}
+
+/// Cause of a failure, classified from the failing command's output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    Busy,
+    NoSpace,
+    AlreadyAttached,
+    UnsupportedFormat,
+    Permission,
+    NotFound,
+    Other,
+}
+
+impl ErrorKind {
+    /// Classify the stderr of diskutil, hdiutil and friends
+    pub fn classify(stderr: &str) -> Self {
+        const PATTERNS: [(ErrorKind, &[&str]); 6] = [
+            (
+                ErrorKind::NoSpace,
+                &[
+                    "no space left",
+                    "not enough space",
+                    "disk full",
+                    "quota exceeded",
+                ],
+            ),
+            (
+                ErrorKind::Busy,
+                &[
+                    "busy",
+                    "dissented",
+                    "failed to unmount",
+                    "could not be unmounted",
+                ],
+            ),
+            (
+                ErrorKind::AlreadyAttached,
+                &["already attached", "already mounted", "already in use"],
+            ),
+            (
+                ErrorKind::UnsupportedFormat,
+                &[
+                    "not recognized",
+                    "unsupported",
+                    "unknown format",
+                    "invalid image",
+                ],
+            ),
+            (
+                ErrorKind::Permission,
+                &[
+                    "permission denied",
+                    "not permitted",
+                    "not authorized",
+                    "authentication",
+                ],
+            ),
+            (
+                ErrorKind::NotFound,
+                &[
+                    "no such file",
+                    "not found",
+                    "does not exist",
+                    "could not find",
+                ],
+            ),
+        ];
+        let stderr = stderr.to_lowercase();
+        PATTERNS
+            .iter()
+            .find(|(_, patterns)| patterns.iter().any(|p| stderr.contains(p)))
+            .map_or(ErrorKind::Other, |(kind, _)| *kind)
+    }
+
+    /// Classify a failed system call
+    pub fn from_io(kind: std::io::ErrorKind) -> Self {
+        match kind {
+            std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorKind::Permission,
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                ErrorKind::NoSpace
+            }
+            std::io::ErrorKind::ResourceBusy => ErrorKind::Busy,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Busy => write!(f, "busy"),
+            ErrorKind::NoSpace => write!(f, "no-space"),
+            ErrorKind::AlreadyAttached => write!(f, "already-attached"),
+            ErrorKind::UnsupportedFormat => write!(f, "unsupported-format"),
+            ErrorKind::Permission => write!(f, "permission"),
+            ErrorKind::NotFound => write!(f, "not-found"),
+            ErrorKind::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// A command that ran and exited unsuccessfully
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandError {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Exit code, `None` when the command was killed by a signal
+    pub status: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub kind: ErrorKind,
+}
+
+impl CommandError {
+    /// Failure of `program args`, classified by its stderr
+    pub fn new(
+        program: impl Into<String>,
+        args: &[String],
+        status: Option<i32>,
+        stdout: impl Into<String>,
+        stderr: impl Into<String>,
+    ) -> Self {
+        let stderr = stderr.into();
+        Self {
+            program: program.into(),
+            args: args.to_vec(),
+            status,
+            stdout: stdout.into(),
+            kind: ErrorKind::classify(&stderr),
+            stderr,
+        }
+    }
+
+    /// The command line, as it would be typed
+    pub fn command_line(&self) -> String {
+        std::iter::once(&self.program)
+            .chain(&self.args)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` ", self.command_line())?;
+        match self.status {
+            Some(code) => write!(f, "exited with status {}", code)?,
+            None => write!(f, "was killed")?,
+        }
+        let stderr = self.stderr.trim();
+        if !stderr.is_empty() {
+            write!(f, ": {}", stderr)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum DiskImageError {
-    CommandFailed(String),
+    /// An external command failed, boxed as it carries its whole output
+    Command(Box<CommandError>),
+    /// An external command could not be started
+    Spawn {
+        program: String,
+        error: std::io::Error,
+    },
+    /// A command succeeded but its output could not be understood
+    UnexpectedOutput {
+        program: String,
+        output: String,
+    },
+    /// No passphrase could be read or stored
+    Passphrase {
+        message: String,
+        kind: ErrorKind,
+    },
+    /// Some of several images failed, each already reported, classified as the first
+    Incomplete {
+        failed: usize,
+        action: &'static str,
+        kind: ErrorKind,
+    },
+    /// applesauce could not (de)compress some files of `path`
+    Compression {
+        path: PathBuf,
+        errors: Vec<String>,
+    },
+    /// A branch variant was asked for while HEAD is detached
+    DetachedHead(PathBuf),
     InvalidPath(String),
     InvalidSize(String),
-    DiskutilNotFound,
+    Registry(String),
+    Config(String),
+    Policy(String),
+    Validation(ValidationError),
+    Daemon(String),
+    /// A plan file could not be read
+    Plan(String),
+    /// The volume could not be unmounted because files on it are open
+    Busy {
+        mount_point: PathBuf,
+        processes: Vec<OpenProcess>,
+    },
+    Io(std::io::Error),
 }
 
 impl std::fmt::Display for DiskImageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            DiskImageError::CommandFailed(msg) => write!(f, "Command failed: {}", msg),
+            DiskImageError::Command(e) => write!(f, "Command failed: {}", e),
+            DiskImageError::Spawn { program, error } => {
+                write!(f, "Could not run {}: {}", program, error)
+            }
+            DiskImageError::UnexpectedOutput { program, output } => {
+                write!(f, "Unexpected {} output: {}", program, output.trim())
+            }
+            DiskImageError::Passphrase { message, .. } => write!(f, "{}", message),
+            DiskImageError::Incomplete { failed, action, .. } => {
+                write!(f, "{} image(s) could not be {}", failed, action)
+            }
+            DiskImageError::Compression { path, errors } => {
+                write!(f, "Could not compress {}", path.display())?;
+                if let Some(first) = errors.first() {
+                    write!(f, ", {}", first)?;
+                }
+                if errors.len() > 1 {
+                    write!(f, " and {} more", errors.len() - 1)?;
+                }
+                Ok(())
+            }
+            DiskImageError::DetachedHead(project) => write!(
+                f,
+                "HEAD of {} is detached, no branch to switch to",
+                project.display()
+            ),
             DiskImageError::InvalidPath(path) => write!(f, "Invalid path: {}", path),
             DiskImageError::InvalidSize(size) => write!(f, "Invalid size: {}", size),
-            DiskImageError::DiskutilNotFound => write!(f, "diskutil command not found"),
+            DiskImageError::Registry(msg) => write!(f, "Invalid registry: {}", msg),
+            DiskImageError::Config(msg) => write!(f, "Invalid config: {}", msg),
+            DiskImageError::Policy(msg) => write!(f, "Policy violation: {}", msg),
+            DiskImageError::Validation(e) => write!(f, "Invalid options: {}", e),
+            DiskImageError::Daemon(msg) => write!(f, "afpackd: {}", msg),
+            DiskImageError::Plan(msg) => write!(f, "Invalid plan: {}", msg),
+            DiskImageError::Busy {
+                mount_point,
+                processes,
+            } => {
+                write!(f, "{} is busy", mount_point.display())?;
+                for (i, process) in processes.iter().enumerate() {
+                    write!(
+                        f,
+                        "{} {}",
+                        if i == 0 { ", in use by" } else { "," },
+                        process
+                    )?;
+                }
+                Ok(())
+            }
+            DiskImageError::Io(e) => write!(f, "I/O error: {}", e),
         }
     }
 }
 
 impl std::error::Error for DiskImageError {}
 
+impl DiskImageError {
+    /// What kind of failure this is, `Other` unless it could be classified
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            DiskImageError::Command(e) => e.kind,
+            DiskImageError::Spawn { error, .. } => ErrorKind::from_io(error.kind()),
+            DiskImageError::Passphrase { kind, .. } | DiskImageError::Incomplete { kind, .. } => {
+                *kind
+            }
+            DiskImageError::Compression { errors, .. } => ErrorKind::classify(&errors.join("\n")),
+            DiskImageError::Busy { .. } => ErrorKind::Busy,
+            DiskImageError::Io(e) => ErrorKind::from_io(e.kind()),
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl From<CommandError> for DiskImageError {
+    fn from(e: CommandError) -> Self {
+        DiskImageError::Command(Box::new(e))
+    }
+}
+
+impl From<ValidationError> for DiskImageError {
+    fn from(e: ValidationError) -> Self {
+        DiskImageError::Validation(e)
+    }
+}
+
+impl From<std::io::Error> for DiskImageError {
+    fn from(e: std::io::Error) -> Self {
+        DiskImageError::Io(e)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, DiskImageError>;
 
+/// Runs external commands, replaced by a fake in tests
+pub trait CommandRunner {
+    /// Run `program` with `args`, feeding it `stdin`, and return its stdout
+    fn run(&self, program: &str, args: &[String], stdin: Option<&str>) -> Result<String>;
+}
+
+/// Runs commands on the host
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[String], stdin: Option<&str>) -> Result<String> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| DiskImageError::Spawn {
+                program: program.to_string(),
+                error,
+            })?;
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(input.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+
+        if !output.status.success() {
+            return Err(CommandError::new(
+                program,
+                args,
+                output.status.code(),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr),
+            )
+            .into());
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout.to_string())
+    }
+}
+
 pub struct DiskImage;
 
 impl DiskImage {
     /// Attach a disk image
     pub fn attach<P: AsRef<Path>>(image_path: P, options: AttachOptions) -> Result<String> {
-        let path = image_path.as_ref();
-        // if !path.exists() {
-        //     return Err(DiskImageError::InvalidPath(path.display().to_string()));
-        // }
+        Self::attach_with(&SystemRunner, image_path, options)
+    }
 
-        let mut cmd = Command::new("diskutil");
-        cmd.arg("image").arg("attach");
+    /// Attach a disk image, running commands through `runner`
+    pub fn attach_with<P: AsRef<Path>>(
+        runner: &dyn CommandRunner,
+        image_path: P,
+        options: AttachOptions,
+    ) -> Result<String> {
+        let path = image_path.as_ref();
 
         if let Some(mount_point) = &options.mount_point {
             // Only create directory if it doesn't exist and not in dry run
-            if !Path::new(mount_point).exists() {
-                if !options.dry_run {
-                    std::fs::create_dir_all(Path::new(mount_point))
-                        .map_err(|e| DiskImageError::CommandFailed(e.to_string()))?;
-                }
+            if !Path::new(mount_point).exists() && !options.dry_run {
+                std::fs::create_dir_all(Path::new(mount_point))?;
             }
-            cmd.arg("--mountPoint").arg(mount_point);
         }
 
+        let (program, args) = Self::attach_args(path, &options);
+        Self::run(
+            runner,
+            program,
+            &args,
+            options.passphrase.as_ref(),
+            options.dry_run,
+            options.verbose,
+        )
+    }
+
+    fn attach_args(path: &Path, options: &AttachOptions) -> (&'static str, Vec<String>) {
+        // diskutil image attach has no shadow or mount option support, hdiutil does
+        if !options.needs_hdiutil() {
+            let mut args: Vec<String> = vec!["image".into(), "attach".into()];
+            if let Some(mount_point) = &options.mount_point {
+                args.push("--mountPoint".into());
+                args.push(mount_point.clone());
+            }
+            if options.readonly {
+                args.push("--readOnly".into());
+            }
+            if options.verbose {
+                args.push("--verbose".into());
+            }
+            args.push(path.display().to_string());
+            return ("diskutil", args);
+        }
+
+        let mut args: Vec<String> = vec!["attach".into()];
+        if let Some(shadow) = &options.shadow {
+            args.push("-shadow".into());
+            args.push(shadow.clone());
+        }
+        if let Some(mount_point) = &options.mount_point {
+            args.push("-mountpoint".into());
+            args.push(mount_point.clone());
+        }
+        if options.readonly {
+            args.push("-readonly".into());
+        }
+        if options.passphrase.is_some() {
+            args.push("-stdinpass".into());
+        }
+        if options.nobrowse {
+            args.push("-nobrowse".into());
+        }
+        if let Some(owners) = options.owners {
+            args.push("-owners".into());
+            args.push(if owners { "on" } else { "off" }.into());
+        }
+        let mount_options: Vec<&str> = [("noatime", options.noatime), ("nosuid", options.nosuid)]
+            .iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| *name)
+            .collect();
+        if !mount_options.is_empty() {
+            args.push("-mountoptions".into());
+            args.push(mount_options.join(","));
+        }
         if options.verbose {
-            cmd.arg("--verbose");
+            args.push("-verbose".into());
         }
+        args.push(path.display().to_string());
+        ("hdiutil", args)
+    }
 
-        cmd.arg(path);
+    /// Mount an attached block device, e.g. an unlocked Linux loop device
+    ///
+    /// The options map to mount flags: `ro`, `noatime`, `nosuid`, and
+    /// `x-gvfs-hide` to keep the volume out of file managers. Linux filesystems
+    /// keep their own ownership rules, `owners` has no flag there.
+    ///
+    /// With a shadow, a directory here, the device is mounted read-only at
+    /// `<shadow>/lower` and an overlay keeping writes in `<shadow>/upper` is
+    /// mounted at the mount point.
+    pub fn mount(device: &str, options: AttachOptions) -> Result<String> {
+        Self::mount_with(&SystemRunner, device, options)
+    }
 
-        if options.dry_run {
-            let cmd_str = format!("{:?}", cmd);
-            println!("[DRY RUN] Would execute: {}", cmd_str);
-            return Ok(format!("[DRY RUN] Command: {}", cmd_str));
+    /// Mount an attached block device, running mount through `runner`
+    pub fn mount_with(
+        runner: &dyn CommandRunner,
+        device: &str,
+        options: AttachOptions,
+    ) -> Result<String> {
+        let mount_point = options
+            .mount_point
+            .clone()
+            .ok_or_else(|| DiskImageError::InvalidPath(format!("no mount point for {}", device)))?;
+        let Some(shadow) = &options.shadow else {
+            return Self::mount_at(runner, device, &mount_point, &options);
+        };
+        let shadow = Path::new(shadow);
+        let [lower, upper, work] = ["lower", "upper", "work"].map(|dir| shadow.join(dir));
+        if !options.dry_run {
+            for dir in [&upper, &work] {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        let lower = lower.display().to_string();
+        let base = AttachOptions {
+            shadow: None,
+            ..options.clone()
+        }
+        .readonly();
+        Self::mount_at(runner, device, &lower, &base)?;
+
+        let mut flags = vec![
+            format!("lowerdir={}", overlay_escape(&lower)),
+            format!("upperdir={}", overlay_escape(&upper.display().to_string())),
+            format!("workdir={}", overlay_escape(&work.display().to_string())),
+        ];
+        flags.extend(Self::mount_flags(&options).into_iter().map(String::from));
+        let mut args = vec!["-t".to_string(), "overlay".to_string()];
+        args.push("-o".to_string());
+        args.push(flags.join(","));
+        args.push("overlay".to_string());
+        args.push(mount_point.clone());
+        if !Path::new(&mount_point).exists() && !options.dry_run {
+            std::fs::create_dir_all(&mount_point)?;
         }
+        Self::run(
+            runner,
+            "mount",
+            &args,
+            None,
+            options.dry_run,
+            options.verbose,
+        )
+    }
 
-        if options.verbose {
-            let cmd_str = format!("{:?}", cmd);
-            println!("[VERBOSE] Executing: {}", cmd_str);
+    /// `mount -o <flags> <device> <mount point>`
+    fn mount_at(
+        runner: &dyn CommandRunner,
+        device: &str,
+        mount_point: &str,
+        options: &AttachOptions,
+    ) -> Result<String> {
+        if !Path::new(mount_point).exists() && !options.dry_run {
+            std::fs::create_dir_all(mount_point)?;
         }
+        let mut args = Vec::new();
+        let flags = Self::mount_flags(options);
+        if !flags.is_empty() {
+            args.push("-o".to_string());
+            args.push(flags.join(","));
+        }
+        args.push(device.to_string());
+        args.push(mount_point.to_string());
+        Self::run(
+            runner,
+            "mount",
+            &args,
+            None,
+            options.dry_run,
+            options.verbose,
+        )
+    }
 
-        let output = cmd.output().map_err(|_| DiskImageError::DiskutilNotFound)?;
+    fn mount_flags(options: &AttachOptions) -> Vec<&'static str> {
+        [
+            ("ro", options.readonly),
+            ("noatime", options.noatime),
+            ("nosuid", options.nosuid),
+            ("x-gvfs-hide", options.nobrowse),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(flag, _)| *flag)
+        .collect()
+    }
 
-        if !output.status.success() {
-            let error_msg = String::from_utf8_lossy(&output.stderr);
-            return Err(DiskImageError::CommandFailed(error_msg.to_string()));
+    /// Print or run a command, honouring dry run and verbose
+    pub(crate) fn run(
+        runner: &dyn CommandRunner,
+        program: &str,
+        args: &[String],
+        passphrase: Option<&Passphrase>,
+        dry_run: bool,
+        verbose: bool,
+    ) -> Result<String> {
+        let _span = tracing::debug_span!("command", program).entered();
+        tracing::debug!(args = %args.join(" "), dry_run, "running {}", program);
+        if dry_run || verbose {
+            output::emit(Event::Command {
+                program: program.to_string(),
+                args: args.to_vec(),
+                dry_run,
+            });
         }
-
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        Ok(stdout.to_string())
+        if dry_run {
+            return Ok(format!("[DRY RUN] Command: {} {}", program, args.join(" ")));
+        }
+        runner.run(program, args, passphrase.map(Passphrase::expose))
     }
 
     /// Create a blank disk image
@@ -293,6 +1085,15 @@ impl DiskImage {
     pub fn create_blank<P: AsRef<Path>>(
         image_path: P,
         options: CreateBlankOptions,
+    ) -> Result<String> {
+        Self::create_blank_with(&SystemRunner, image_path, options)
+    }
+
+    /// Create a blank disk image, running commands through `runner`
+    pub fn create_blank_with<P: AsRef<Path>>(
+        runner: &dyn CommandRunner,
+        image_path: P,
+        options: CreateBlankOptions,
     ) -> Result<String> {
         let path = image_path.as_ref();
 
@@ -300,41 +1101,106 @@ impl DiskImage {
         if !Self::is_valid_size(&options.size) {
             return Err(DiskImageError::InvalidSize(options.size));
         }
+        options.validate()?;
+
+        let args = Self::create_blank_args(path, &options);
+        Self::run(
+            runner,
+            "diskutil",
+            &args,
+            options
+                .encryption
+                .as_ref()
+                .map(|(_, passphrase)| passphrase),
+            options.dry_run,
+            options.verbose,
+        )
+    }
 
-        if options.dry_run {
-            let cmd_str = format!(
-                "diskutil image create blank --fs {} --format {} --size {} {}",
-                options.fs.to_string().to_lowercase(),
-                options.format,
-                options.size,
-                path.display()
-            );
-            println!("[DRY RUN] Would execute: {}", cmd_str);
-            return Ok(format!("[DRY RUN] Command: {}", cmd_str));
+    fn create_blank_args(path: &Path, options: &CreateBlankOptions) -> Vec<String> {
+        let mut args: Vec<String> = vec!["image".into(), "create".into(), "blank".into()];
+        args.push("--fs".into());
+        args.push(options.fs.diskutil_name().into());
+        args.push("--format".into());
+        args.push(options.format.to_string());
+        args.push("--size".into());
+        args.push(options.size.clone());
+        if let Some(name) = &options.volume_name {
+            args.push("--volumeName".into());
+            args.push(name.clone());
         }
+        push_encryption_args(&mut args, options.encryption.as_ref());
+        args.push(path.display().to_string());
+        args
+    }
 
-        let mut cmd = Command::new("diskutil");
-        cmd.arg("image").arg("create").arg("blank");
-
-        cmd.arg("--fs").arg(options.fs.to_string().to_lowercase());
-        cmd.arg("--format").arg(options.format.to_string());
-        cmd.arg("--size").arg(&options.size);
-        cmd.arg(path);
+    /// Format an attached block device, e.g. a Linux loop device, with the filesystem
+    pub fn format(device: &str, options: CreateBlankOptions) -> Result<String> {
+        Self::format_with(&SystemRunner, device, options)
+    }
 
-        if options.verbose {
-            let cmd_str = format!("{:?}", cmd);
-            println!("[VERBOSE] Executing: {}", cmd_str);
+    /// Format an attached block device, running mkfs through `runner`
+    pub fn format_with(
+        runner: &dyn CommandRunner,
+        device: &str,
+        options: CreateBlankOptions,
+    ) -> Result<String> {
+        options.validate()?;
+        if options.encryption.is_some() {
+            return Err(ValidationError::EncryptionNeedsDiskutil.into());
         }
+        match Self::mkfs_args(device, &options) {
+            Some((program, args)) => Self::run(
+                runner,
+                program,
+                &args,
+                None,
+                options.dry_run,
+                options.verbose,
+            ),
+            None => Ok(String::new()),
+        }
+    }
 
-        let output = cmd.output().map_err(|_| DiskImageError::DiskutilNotFound)?;
-
-        if !output.status.success() {
-            let error_msg = String::from_utf8_lossy(&output.stderr);
-            return Err(DiskImageError::CommandFailed(error_msg.to_string()));
+    /// mkfs command formatting `device`, `None` when no filesystem is wanted
+    fn mkfs_args(
+        device: &str,
+        options: &CreateBlankOptions,
+    ) -> Option<(&'static str, Vec<String>)> {
+        // the flag naming the volume, and the one making it case-sensitive
+        let (program, label, case_sensitive) = match options.fs {
+            FileSystem::APFS => ("mkfs.apfs", "-L", false),
+            FileSystem::APFSCaseSensitive => ("mkfs.apfs", "-L", true),
+            FileSystem::HFSPlus => ("mkfs.hfsplus", "-v", false),
+            FileSystem::HFSPlusCaseSensitive => ("mkfs.hfsplus", "-v", true),
+            FileSystem::ExFAT => ("mkfs.exfat", "-L", false),
+            FileSystem::MSDOS => ("mkfs.fat", "-n", false),
+            FileSystem::None => return None,
+        };
+        let mut args = Vec::new();
+        if case_sensitive {
+            args.push("-s".to_string());
+        }
+        if let Some(name) = &options.volume_name {
+            args.push(label.to_string());
+            args.push(name.clone());
         }
+        args.push(device.to_string());
+        Some((program, args))
+    }
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        Ok(stdout.to_string())
+    /// Honour file ownership on a mounted volume
+    /// diskutil enableOwnership ./node_modules
+    pub fn enable_ownership<P: AsRef<Path>>(
+        mount_point: P,
+        dry_run: bool,
+        verbose: bool,
+    ) -> Result<()> {
+        let args = vec![
+            "enableOwnership".to_string(),
+            mount_point.as_ref().display().to_string(),
+        ];
+        Self::run(&SystemRunner, "diskutil", &args, None, dry_run, verbose).map(|_| ())
     }
 
     /// Create disk image from existing image
@@ -343,106 +1209,156 @@ impl DiskImage {
         source_path: P,
         dest_path: Q,
         options: CreateFromOptions,
+    ) -> Result<String> {
+        Self::create_from_with(&SystemRunner, source_path, dest_path, options)
+    }
+
+    /// Create disk image from existing image, running commands through `runner`
+    pub fn create_from_with<P: AsRef<Path>, Q: AsRef<Path>>(
+        runner: &dyn CommandRunner,
+        source_path: P,
+        dest_path: Q,
+        options: CreateFromOptions,
     ) -> Result<String> {
         let source = source_path.as_ref();
         let dest = dest_path.as_ref();
 
-        if options.dry_run {
-            let cmd_str = format!(
-                "diskutil image create from --format {} {} {}",
-                options.format,
-                source.display(),
-                dest.display()
-            );
-            println!("[DRY RUN] Would execute: {}", cmd_str);
-            return Ok(format!("[DRY RUN] Command: {}", cmd_str));
-        }
-
-        if !source.exists() {
+        if !options.dry_run && !source.exists() {
             return Err(DiskImageError::InvalidPath(source.display().to_string()));
         }
 
-        let mut cmd = Command::new("diskutil");
-        cmd.arg("image").arg("create").arg("from");
-        cmd.arg("--format").arg(options.format.to_string());
-        cmd.arg(source);
-        cmd.arg(dest);
-
-        if options.verbose {
-            let cmd_str = format!("{:?}", cmd);
-            println!("[VERBOSE] Executing: {}", cmd_str);
-        }
-
-        let output = cmd.output().map_err(|_| DiskImageError::DiskutilNotFound)?;
-
-        if !output.status.success() {
-            let error_msg = String::from_utf8_lossy(&output.stderr);
-            return Err(DiskImageError::CommandFailed(error_msg.to_string()));
-        }
-
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        Ok(stdout.to_string())
+        let mut args: Vec<String> = vec!["image".into(), "create".into(), "from".into()];
+        args.push("--format".into());
+        args.push(options.format.to_string());
+        push_encryption_args(&mut args, options.encryption.as_ref());
+        args.push(source.display().to_string());
+        args.push(dest.display().to_string());
+        Self::run(
+            runner,
+            "diskutil",
+            &args,
+            options
+                .encryption
+                .as_ref()
+                .map(|(_, passphrase)| passphrase),
+            options.dry_run,
+            options.verbose,
+        )
     }
 
     /// Resize a disk image
     pub fn resize<P: AsRef<Path>>(image_path: P, options: ResizeOptions) -> Result<String> {
+        Self::resize_with(&SystemRunner, image_path, options)
+    }
+
+    /// Resize a disk image, running commands through `runner`
+    pub fn resize_with<P: AsRef<Path>>(
+        runner: &dyn CommandRunner,
+        image_path: P,
+        options: ResizeOptions,
+    ) -> Result<String> {
         let path = image_path.as_ref();
 
         if !Self::is_valid_size(&options.size) {
             return Err(DiskImageError::InvalidSize(options.size));
         }
 
-        if options.dry_run {
-            let cmd_str = format!(
-                "diskutil image resize --size {} {}",
-                options.size,
-                path.display()
-            );
-            println!("[DRY RUN] Would execute: {}", cmd_str);
-            return Ok(format!("[DRY RUN] Command: {}", cmd_str));
-        }
-
-        if !path.exists() {
+        if !options.dry_run && !path.exists() {
             return Err(DiskImageError::InvalidPath(path.display().to_string()));
         }
 
-        let mut cmd = Command::new("diskutil");
-        cmd.arg("image").arg("resize");
-        cmd.arg("--size").arg(&options.size);
-        cmd.arg(path);
-
-        if options.verbose {
-            let cmd_str = format!("{:?}", cmd);
-            println!("[VERBOSE] Executing: {}", cmd_str);
+        let mut args: Vec<String> = vec!["image".into(), "resize".into()];
+        args.push("--size".into());
+        args.push(options.size.clone());
+        if options.passphrase.is_some() {
+            args.push("--stdinpass".into());
         }
+        args.push(path.display().to_string());
+        Self::run(
+            runner,
+            "diskutil",
+            &args,
+            options.passphrase.as_ref(),
+            options.dry_run,
+            options.verbose,
+        )
+    }
 
-        let output = cmd.output().map_err(|_| DiskImageError::DiskutilNotFound)?;
-
-        if !output.status.success() {
-            let error_msg = String::from_utf8_lossy(&output.stderr);
-            return Err(DiskImageError::CommandFailed(error_msg.to_string()));
-        }
+    /// Filesystem of the volume mounted at `mount_point`, `None` if diskutil names none we know
+    pub fn volume_filesystem<P: AsRef<Path>>(mount_point: P) -> Result<Option<FileSystem>> {
+        Self::volume_filesystem_with(&SystemRunner, mount_point)
+    }
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        Ok(stdout.to_string())
+    /// Filesystem of a mounted volume, running diskutil through `runner`
+    pub fn volume_filesystem_with<P: AsRef<Path>>(
+        runner: &dyn CommandRunner,
+        mount_point: P,
+    ) -> Result<Option<FileSystem>> {
+        let args = vec![
+            "info".to_string(),
+            mount_point.as_ref().display().to_string(),
+        ];
+        let info = Self::run(runner, "diskutil", &args, None, false, false)?;
+        Ok(info
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("File System Personality:"))
+            .and_then(FileSystem::from_personality))
     }
 
     /// Detach a disk image
-    pub fn detach<P: AsRef<Path>>(mount_point: P) -> Result<String> {
-        let path = mount_point.as_ref();
-
-        let mut cmd = Command::new("diskutil");
-        cmd.arg("unmount").arg(path);
-
-        let output = cmd.output().map_err(|_| DiskImageError::DiskutilNotFound)?;
+    pub fn detach<P: AsRef<Path>>(mount_point: P, options: DetachOptions) -> Result<String> {
+        Self::detach_with(&SystemRunner, mount_point, options)
+    }
 
-        if !output.status.success() {
-            let error_msg = String::from_utf8_lossy(&output.stderr);
-            return Err(DiskImageError::CommandFailed(error_msg.to_string()));
+    /// Detach a disk image, running commands through `runner`
+    ///
+    /// While the volume is busy the unmount is retried with backoff, signalling
+    /// the processes holding it if asked. Without `force` the last failure is a
+    /// [`DiskImageError::Busy`] naming those processes.
+    pub fn detach_with<P: AsRef<Path>>(
+        runner: &dyn CommandRunner,
+        mount_point: P,
+        options: DetachOptions,
+    ) -> Result<String> {
+        let mount_point = mount_point.as_ref();
+        let target = mount_point.display().to_string();
+        let args = vec!["unmount".to_string(), target.clone()];
+        let mut attempt = 0;
+        loop {
+            match Self::run(
+                runner,
+                "diskutil",
+                &args,
+                None,
+                options.dry_run,
+                options.verbose,
+            ) {
+                Err(e) if e.kind() == ErrorKind::Busy => {}
+                result => return result,
+            }
+            let processes = blocking_processes(runner, mount_point);
+            if attempt == options.retries {
+                if options.force {
+                    let args = vec!["unmount".to_string(), "force".to_string(), target];
+                    return Self::run(runner, "diskutil", &args, None, false, options.verbose);
+                }
+                return Err(DiskImageError::Busy {
+                    mount_point: mount_point.to_path_buf(),
+                    processes,
+                });
+            }
+            if let Some(signal) = options.signal {
+                // afpack itself may be one of them, e.g. run from inside the volume
+                for process in processes.iter().filter(|p| p.pid != std::process::id()) {
+                    tracing::info!("sending SIG{} to {}", signal, process);
+                    let _ = activity::signal_with(runner, process.pid, signal);
+                }
+            }
+            let wait = options.backoff.saturating_mul(1 << attempt.min(16));
+            tracing::info!("{} is busy, retrying in {:.1}s", target, wait.as_secs_f64());
+            std::thread::sleep(wait);
+            attempt += 1;
         }
-
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        Ok(stdout.to_string())
     }
 
     /// Check if size format is valid (basic validation)
@@ -461,7 +1377,79 @@ impl DiskImage {
     }
 }
 
+/// Processes with files open on the volume, empty if they cannot be listed
+fn blocking_processes(runner: &dyn CommandRunner, mount_point: &Path) -> Vec<OpenProcess> {
+    #[cfg(target_os = "linux")]
+    let processes = {
+        let _ = runner;
+        activity::processes_using(mount_point)
+    };
+    #[cfg(not(target_os = "linux"))]
+    let processes = activity::processes_using_with(runner, mount_point);
+    processes.unwrap_or_default()
+}
+
+/// Escape the separators of overlay mount options in a path
+fn overlay_escape(path: &str) -> String {
+    path.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(':', "\\:")
+}
+
+/// `--encryption <cipher> --stdinpass` for an encrypted image
+fn push_encryption_args(args: &mut Vec<String>, encryption: Option<&(Encryption, Passphrase)>) {
+    if let Some((encryption, _)) = encryption {
+        args.push("--encryption".into());
+        args.push(encryption.to_string());
+        args.push("--stdinpass".into());
+    }
+}
+
+/// Whether a volume is mounted at `path`, i.e. it is on another device than its parent
+pub fn is_mount_point<P: AsRef<Path>>(path: P) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let path = path.as_ref();
+    let parent = path.parent().unwrap_or(Path::new("/"));
+    match (std::fs::metadata(path), std::fs::metadata(parent)) {
+        (Ok(dir), Ok(parent)) => dir.dev() != parent.dev(),
+        _ => false,
+    }
+}
+
+/// Byte count of a diskutil size such as `20G`, `512MB` or `1.5T` (binary units)
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_lowercase();
+    let digits = size.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match size[digits.len()..].trim_end_matches('b') {
+        "" => 1u64,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        _ => return None,
+    };
+    let value: f64 = digits.trim().parse().ok()?;
+    Some((value * unit as f64) as u64)
+}
+
+/// Human readable byte count, e.g. `1.5 GB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
 // Convenience functions for easier usage
+#[allow(clippy::module_inception)]
 pub mod diskimage {
     use super::*;
 
@@ -493,14 +1481,378 @@ pub mod diskimage {
     }
 
     /// Detach/unmount a disk image
-    pub fn detach<P: AsRef<Path>>(mount_point: P) -> Result<String> {
-        DiskImage::detach(mount_point)
+    pub fn detach<P: AsRef<Path>>(mount_point: P, options: DetachOptions) -> Result<String> {
+        DiskImage::detach(mount_point, options)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::{FakeRunner, TempDir};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_filesystem_from_personality() {
+        assert_eq!(
+            FileSystem::from_personality("  Case-sensitive APFS"),
+            Some(FileSystem::APFSCaseSensitive)
+        );
+        assert_eq!(FileSystem::from_personality("APFS"), Some(FileSystem::APFS));
+        assert_eq!(
+            FileSystem::from_personality("Journaled HFS+"),
+            Some(FileSystem::HFSPlus)
+        );
+        assert_eq!(
+            FileSystem::from_personality("MS-DOS FAT32"),
+            Some(FileSystem::MSDOS)
+        );
+        assert_eq!(FileSystem::from_personality("NTFS"), None);
+    }
+
+    #[test]
+    fn test_attach_argv_diskutil() {
+        let runner = FakeRunner::default();
+        let mount = std::env::temp_dir().display().to_string();
+        DiskImage::attach_with(
+            &runner,
+            "nm.asif",
+            AttachOptions::new().with_mount_point(&mount).readonly(),
+        )
+        .unwrap();
+        assert_eq!(
+            runner.argv().pop().unwrap(),
+            vec![
+                "diskutil",
+                "image",
+                "attach",
+                "--mountPoint",
+                &mount,
+                "--readOnly",
+                "nm.asif"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_attach_argv_hdiutil() {
+        let runner = FakeRunner::default();
+        let mount = std::env::temp_dir().display().to_string();
+        DiskImage::attach_with(
+            &runner,
+            "nm.asif",
+            AttachOptions::new()
+                .hidden_at(&mount)
+                .with_shadow("nm.shadow")
+                .noatime()
+                .nosuid()
+                .with_owners(false),
+        )
+        .unwrap();
+        assert_eq!(
+            runner.argv().pop().unwrap(),
+            vec![
+                "hdiutil",
+                "attach",
+                "-shadow",
+                "nm.shadow",
+                "-mountpoint",
+                &mount,
+                "-nobrowse",
+                "-owners",
+                "off",
+                "-mountoptions",
+                "noatime,nosuid",
+                "nm.asif"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mount_argv_linux() {
+        let runner = FakeRunner::new();
+        let dir = TempDir::new("mount");
+        let mount = dir.join("node_modules").display().to_string();
+        DiskImage::mount_with(
+            &runner,
+            "/dev/loop3",
+            AttachOptions::new()
+                .hidden_at(&mount)
+                .readonly()
+                .noatime()
+                .nosuid()
+                .with_owners(false),
+        )
+        .unwrap();
+        assert!(Path::new(&mount).is_dir());
+        DiskImage::mount_with(
+            &runner,
+            "/dev/loop4",
+            AttachOptions::new().with_mount_point(&mount),
+        )
+        .unwrap();
+        assert_eq!(
+            runner.argv(),
+            vec![
+                vec![
+                    "mount",
+                    "-o",
+                    "ro,noatime,nosuid,x-gvfs-hide",
+                    "/dev/loop3",
+                    &mount
+                ],
+                vec!["mount", "/dev/loop4", &mount],
+            ]
+        );
+        assert!(DiskImage::mount_with(&runner, "/dev/loop5", AttachOptions::new()).is_err());
+    }
+
+    #[test]
+    fn test_mount_overlay_linux() {
+        let runner = FakeRunner::new();
+        let dir = TempDir::new("overlay");
+        let shadow = dir.join("wt,1.shadow");
+        let mount = dir.join("node_modules").display().to_string();
+        DiskImage::mount_with(
+            &runner,
+            "/dev/loop3",
+            AttachOptions::new()
+                .with_mount_point(&mount)
+                .with_shadow(shadow.display().to_string())
+                .noatime(),
+        )
+        .unwrap();
+        assert!(shadow.join("upper").is_dir() && shadow.join("work").is_dir());
+        let escaped = dir.display().to_string().replace(':', "\\:");
+        assert_eq!(
+            runner.calls(),
+            vec![
+                format!(
+                    "mount -o ro,noatime /dev/loop3 {}",
+                    shadow.join("lower").display()
+                ),
+                format!(
+                    "mount -t overlay -o lowerdir={0}/wt\\,1.shadow/lower,\
+                     upperdir={0}/wt\\,1.shadow/upper,workdir={0}/wt\\,1.shadow/work,noatime \
+                     overlay {1}",
+                    escaped, mount
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encrypted_create_and_attach() {
+        let runner = FakeRunner::default();
+        let passphrase = Passphrase::new("s3cret");
+        let options = CreateBlankOptions::new("10G", FileSystem::APFS, Format::ASIF)
+            .with_encryption(Encryption::AES256, passphrase.clone());
+        DiskImage::create_blank_with(&runner, "sdk.asif", options).unwrap();
+        assert_eq!(
+            runner.argv().pop().unwrap(),
+            vec![
+                "diskutil",
+                "image",
+                "create",
+                "blank",
+                "--fs",
+                "apfs",
+                "--format",
+                "ASIF",
+                "--size",
+                "10G",
+                "--encryption",
+                "AES-256",
+                "--stdinpass",
+                "sdk.asif"
+            ]
+        );
+        assert_eq!(runner.stdin().pop().unwrap().as_deref(), Some("s3cret"));
+
+        DiskImage::attach_with(
+            &runner,
+            "sdk.asif",
+            AttachOptions::new().with_passphrase(passphrase),
+        )
+        .unwrap();
+        assert_eq!(
+            runner.argv().pop().unwrap(),
+            vec!["hdiutil", "attach", "-stdinpass", "sdk.asif"]
+        );
+        assert_eq!(runner.stdin().pop().unwrap().as_deref(), Some("s3cret"));
+
+        DiskImage::detach_with(&runner, "/p/sdk", DetachOptions::new()).unwrap();
+        assert_eq!(runner.stdin().pop().unwrap(), None);
+    }
+
+    #[test]
+    fn test_dry_run_does_not_run() {
+        let runner = FakeRunner::default();
+        let output =
+            DiskImage::attach_with(&runner, "nm.asif", AttachOptions::new().with_dry_run(true))
+                .unwrap();
+        assert_eq!(output, "[DRY RUN] Command: diskutil image attach nm.asif");
+        assert!(runner.calls().is_empty());
+
+        let options = DetachOptions::new().with_dry_run(true);
+        DiskImage::detach_with(&runner, "/p/node_modules", options).unwrap();
+        assert!(runner.calls().is_empty());
+
+        DiskImage::detach_with(&runner, "/p/node_modules", DetachOptions::new()).unwrap();
+        assert_eq!(
+            runner.argv().pop().unwrap(),
+            vec!["diskutil", "unmount", "/p/node_modules"]
+        );
+    }
+
+    /// Fails to unmount while `busy` attempts are left, with node holding the volume
+    fn busy_runner(busy: Rc<Cell<u32>>) -> FakeRunner {
+        FakeRunner::replying(move |program, args, _| match program {
+            "diskutil" if args[1] != "force" && busy.get() > 0 => {
+                busy.set(busy.get() - 1);
+                Err(CommandError::new(
+                    program,
+                    args,
+                    Some(1),
+                    "",
+                    "Volume node_modules on disk6s1 failed to unmount: dissented by PID 812",
+                )
+                .into())
+            }
+            "lsof" => Ok("p812\ncnode\nf23\nn/p/node_modules/.vite/deps\n".to_string()),
+            _ => Ok(String::new()),
+        })
+    }
+
+    #[test]
+    fn test_detach_busy() {
+        let options = DetachOptions::new().with_backoff(Duration::ZERO);
+        let busy = Rc::new(Cell::new(2));
+        let runner = busy_runner(Rc::clone(&busy));
+        DiskImage::detach_with(&runner, "/p/node_modules", options.clone()).unwrap();
+        let unmounts = runner
+            .calls()
+            .iter()
+            .filter(|c| c.starts_with("diskutil"))
+            .count();
+        assert_eq!(unmounts, 3);
+
+        busy.set(u32::MAX);
+        let err =
+            DiskImage::detach_with(&runner, "/p/node_modules", options.clone().with_retries(1))
+                .unwrap_err();
+        assert!(
+            matches!(&err, DiskImageError::Busy { mount_point, .. } if mount_point == Path::new("/p/node_modules")),
+            "{}",
+            err
+        );
+        // lsof is only used on macOS, Linux reads /proc
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(
+            err.to_string(),
+            "/p/node_modules is busy, in use by node (812) /p/node_modules/.vite/deps"
+        );
+
+        runner.clear();
+        let options = options.with_retries(0).with_signal(Signal::Term).force();
+        DiskImage::detach_with(&runner, "/p/node_modules", options).unwrap();
+        assert_eq!(
+            runner.last().unwrap(),
+            "diskutil unmount force /p/node_modules"
+        );
+    }
+
+    #[test]
+    fn test_busy_display() {
+        let err = DiskImageError::Busy {
+            mount_point: PathBuf::from("/p/target"),
+            processes: vec![
+                OpenProcess {
+                    pid: 907,
+                    command: "rust-analyzer".into(),
+                    path: Some("/p/target/debug".into()),
+                },
+                OpenProcess {
+                    pid: 912,
+                    command: "cargo".into(),
+                    path: None,
+                },
+            ],
+        };
+        assert_eq!(
+            err.to_string(),
+            "/p/target is busy, in use by rust-analyzer (907) /p/target/debug, cargo (912)"
+        );
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let cases = [
+            (
+                "hdiutil: couldn't unmount \"disk6\" - Resource busy",
+                ErrorKind::Busy,
+            ),
+            (
+                "hdiutil: create failed - No space left on device",
+                ErrorKind::NoSpace,
+            ),
+            (
+                "hdiutil: attach failed - image not recognized",
+                ErrorKind::UnsupportedFormat,
+            ),
+            (
+                "Could not attach: Operation not permitted",
+                ErrorKind::Permission,
+            ),
+            (
+                "hdiutil: attach failed - No such file or directory",
+                ErrorKind::NotFound,
+            ),
+            (
+                "Unmount failed for /p/target: not mounted",
+                ErrorKind::Other,
+            ),
+        ];
+        for (stderr, kind) in cases {
+            assert_eq!(ErrorKind::classify(stderr), kind, "{}", stderr);
+        }
+
+        let args = vec!["attach".to_string(), "nm.asif".to_string()];
+        let err = DiskImageError::from(CommandError::new(
+            "hdiutil",
+            &args,
+            Some(1),
+            "",
+            "hdiutil: attach failed - Resource busy\n",
+        ));
+        assert_eq!(err.kind(), ErrorKind::Busy);
+        assert_eq!(
+            err.to_string(),
+            "Command failed: `hdiutil attach nm.asif` exited with status 1: \
+             hdiutil: attach failed - Resource busy"
+        );
+        let err = DiskImageError::from(CommandError::new("security", &args, None, "", ""));
+        assert_eq!(
+            err.to_string(),
+            "Command failed: `security attach nm.asif` was killed"
+        );
+
+        let partial = DiskImageError::Incomplete {
+            failed: 2,
+            action: "remounted",
+            kind: ErrorKind::NotFound,
+        };
+        assert_eq!(partial.kind(), ErrorKind::NotFound);
+        assert_eq!(partial.to_string(), "2 image(s) could not be remounted");
+
+        let missing = SystemRunner.run("afpack-no-such-program", &[], None);
+        assert!(
+            matches!(&missing, Err(e @ DiskImageError::Spawn { .. }) if e.kind() == ErrorKind::NotFound),
+            "{:?}",
+            missing
+        );
+    }
 
     #[test]
     fn test_filesystem_display() {
@@ -508,6 +1860,102 @@ mod tests {
         assert_eq!(FileSystem::ExFAT.to_string(), "ExFAT");
         assert_eq!(FileSystem::MSDOS.to_string(), "MS-DOS");
         assert_eq!(FileSystem::None.to_string(), "None");
+        assert_eq!(FileSystem::HFSPlus.to_string(), "HFS+");
+        assert_eq!(
+            FileSystem::APFSCaseSensitive.diskutil_name(),
+            "Case-sensitive APFS"
+        );
+    }
+
+    #[test]
+    fn test_create_blank_args() {
+        let options = CreateBlankOptions::new("20G", FileSystem::APFSCaseSensitive, Format::ASIF)
+            .with_volume_name("node_modules");
+        assert_eq!(
+            DiskImage::create_blank_args(Path::new("nm.asif"), &options),
+            vec![
+                "image",
+                "create",
+                "blank",
+                "--fs",
+                "Case-sensitive APFS",
+                "--format",
+                "ASIF",
+                "--size",
+                "20G",
+                "--volumeName",
+                "node_modules",
+                "nm.asif"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_argv_mkfs() {
+        let runner = FakeRunner::new();
+        let options = CreateBlankOptions::new("20G", FileSystem::APFSCaseSensitive, Format::RAW)
+            .with_volume_name("node_modules");
+        DiskImage::format_with(&runner, "/dev/loop3", options).unwrap();
+        let options = CreateBlankOptions::new("1G", FileSystem::HFSPlus, Format::RAW)
+            .with_volume_name("deps")
+            .with_owners(true);
+        DiskImage::format_with(&runner, "/dev/loop4", options).unwrap();
+        let options =
+            CreateBlankOptions::new("1G", FileSystem::MSDOS, Format::RAW).with_volume_name("DEPS");
+        DiskImage::format_with(&runner, "/dev/loop5", options).unwrap();
+        let options = CreateBlankOptions::new("1G", FileSystem::None, Format::RAW);
+        DiskImage::format_with(&runner, "/dev/loop6", options).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "mkfs.apfs -s -L node_modules /dev/loop3",
+                "mkfs.hfsplus -v deps /dev/loop4",
+                "mkfs.fat -n DEPS /dev/loop5",
+            ]
+        );
+
+        let encrypted = CreateBlankOptions::new("1G", FileSystem::ExFAT, Format::RAW)
+            .with_encryption(Encryption::AES256, Passphrase::new("s3cret"));
+        assert!(matches!(
+            DiskImage::format_with(&runner, "/dev/loop7", encrypted),
+            Err(DiskImageError::Validation(
+                ValidationError::EncryptionNeedsDiskutil
+            ))
+        ));
+        let unnamed = CreateBlankOptions::new("1G", FileSystem::ExFAT, Format::RAW)
+            .with_volume_name("node_modules_cache");
+        assert!(DiskImage::format_with(&runner, "/dev/loop7", unnamed).is_err());
+        assert_eq!(runner.calls().len(), 3);
+    }
+
+    #[test]
+    fn test_create_blank_validation() {
+        let options = CreateBlankOptions::new("1G", FileSystem::HFSPlus, Format::ASIF)
+            .with_volume_name("deps")
+            .with_owners(true);
+        assert_eq!(options.validate(), Ok(()));
+
+        let options = CreateBlankOptions::default().with_volume_name("deps");
+        assert_eq!(
+            options.validate(),
+            Err(ValidationError::VolumeNameWithoutFileSystem)
+        );
+        let options = CreateBlankOptions::new("1G", FileSystem::MSDOS, Format::ASIF)
+            .with_volume_name("node_modules");
+        assert!(matches!(
+            options.validate(),
+            Err(ValidationError::VolumeNameTooLong { max: 11, .. })
+        ));
+        let options =
+            CreateBlankOptions::new("1G", FileSystem::ExFAT, Format::ASIF).with_owners(true);
+        assert_eq!(
+            options.validate(),
+            Err(ValidationError::OwnershipNotSupported(FileSystem::ExFAT))
+        );
+        assert!(matches!(
+            DiskImage::create_blank("x.asif", options),
+            Err(DiskImageError::Validation(_))
+        ));
     }
 
     #[test]
@@ -522,6 +1970,10 @@ mod tests {
     fn test_default_options() {
         let attach_opts = AttachOptions::default();
         assert_eq!(attach_opts.mount_point, None);
+        assert_eq!(attach_opts.shadow, None);
+        assert_eq!(attach_opts.owners, None);
+        assert!(!attach_opts.nobrowse);
+        assert!(!attach_opts.noatime);
         assert!(!attach_opts.readonly);
         assert!(!attach_opts.verbose);
         assert!(!attach_opts.dry_run);
@@ -535,6 +1987,33 @@ mod tests {
         assert_eq!(create_from_opts.format, Format::ASIF);
     }
 
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(10 * 1024 * 1024 * 1024), "10.0 GB");
+    }
+
+    #[test]
+    fn test_is_mount_point() {
+        assert!(!is_mount_point(
+            std::env::temp_dir().join("afpack-no-such-dir")
+        ));
+        let dir = TempDir::new("mount-point");
+        std::fs::create_dir(dir.join("node_modules")).unwrap();
+        assert!(!is_mount_point(dir.join("node_modules")));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("20G"), Some(20 << 30));
+        assert_eq!(parse_size("20GB"), Some(20 << 30));
+        assert_eq!(parse_size("1.5t"), Some(3 << 39));
+        assert_eq!(parse_size("10X"), None);
+        assert_eq!(parse_size("G"), None);
+    }
+
     #[test]
     fn test_format_display() {
         assert_eq!(Format::RAW.to_string(), "RAW");
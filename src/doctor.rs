@@ -0,0 +1,765 @@
+//! `afpack doctor`: what the host offers afpack and what is wrong with it
+//!
+//! Every [`Check`] has a status, what was found and, unless it passed, a hint
+//! on how to fix it. The [`Report`] is printed or emitted as the `doctor` result.
+
+use crate::config::{self, Settings};
+use crate::diskimage::{self, CommandRunner, Result, SystemRunner};
+use crate::registry::{self, Registry};
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// First macOS with `diskutil image` and ASIF images
+pub const MIN_MACOS: OsVersion = OsVersion {
+    major: 26,
+    minor: 0,
+    patch: 0,
+};
+
+/// Free space below which images cannot grow at all
+const LOW_SPACE: u64 = 1 << 30;
+
+/// Outcome of a check, ordered by severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Not a problem, e.g. a backend this host does not have
+    Info,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Ok => write!(f, "ok"),
+            Status::Info => write!(f, "info"),
+            Status::Warning => write!(f, "warn"),
+            Status::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// One diagnostic
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    /// What was found
+    pub detail: String,
+    /// How to fix it, set unless the check passed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+impl Check {
+    pub fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    pub fn ok(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self::new(name, Status::Ok, detail)
+    }
+
+    pub fn with_fix(mut self, fix: impl Into<String>) -> Self {
+        self.fix = Some(fix.into());
+        self
+    }
+}
+
+/// Result of `afpack doctor`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub os: Os,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// The most severe status of the checks
+    pub fn status(&self) -> Status {
+        self.checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(Status::Ok)
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.checks.iter().filter(|c| c.status == status).count()
+    }
+}
+
+/// A `major.minor.patch` operating system version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct OsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl std::str::FromStr for OsVersion {
+    type Err = String;
+
+    /// Parse `26`, `15.1` or `26.0.1`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts = s
+            .trim()
+            .split('.')
+            .map(|part| part.parse::<u32>())
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .map_err(|_| format!("invalid version {:?}", s))?;
+        match parts[..] {
+            [major] => Ok(OsVersion {
+                major,
+                minor: 0,
+                patch: 0,
+            }),
+            [major, minor] => Ok(OsVersion {
+                major,
+                minor,
+                patch: 0,
+            }),
+            [major, minor, patch] => Ok(OsVersion {
+                major,
+                minor,
+                patch,
+            }),
+            _ => Err(format!("invalid version {:?}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for OsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch > 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+        Ok(())
+    }
+}
+
+/// The operating system afpack runs on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Os {
+    /// `version` is unknown if sw_vers failed
+    MacOs {
+        version: Option<OsVersion>,
+    },
+    Linux {
+        release: String,
+    },
+    Other {
+        name: String,
+    },
+}
+
+impl Os {
+    pub fn detect() -> Self {
+        Self::detect_with(&SystemRunner)
+    }
+
+    /// Detect the operating system, running sw_vers through `runner` on macOS
+    pub fn detect_with(runner: &dyn CommandRunner) -> Self {
+        match std::env::consts::OS {
+            "macos" => Os::MacOs {
+                version: runner
+                    .run("sw_vers", &["-productVersion".to_string()], None)
+                    .ok()
+                    .and_then(|output| output.parse().ok()),
+            },
+            "linux" => Os::Linux {
+                release: std::fs::read_to_string("/proc/sys/kernel/osrelease")
+                    .map(|release| release.trim().to_string())
+                    .unwrap_or_default(),
+            },
+            name => Os::Other {
+                name: name.to_string(),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Os {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Os::MacOs {
+                version: Some(version),
+            } => write!(f, "macOS {}", version),
+            Os::MacOs { version: None } => write!(f, "macOS (unknown version)"),
+            Os::Linux { release } => write!(f, "Linux {}", release),
+            Os::Other { name } => write!(f, "{}", name),
+        }
+    }
+}
+
+/// A way of attaching images and the programs it needs, each one of alternatives
+struct Backend {
+    name: &'static str,
+    about: &'static str,
+    tools: &'static [&'static [&'static str]],
+}
+
+/// The backend afpack drives images with
+const DISKUTIL: &str = "diskutil";
+
+const BACKENDS: &[Backend] = &[
+    Backend {
+        name: DISKUTIL,
+        about: "ASIF, UDSB and RAW images on macOS",
+        tools: &[&["diskutil"], &["hdiutil"]],
+    },
+    Backend {
+        name: "loop",
+        about: "raw images on Linux loop devices",
+        tools: &[&["losetup"], &["mount"]],
+    },
+    Backend {
+        name: "squashfuse",
+        about: "read-only squashfs images through FUSE",
+        tools: &[&["squashfuse"], &["fusermount3", "fusermount", "umount"]],
+    },
+];
+
+/// Run every check for the project in `project`
+///
+/// A config or registry that failed to load is reported as a check.
+pub fn diagnose(project: &Path, settings: Result<Settings>, registry: Result<Registry>) -> Report {
+    let os = Os::detect();
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut checks = vec![check_os(&os)];
+    checks.extend(check_backends(&path));
+    checks.push(check_fuse());
+
+    let settings = match settings {
+        Ok(settings) => {
+            checks.push(Check::ok("config", "settings are valid"));
+            settings
+        }
+        Err(e) => {
+            checks.push(
+                Check::new("config", Status::Error, e.to_string()).with_fix(format!(
+                    "correct {} or the {}* environment variables, see `afpack config show`",
+                    config::PROJECT_FILE,
+                    config::ENV_PREFIX
+                )),
+            );
+            Settings::default()
+        }
+    };
+    checks.push(check_filesystem(project, &settings));
+    checks.push(check_free_space(project, &settings));
+    checks.push(
+        check_writable("state dir", &registry::state_dir()).with_fix_if_failed(
+            "make it writable, or point AFPACK_STATE_DIR at a writable directory",
+        ),
+    );
+    checks.push(
+        check_writable("project", project)
+            .with_fix_if_failed("run afpack as the owner of the project directory"),
+    );
+
+    let registry_path = Registry::default_path();
+    match registry {
+        Ok(registry) => checks.extend(check_registry(&registry, &registry_path, |afdir| {
+            diskimage::is_mount_point(afdir)
+        })),
+        Err(e) => checks.push(
+            Check::new("registry", Status::Error, e.to_string())
+                .with_fix(format!("repair or remove {}", registry_path.display())),
+        ),
+    }
+    Report { os, checks }
+}
+
+impl Check {
+    fn with_fix_if_failed(self, fix: &str) -> Self {
+        if self.status == Status::Ok {
+            self
+        } else {
+            self.with_fix(fix)
+        }
+    }
+}
+
+/// Whether the OS can run the diskutil backend
+pub fn check_os(os: &Os) -> Check {
+    match os {
+        Os::MacOs {
+            version: Some(version),
+        } if *version >= MIN_MACOS => Check::ok("os", os.to_string()),
+        Os::MacOs {
+            version: Some(version),
+        } => Check::new(
+            "os",
+            Status::Error,
+            format!(
+                "macOS {} lacks `diskutil image` and ASIF, which need macOS {} or later",
+                version, MIN_MACOS
+            ),
+        )
+        .with_fix(format!("upgrade to macOS {} or later", MIN_MACOS)),
+        Os::MacOs { version: None } => Check::new(
+            "os",
+            Status::Warning,
+            "could not read the macOS version from sw_vers",
+        )
+        .with_fix("check that /usr/bin/sw_vers runs"),
+        Os::Linux { .. } | Os::Other { .. } => Check::new(
+            "os",
+            Status::Info,
+            format!("{}, images can only be attached by the backends found", os),
+        ),
+    }
+}
+
+/// One check per backend, and whether afpack can attach images at all
+pub fn check_backends(path: &OsStr) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut available = Vec::new();
+    for backend in BACKENDS {
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+        for alternatives in backend.tools {
+            match alternatives
+                .iter()
+                .find_map(|tool| find_program(tool, path))
+            {
+                Some(program) => found.push(program.display().to_string()),
+                None => missing.push(alternatives.join(" or ")),
+            }
+        }
+        let name = format!("backend {}", backend.name);
+        checks.push(if missing.is_empty() {
+            available.push(backend.name);
+            Check::ok(name, format!("{}: {}", backend.about, found.join(", ")))
+        } else {
+            Check::new(
+                name,
+                Status::Info,
+                format!("{}: missing {}", backend.about, missing.join(", ")),
+            )
+        });
+    }
+
+    checks.push(if available.contains(&DISKUTIL) {
+        Check::ok("backend", "afpack attaches images with diskutil")
+    } else if available.is_empty() {
+        Check::new("backend", Status::Error, "no backend can attach images")
+            .with_fix(format!("run afpack on macOS {} or later", MIN_MACOS))
+    } else {
+        Check::new(
+            "backend",
+            Status::Warning,
+            format!(
+                "afpack attaches images with diskutil, this host only has {}",
+                available.join(", ")
+            ),
+        )
+        .with_fix(format!("run afpack on macOS {} or later", MIN_MACOS))
+    });
+    checks
+}
+
+/// Whether FUSE filesystems can be mounted
+pub fn check_fuse() -> Check {
+    let devices = ["/dev/fuse", "/Library/Filesystems/macfuse.fs"];
+    match devices.iter().find(|path| Path::new(path).exists()) {
+        Some(path) => Check::ok("fuse", format!("{} exists", path)),
+        None => Check::new(
+            "fuse",
+            Status::Info,
+            "no FUSE support, only needed by squashfuse",
+        ),
+    }
+}
+
+/// Transparent compression needs the project to be on APFS
+pub fn check_filesystem(project: &Path, settings: &Settings) -> Check {
+    match filesystem_type(project) {
+        Some(fs) if fs == "apfs" => {
+            Check::ok("filesystem", format!("{} is on APFS", project.display()))
+        }
+        Some(fs) if settings.compression.is_enabled() => Check::new(
+            "filesystem",
+            Status::Warning,
+            format!(
+                "{} is on {}, transparent compression needs APFS",
+                project.display(),
+                fs
+            ),
+        )
+        .with_fix("move the project to an APFS volume, or set compression.algorithm = \"none\""),
+        Some(fs) => Check::new(
+            "filesystem",
+            Status::Info,
+            format!(
+                "{} is on {}, transparent compression would need APFS",
+                project.display(),
+                fs
+            ),
+        ),
+        None => Check::new(
+            "filesystem",
+            Status::Warning,
+            format!("could not tell the filesystem of {}", project.display()),
+        )
+        .with_fix("check that the project directory exists"),
+    }
+}
+
+/// Size all configured images may grow to together, the default image if none are
+pub fn total_maxsize(settings: &Settings) -> u64 {
+    if settings.dirs.is_empty() {
+        return diskimage::parse_size(&settings.maxsize).unwrap_or(0);
+    }
+    settings
+        .dirs
+        .iter()
+        .filter_map(|dir| diskimage::parse_size(settings.maxsize_for(dir.path())))
+        .sum()
+}
+
+/// Free space next to the images against the size they may grow to together
+pub fn check_free_space(project: &Path, settings: &Settings) -> Check {
+    let Some(available) = available_space(project) else {
+        return Check::new(
+            "free space",
+            Status::Warning,
+            format!("could not tell the free space of {}", project.display()),
+        )
+        .with_fix("check that the project directory exists");
+    };
+    let total = total_maxsize(settings);
+    let detail = format!(
+        "{} free on {}, images may grow to {} together",
+        diskimage::format_size(available),
+        project.display(),
+        diskimage::format_size(total)
+    );
+    if available < LOW_SPACE {
+        Check::new("free space", Status::Error, detail).with_fix("free up disk space")
+    } else if available < total {
+        Check::new("free space", Status::Warning, detail)
+            .with_fix("free up disk space, or lower maxsize")
+    } else {
+        Check::ok("free space", detail)
+    }
+}
+
+/// Whether files can be created in `dir`, creating it if needed
+pub fn check_writable(name: &str, dir: &Path) -> Check {
+    let probe = dir.join(format!(".afpack-doctor-{}", std::process::id()));
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&probe, b""))
+        .and_then(|()| std::fs::remove_file(&probe));
+    match result {
+        Ok(()) => Check::ok(name, format!("{} is writable", dir.display())),
+        Err(e) => Check::new(
+            name,
+            Status::Error,
+            format!("cannot write to {}: {}", dir.display(), e),
+        ),
+    }
+}
+
+/// Registry entries disagreeing with the disk, one check each, or one passing check
+pub fn check_registry(
+    registry: &Registry,
+    registry_path: &Path,
+    is_mounted: impl Fn(&Path) -> bool,
+) -> Vec<Check> {
+    let remove = |afdir: &Path| {
+        format!(
+            "remove the entry of {} from {}",
+            afdir.display(),
+            registry_path.display()
+        )
+    };
+    let mut checks = Vec::new();
+    for entry in &registry.entries {
+        let afdir = entry.afdir.display();
+        let problem = |status, detail: String| Check::new("registry", status, detail);
+        if !entry.afdir.parent().is_some_and(Path::exists) {
+            checks.push(
+                problem(
+                    Status::Warning,
+                    format!("the project of {} no longer exists", afdir),
+                )
+                .with_fix(remove(&entry.afdir)),
+            );
+            continue;
+        }
+        if !entry.image.exists() {
+            checks.push(
+                problem(
+                    Status::Error,
+                    format!("image {} of {} is missing", entry.image.display(), afdir),
+                )
+                .with_fix(format!("restore the image, or {}", remove(&entry.afdir))),
+            );
+            continue;
+        }
+        let mounted = is_mounted(&entry.afdir);
+        if entry.attached && !mounted {
+            checks.push(
+                problem(
+                    Status::Warning,
+                    format!("{} is recorded as attached but not mounted", afdir),
+                )
+                .with_fix(format!("afpack remount {}", afdir)),
+            );
+        } else if !entry.attached && mounted && !entry.idle_detached {
+            checks.push(
+                problem(
+                    Status::Warning,
+                    format!("{} is mounted but recorded as detached", afdir),
+                )
+                .with_fix(format!("afpack detach {}", afdir)),
+            );
+        }
+        if let Some(shadow) = entry.shadow.as_ref().filter(|s| !s.exists()) {
+            checks.push(
+                problem(
+                    Status::Warning,
+                    format!("shadow {} of {} is missing", shadow.display(), afdir),
+                )
+                .with_fix("afpack worktree clean"),
+            );
+        }
+        for (key, variant) in &entry.variants {
+            if !variant.image.exists() {
+                checks.push(
+                    problem(
+                        Status::Warning,
+                        format!(
+                            "image {} of variant {} of {} is missing",
+                            variant.image.display(),
+                            key,
+                            afdir
+                        ),
+                    )
+                    .with_fix(format!(
+                        "remove variant {} of {} from {}",
+                        key,
+                        afdir,
+                        registry_path.display()
+                    )),
+                );
+            }
+        }
+    }
+    if checks.is_empty() {
+        checks.push(Check::ok(
+            "registry",
+            format!("{} entries consistent", registry.entries.len()),
+        ));
+    }
+    checks
+}
+
+/// `program` in the directories of `path`, if executable
+fn find_program(program: &str, path: &OsStr) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    std::env::split_paths(path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| {
+            std::fs::metadata(candidate)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+}
+
+fn statfs(path: &Path) -> Option<libc::statfs> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL terminated and `stat` is a valid out pointer
+    (unsafe { libc::statfs(path.as_ptr(), &mut stat) } == 0).then_some(stat)
+}
+
+/// Name of the filesystem holding `path`, e.g. `apfs`
+#[cfg(target_os = "macos")]
+fn filesystem_type(path: &Path) -> Option<String> {
+    let stat = statfs(path)?;
+    // SAFETY: the kernel NUL terminates f_fstypename
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Name of the filesystem holding `path`, e.g. `ext4`
+#[cfg(not(target_os = "macos"))]
+fn filesystem_type(path: &Path) -> Option<String> {
+    let stat = statfs(path)?;
+    Some(
+        match stat.f_type {
+            libc::EXT4_SUPER_MAGIC => "ext4",
+            libc::XFS_SUPER_MAGIC => "xfs",
+            libc::BTRFS_SUPER_MAGIC => "btrfs",
+            libc::TMPFS_MAGIC => "tmpfs",
+            libc::OVERLAYFS_SUPER_MAGIC => "overlayfs",
+            libc::NFS_SUPER_MAGIC => "nfs",
+            other => return Some(format!("{:#x}", other)),
+        }
+        .to_string(),
+    )
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+// The field widths differ between macOS and Linux
+#[allow(clippy::unnecessary_cast)]
+fn available_space(path: &Path) -> Option<u64> {
+    let stat = statfs(path)?;
+    Some(stat.f_bavail as u64 * stat.f_bsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{Algorithm, CompressionPolicy};
+    use crate::config::DirConfig;
+    use crate::registry::Entry;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_os_version() {
+        let version: OsVersion = "26.0.1\n".parse().unwrap();
+        assert_eq!(
+            version,
+            OsVersion {
+                major: 26,
+                minor: 0,
+                patch: 1
+            }
+        );
+        assert!(version >= MIN_MACOS);
+        assert!("15.7".parse::<OsVersion>().unwrap() < MIN_MACOS);
+        assert_eq!("26".parse::<OsVersion>().unwrap().to_string(), "26.0");
+        assert!("".parse::<OsVersion>().is_err());
+        assert!("26.0 beta".parse::<OsVersion>().is_err());
+        assert!("1.2.3.4".parse::<OsVersion>().is_err());
+    }
+
+    #[test]
+    fn test_check_os() {
+        let macos = |v: &str| Os::MacOs {
+            version: v.parse().ok(),
+        };
+        assert_eq!(check_os(&macos("26.1")).status, Status::Ok);
+        let old = check_os(&macos("12.7.4"));
+        assert_eq!(old.status, Status::Error);
+        assert!(old.detail.starts_with("macOS 12.7.4 lacks"));
+        assert_eq!(check_os(&macos("garbage")).status, Status::Warning);
+        let linux = Os::Linux {
+            release: "6.8.0".into(),
+        };
+        assert_eq!(check_os(&linux).status, Status::Info);
+    }
+
+    #[test]
+    fn test_check_backends() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("doctor");
+        for tool in ["losetup", "mount", "squashfuse"] {
+            std::fs::write(dir.join(tool), "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(dir.join(tool), std::fs::Permissions::from_mode(0o755))
+                .unwrap();
+        }
+        // Not executable, so not found
+        std::fs::write(dir.join("diskutil"), "").unwrap();
+
+        let checks = check_backends(dir.as_os_str());
+        let status = |name: &str| checks.iter().find(|c| c.name == name).unwrap().status;
+        assert_eq!(status("backend diskutil"), Status::Info);
+        assert_eq!(status("backend loop"), Status::Ok);
+        assert_eq!(status("backend squashfuse"), Status::Info);
+        let summary = checks.last().unwrap();
+        assert_eq!(summary.status, Status::Warning);
+        assert!(summary.detail.ends_with("this host only has loop"));
+
+        let none = check_backends(OsStr::new("/nonexistent"));
+        assert_eq!(none.last().unwrap().status, Status::Error);
+    }
+
+    #[test]
+    fn test_check_registry() {
+        let dir = TempDir::new("doctor-reg");
+        std::fs::write(dir.join("target.asif"), "").unwrap();
+        let registry_path = dir.join("registry.json");
+
+        let mut attached = Entry::new(dir.join("target"), dir.join("target.asif"), "10G");
+        attached.attached = true;
+        let missing = Entry::new(dir.join("node_modules"), dir.join("nm.asif"), "10G");
+        let gone = Entry::new("/nonexistent/project/target", "/nonexistent/t.asif", "10G");
+        let registry = Registry {
+            entries: vec![attached, missing, gone],
+        };
+
+        let checks = check_registry(&registry, &registry_path, |_| false);
+        assert_eq!(checks.len(), 3);
+        assert_eq!(checks[0].status, Status::Warning);
+        assert_eq!(
+            checks[0].fix.as_deref(),
+            Some(format!("afpack remount {}", dir.join("target").display()).as_str())
+        );
+        assert_eq!(checks[1].status, Status::Error);
+        assert!(checks[1].detail.contains("nm.asif"));
+        assert!(checks[2].detail.contains("no longer exists"));
+
+        let registry = Registry {
+            entries: vec![Entry::new(
+                dir.join("target"),
+                dir.join("target.asif"),
+                "10G",
+            )],
+        };
+        let checks = check_registry(&registry, &registry_path, |_| false);
+        assert_eq!(checks, [Check::ok("registry", "1 entries consistent")]);
+    }
+
+    #[test]
+    fn test_check_filesystem_and_space() {
+        let settings = Settings {
+            compression: CompressionPolicy::new(Algorithm::Lzfse),
+            maxsize: "1000T".into(),
+            ..Settings::default()
+        };
+        assert_eq!(total_maxsize(&settings), 1000 << 40);
+        let dirs = Settings {
+            dirs: vec![
+                DirConfig::Path("node_modules".into()),
+                DirConfig::Table {
+                    path: "target".into(),
+                    maxsize: Some("2G".into()),
+                },
+            ],
+            maxsize: "10G".into(),
+            ..Settings::default()
+        };
+        assert_eq!(total_maxsize(&dirs), 12 << 30);
+        let tmp = std::env::temp_dir();
+        // Wherever the tests run, a 1000T image does not fit
+        assert_ne!(check_free_space(&tmp, &settings).status, Status::Ok);
+        let missing = Path::new("/nonexistent/project");
+        assert_eq!(check_filesystem(missing, &settings).status, Status::Warning);
+        let report = Report {
+            os: Os::Other {
+                name: "test".into(),
+            },
+            checks: vec![
+                check_writable("tmp", &tmp),
+                check_free_space(missing, &settings),
+            ],
+        };
+        assert_eq!(report.checks[0].status, Status::Ok);
+        assert_eq!(report.status(), Status::Warning);
+        assert_eq!(report.count(Status::Warning), 1);
+    }
+}
@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+
+/// Package ecosystem owning an artifact directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ecosystem {
+    Node,
+    Cargo,
+    SwiftPM,
+    Unknown,
+}
+
+impl Ecosystem {
+    /// Detect the ecosystem from the artifact directory name
+    pub fn detect<P: AsRef<Path>>(afdir: P) -> Self {
+        let name = afdir
+            .as_ref()
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match name.as_str() {
+            "node_modules" => Ecosystem::Node,
+            "target" => Ecosystem::Cargo,
+            ".build" => Ecosystem::SwiftPM,
+            _ => Ecosystem::Unknown,
+        }
+    }
+
+    /// Lockfile names that pin the content of the artifact directory
+    pub fn lockfiles(&self) -> &'static [&'static str] {
+        match self {
+            Ecosystem::Node => &[
+                "package-lock.json",
+                "yarn.lock",
+                "pnpm-lock.yaml",
+                "bun.lockb",
+                "bun.lock",
+            ],
+            Ecosystem::Cargo => &["Cargo.lock"],
+            Ecosystem::SwiftPM => &["Package.resolved"],
+            Ecosystem::Unknown => &[],
+        }
+    }
+
+    /// Lockfiles present next to the artifact directory
+    pub fn find_lockfiles<P: AsRef<Path>>(&self, afdir: P) -> Vec<PathBuf> {
+        let parent = project_dir(afdir.as_ref());
+        self.lockfiles()
+            .iter()
+            .map(|name| parent.join(name))
+            .filter(|path| path.is_file())
+            .collect()
+    }
+
+    /// Top-level package a path inside the artifact directory belongs to
+    ///
+    /// `@types/node/index.d.ts` -> `@types/node`, `release/deps/foo.rlib` -> `release`,
+    /// `aarch64-apple-darwin/release/foo` -> `aarch64-apple-darwin/release`.
+    pub fn package_of(&self, relative: &Path) -> String {
+        let components: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let Some(first) = components.first() else {
+            return String::new();
+        };
+        let depth = match self {
+            Ecosystem::Node if first.starts_with('@') || first == ".pnpm" => 2,
+            Ecosystem::Cargo | Ecosystem::SwiftPM if is_target_triple(first) => 2,
+            Ecosystem::SwiftPM if first == "checkouts" || first == "repositories" => 2,
+            _ => 1,
+        };
+        // A file directly at the package level is its own group
+        let depth = depth.min(components.len().saturating_sub(1)).max(1);
+        components[..depth].join("/")
+    }
+}
+
+/// `aarch64-apple-darwin`, `x86_64-unknown-linux-gnu`, ...
+fn is_target_triple(name: &str) -> bool {
+    name.split('-').count() >= 3
+}
+
+impl std::fmt::Display for Ecosystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ecosystem::Node => write!(f, "node"),
+            Ecosystem::Cargo => write!(f, "cargo"),
+            Ecosystem::SwiftPM => write!(f, "swiftpm"),
+            Ecosystem::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Directory containing the artifact directory (the project root)
+pub fn project_dir(afdir: &Path) -> PathBuf {
+    match afdir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Ecosystem::detect("node_modules"), Ecosystem::Node);
+        assert_eq!(Ecosystem::detect("app/target"), Ecosystem::Cargo);
+        assert_eq!(Ecosystem::detect("./.build"), Ecosystem::SwiftPM);
+        assert_eq!(Ecosystem::detect("vendor"), Ecosystem::Unknown);
+    }
+
+    #[test]
+    fn test_package_of() {
+        let node = Ecosystem::Node;
+        assert_eq!(node.package_of(Path::new("lodash/fp/map.js")), "lodash");
+        assert_eq!(
+            node.package_of(Path::new("@types/node/index.d.ts")),
+            "@types/node"
+        );
+        assert_eq!(
+            node.package_of(Path::new(".package-lock.json")),
+            ".package-lock.json"
+        );
+
+        let cargo = Ecosystem::Cargo;
+        assert_eq!(cargo.package_of(Path::new("debug/deps/a.rlib")), "debug");
+        assert_eq!(
+            cargo.package_of(Path::new("aarch64-apple-darwin/release/app")),
+            "aarch64-apple-darwin/release"
+        );
+    }
+
+    #[test]
+    fn test_project_dir() {
+        assert_eq!(project_dir(Path::new("node_modules")), PathBuf::from("."));
+        assert_eq!(
+            project_dir(Path::new("web/node_modules")),
+            PathBuf::from("web")
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use crate::compression::Algorithm;
+use crate::diskimage::Result;
+use flate2::write::ZlibEncoder;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// decmpfs compresses files in independent 64 KiB chunks
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Algorithms `estimate` reports on
+pub const ALGORITHMS: [Algorithm; 3] = [Algorithm::Lzfse, Algorithm::Lzvn, Algorithm::Zlib];
+
+#[derive(Debug, Clone)]
+pub struct EstimateOptions {
+    /// Number of blocks read from the input
+    pub samples: usize,
+    pub block_size: usize,
+    /// zlib compression level (1-9)
+    pub level: u32,
+    /// Worker threads the real run will use, for the time prediction
+    ///
+    /// Defaults to `available_parallelism`, the size of applesauce's pool.
+    pub threads: usize,
+}
+
+impl EstimateOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_samples(mut self, samples: usize) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+}
+
+impl Default for EstimateOptions {
+    fn default() -> Self {
+        Self {
+            samples: 256,
+            block_size: BLOCK_SIZE,
+            level: 5,
+            threads: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+        }
+    }
+}
+
+/// Blocks read at evenly spaced offsets across a file or directory tree
+#[derive(Debug, Default)]
+pub struct Samples {
+    pub blocks: Vec<Vec<u8>>,
+    /// Logical size of everything the blocks were drawn from
+    pub total_size: u64,
+}
+
+impl Samples {
+    /// Sample `path`, a single file (e.g. an image) or a directory of files
+    pub fn read<P: AsRef<Path>>(path: P, options: &EstimateOptions) -> Result<Self> {
+        let mut files = Vec::new();
+        collect_files(path.as_ref(), &mut files)?;
+        let total_size: u64 = files.iter().map(|(_, len)| len).sum();
+        if total_size == 0 || options.samples == 0 {
+            return Ok(Self {
+                blocks: Vec::new(),
+                total_size,
+            });
+        }
+
+        let stride = (total_size / options.samples as u64).max(options.block_size as u64);
+        let mut blocks = Vec::new();
+        let mut file_start = 0;
+        let mut offset = 0;
+        for (path, len) in &files {
+            let file_end = file_start + len;
+            if offset < file_end {
+                let mut file = File::open(path)?;
+                while offset < file_end {
+                    file.seek(SeekFrom::Start(offset - file_start))?;
+                    let mut block = Vec::with_capacity(options.block_size);
+                    (&mut file)
+                        .take(options.block_size as u64)
+                        .read_to_end(&mut block)?;
+                    blocks.push(block);
+                    offset += stride;
+                }
+            }
+            file_start = file_end;
+        }
+        Ok(Self { blocks, total_size })
+    }
+
+    pub fn sampled_size(&self) -> u64 {
+        self.blocks.iter().map(|b| b.len() as u64).sum()
+    }
+}
+
+/// Predicted outcome of compressing with one algorithm
+#[derive(Debug, Clone, Serialize)]
+pub struct Estimate {
+    pub algorithm: Algorithm,
+    pub sampled_size: u64,
+    pub compressed_sample_size: u64,
+    pub predicted_size: u64,
+    pub savings: f64,
+    #[serde(with = "seconds")]
+    pub predicted_time: Duration,
+}
+
+/// Compress `samples` in memory with `algorithm` and extrapolate to the full input
+///
+/// Blocks that do not shrink count at their raw size, as decmpfs stores them
+/// uncompressed.
+pub fn estimate(samples: &Samples, algorithm: Algorithm, options: &EstimateOptions) -> Estimate {
+    let start = Instant::now();
+    let compressed_sample_size: u64 = samples
+        .blocks
+        .iter()
+        .map(|block| compressed_len(block, algorithm, options.level).min(block.len()) as u64)
+        .sum();
+    let elapsed = start.elapsed();
+
+    let sampled_size = samples.sampled_size();
+    let scale = if sampled_size == 0 {
+        0.0
+    } else {
+        samples.total_size as f64 / sampled_size as f64
+    };
+    let ratio = if sampled_size == 0 {
+        1.0
+    } else {
+        compressed_sample_size as f64 / sampled_size as f64
+    };
+    Estimate {
+        algorithm,
+        sampled_size,
+        compressed_sample_size,
+        predicted_size: (samples.total_size as f64 * ratio) as u64,
+        savings: 1.0 - ratio,
+        predicted_time: elapsed.mul_f64(scale / options.threads.max(1) as f64),
+    }
+}
+
+/// Estimates for every supported algorithm
+pub fn estimate_all(samples: &Samples, options: &EstimateOptions) -> Vec<Estimate> {
+    ALGORITHMS
+        .iter()
+        .map(|&algorithm| estimate(samples, algorithm, options))
+        .collect()
+}
+
+/// Compressed length of one block
+///
+/// There is no Rust LZVN encoder, so LZ4 stands in for it: both are byte
+/// oriented LZ77 coders without entropy coding and land within a few percent.
+fn compressed_len(block: &[u8], algorithm: Algorithm, level: u32) -> usize {
+    match algorithm {
+        Algorithm::None => block.len(),
+        Algorithm::Lzfse => {
+            let mut out = Vec::with_capacity(block.len());
+            match lzfse_rust::encode_bytes(block, &mut out) {
+                Ok(_) => out.len(),
+                Err(_) => block.len(),
+            }
+        }
+        Algorithm::Lzvn => lz4_flex::block::compress(block).len(),
+        Algorithm::Zlib => {
+            let mut encoder = ZlibEncoder::new(
+                Vec::with_capacity(block.len()),
+                flate2::Compression::new(level.min(9)),
+            );
+            match encoder.write_all(block).and_then(|_| encoder.finish()) {
+                Ok(out) => out.len(),
+                Err(_) => block.len(),
+            }
+        }
+    }
+}
+
+fn collect_files(path: &Path, files: &mut Vec<(PathBuf, u64)>) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_file() {
+        files.push((path.to_path_buf(), metadata.len()));
+    } else if metadata.is_dir() {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()?;
+        entries.sort();
+        for entry in entries {
+            collect_files(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+mod seconds {
+    use serde::Serializer;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_samples_span_directory() {
+        let dir = TempDir::new("estimate");
+        std::fs::create_dir_all(dir.join("pkg")).unwrap();
+        std::fs::write(dir.join("a.bin"), vec![0u8; 40_000]).unwrap();
+        std::fs::write(dir.join("pkg/b.bin"), vec![1u8; 60_000]).unwrap();
+
+        let options = EstimateOptions::new()
+            .with_samples(10)
+            .with_block_size(1000);
+        let samples = Samples::read(&dir, &options).unwrap();
+        assert_eq!(samples.total_size, 100_000);
+        assert_eq!(samples.blocks.len(), 10);
+        assert_eq!(samples.sampled_size(), 10_000);
+        assert!(samples.blocks[0].iter().all(|&b| b == 0));
+        assert!(samples.blocks[9].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn test_estimate_extrapolates() {
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(1500);
+        let samples = Samples {
+            blocks: vec![text[..BLOCK_SIZE].to_vec(); 4],
+            total_size: 16 * BLOCK_SIZE as u64,
+        };
+        for estimate in estimate_all(&samples, &EstimateOptions::new()) {
+            assert!(estimate.savings > 0.5, "{:?}", estimate);
+            assert!(estimate.predicted_size < samples.total_size / 2);
+        }
+
+        // Random-looking data is stored raw and saves nothing
+        let mut state = 0x2545f4914f6cdd1du64;
+        let noise: Vec<u8> = (0..BLOCK_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let samples = Samples {
+            blocks: vec![noise],
+            total_size: 8 * BLOCK_SIZE as u64,
+        };
+        let estimate = estimate(&samples, Algorithm::Zlib, &EstimateOptions::new());
+        assert_eq!(estimate.predicted_size, samples.total_size);
+        assert_eq!(estimate.savings, 0.0);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::diskimage::{CommandError, DiskImageError, Result};
+use crate::ecosystem::project_dir;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const HOOK_BEGIN: &str = "# >>> afpack >>>";
+const HOOK_END: &str = "# <<< afpack <<<";
+
+/// Run git in the project directory owning `afdir`
+fn git(afdir: &Path, args: &[&str]) -> Result<Output> {
+    Command::new("git")
+        .arg("-C")
+        .arg(project_dir(afdir))
+        .args(args)
+        .output()
+        .map_err(|error| DiskImageError::Spawn {
+            program: "git".to_string(),
+            error,
+        })
+}
+
+/// Error for git `args` exiting unsuccessfully with `output`
+fn failed(afdir: &Path, args: &[&str], output: &Output) -> DiskImageError {
+    let mut argv = vec!["-C".to_string(), project_dir(afdir).display().to_string()];
+    argv.extend(args.iter().map(|a| a.to_string()));
+    CommandError::new(
+        "git",
+        &argv,
+        output.status.code(),
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr),
+    )
+    .into()
+}
+
+fn stdout_line(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// Current branch name, `None` on a detached HEAD
+pub fn current_branch<P: AsRef<Path>>(afdir: P) -> Result<Option<String>> {
+    let output = git(afdir.as_ref(), &["symbolic-ref", "--short", "-q", "HEAD"])?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(stdout_line(&output)))
+}
+
+/// Root of the working tree containing `afdir`
+pub fn toplevel<P: AsRef<Path>>(afdir: P) -> Result<PathBuf> {
+    let args = ["rev-parse", "--show-toplevel"];
+    let output = git(afdir.as_ref(), &args)?;
+    if !output.status.success() {
+        return Err(failed(afdir.as_ref(), &args, &output));
+    }
+    Ok(PathBuf::from(stdout_line(&output)))
+}
+
+/// Number of commits between HEAD and its merge base with `rev`
+///
+/// Returns `None` when `rev` is unknown or shares no history with HEAD.
+pub fn distance_from_head<P: AsRef<Path>>(afdir: P, rev: &str) -> Result<Option<usize>> {
+    let afdir = afdir.as_ref();
+    let output = git(afdir, &["merge-base", "HEAD", rev])?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let range = format!("{}..HEAD", stdout_line(&output));
+    let output = git(afdir, &["rev-list", "--count", &range])?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(stdout_line(&output).parse().ok())
+}
+
+/// Most recent commits reachable from HEAD, newest first
+pub fn rev_list<P: AsRef<Path>>(afdir: P, max_count: usize) -> Result<Vec<String>> {
+    let max_count = format!("--max-count={}", max_count);
+    let args = ["rev-list", &max_count, "HEAD"];
+    let output = git(afdir.as_ref(), &args)?;
+    if !output.status.success() {
+        return Err(failed(afdir.as_ref(), &args, &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Content of `file` (next to `afdir`) at commit `rev`, `None` if it did not exist
+pub fn show_file<P: AsRef<Path>>(afdir: P, rev: &str, file: &Path) -> Result<Option<Vec<u8>>> {
+    let name = file
+        .file_name()
+        .ok_or_else(|| DiskImageError::InvalidPath(file.display().to_string()))?;
+    let spec = format!("{}:./{}", rev, name.to_string_lossy());
+    let output = git(afdir.as_ref(), &["show", &spec])?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(output.stdout))
+}
+
+/// Absolute path printed by `git rev-parse --path-format=absolute <args>`
+fn rev_parse_path(afdir: &Path, args: &[&str]) -> Result<PathBuf> {
+    let mut full = vec!["rev-parse", "--path-format=absolute"];
+    full.extend_from_slice(args);
+    let output = git(afdir, &full)?;
+    if !output.status.success() {
+        return Err(failed(afdir, &full, &output));
+    }
+    Ok(PathBuf::from(stdout_line(&output)))
+}
+
+/// Hooks directory of the repository, honouring `core.hooksPath`
+pub fn hooks_dir<P: AsRef<Path>>(afdir: P) -> Result<PathBuf> {
+    rev_parse_path(afdir.as_ref(), &["--git-path", "hooks"])
+}
+
+/// Git directory private to the worktree containing `afdir`
+pub fn git_dir<P: AsRef<Path>>(afdir: P) -> Result<PathBuf> {
+    rev_parse_path(afdir.as_ref(), &["--git-dir"])
+}
+
+/// Git directory shared by all worktrees of the repository
+pub fn common_dir<P: AsRef<Path>>(afdir: P) -> Result<PathBuf> {
+    rev_parse_path(afdir.as_ref(), &["--git-common-dir"])
+}
+
+/// Write (or refresh) the afpack block of the post-checkout hook
+pub fn install_post_checkout_hook<P: AsRef<Path>>(afdir: P, command: &str) -> Result<PathBuf> {
+    let hooks = hooks_dir(afdir)?;
+    std::fs::create_dir_all(&hooks)?;
+    let hook = hooks.join("post-checkout");
+    let existing = std::fs::read_to_string(&hook).ok();
+    std::fs::write(&hook, render_hook(existing.as_deref(), command))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(hook)
+}
+
+/// Merge the afpack block into an existing hook script, keeping foreign content
+fn render_hook(existing: Option<&str>, command: &str) -> String {
+    // $3 is 1 for branch checkouts and 0 for file checkouts
+    let block = format!(
+        "{}\nif [ \"$3\" = \"1\" ]; then\n    {} || true\nfi\n{}\n",
+        HOOK_BEGIN, command, HOOK_END
+    );
+
+    let Some(existing) = existing.filter(|s| !s.trim().is_empty()) else {
+        return format!("#!/bin/sh\n{}", block);
+    };
+
+    if let (Some(start), Some(end)) = (existing.find(HOOK_BEGIN), existing.find(HOOK_END)) {
+        let end = existing[end..]
+            .find('\n')
+            .map_or(existing.len(), |i| end + i + 1);
+        return format!("{}{}{}", &existing[..start], block, &existing[end..]);
+    }
+
+    let separator = if existing.ends_with('\n') { "" } else { "\n" };
+    format!("{}{}{}", existing, separator, block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_new_hook() {
+        let hook = render_hook(None, "afpack switch node_modules");
+        assert!(hook.starts_with("#!/bin/sh\n# >>> afpack >>>\n"));
+        assert!(hook.contains("    afpack switch node_modules || true\n"));
+        assert!(hook.ends_with("# <<< afpack <<<\n"));
+    }
+
+    #[test]
+    fn test_render_appends_to_foreign_hook() {
+        let hook = render_hook(Some("#!/bin/sh\necho hi"), "afpack switch target");
+        assert!(hook.starts_with("#!/bin/sh\necho hi\n# >>> afpack >>>\n"));
+    }
+
+    #[test]
+    fn test_render_replaces_previous_block() {
+        let first = render_hook(Some("#!/bin/sh\necho hi\n"), "afpack switch a");
+        let second = render_hook(Some(&first), "afpack switch b");
+        assert!(!second.contains("switch a"));
+        assert!(second.contains("switch b"));
+        assert_eq!(second.matches(HOOK_BEGIN).count(), 1);
+        assert!(second.starts_with("#!/bin/sh\necho hi\n"));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::registry::{Entry, Registry};
+use std::path::{Path, PathBuf};
+
+/// Shells `afpack hook` can print a snippet for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Shell {
+    Zsh,
+    Bash,
+    Fish,
+}
+
+impl std::fmt::Display for Shell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Shell::Zsh => write!(f, "zsh"),
+            Shell::Bash => write!(f, "bash"),
+            Shell::Fish => write!(f, "fish"),
+        }
+    }
+}
+
+/// `s` as a single-quoted word for sh, bash, zsh and fish
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Snippet running `program hook-exec` whenever the working directory changes
+///
+/// With `prompt`, the hook's output is kept in `$AFPACK_PROMPT` for use in
+/// the prompt instead of being printed.
+pub fn snippet(shell: Shell, program: &Path, prompt: bool) -> String {
+    let program = quote(&program.display().to_string());
+    let run = if prompt {
+        format!("{} hook-exec --prompt", program)
+    } else {
+        format!("{} hook-exec", program)
+    };
+    match shell {
+        Shell::Zsh => {
+            let body = if prompt {
+                format!("AFPACK_PROMPT=\"$({})\"", run)
+            } else {
+                run
+            };
+            format!(
+                r#"_afpack_hook() {{
+  {}
+}}
+typeset -ag chpwd_functions
+if (( ! ${{chpwd_functions[(I)_afpack_hook]}} )); then
+  chpwd_functions=(_afpack_hook $chpwd_functions)
+fi
+_afpack_hook
+"#,
+                body
+            )
+        }
+        Shell::Bash => {
+            let body = if prompt {
+                format!("AFPACK_PROMPT=\"$({})\"", run)
+            } else {
+                run
+            };
+            format!(
+                r#"_afpack_hook() {{
+  if [[ "$PWD" != "${{_AFPACK_PWD:-}}" ]]; then
+    _AFPACK_PWD="$PWD"
+    {}
+  fi
+}}
+if [[ ";${{PROMPT_COMMAND:-}};" != *";_afpack_hook;"* ]]; then
+  PROMPT_COMMAND="_afpack_hook${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}"
+fi
+"#,
+                body
+            )
+        }
+        Shell::Fish => {
+            let body = if prompt {
+                format!("set -g AFPACK_PROMPT ({})", run)
+            } else {
+                run
+            };
+            format!(
+                r#"function _afpack_hook --on-variable PWD
+  {}
+end
+_afpack_hook
+"#,
+                body
+            )
+        }
+    }
+}
+
+/// Managed directories of the project the shell is in
+#[derive(Debug, Default, PartialEq)]
+pub struct HookStatus {
+    /// Directories that should be attached but are not mounted
+    pub pending: Vec<PathBuf>,
+    pub attached: usize,
+    pub total: usize,
+}
+
+impl HookStatus {
+    /// Inspect the entries whose project contains `cwd`
+    ///
+    /// Only recorded-attached and idle-detached entries are pending, a
+    /// directory detached with `afpack detach` stays detached.
+    pub fn check(registry: &Registry, cwd: &Path, is_mounted: impl Fn(&Path) -> bool) -> Self {
+        let mut status = Self::default();
+        for entry in registry.entries.iter().filter(|e| in_project(e, cwd)) {
+            status.total += 1;
+            if is_mounted(&entry.afdir) {
+                status.attached += 1;
+            } else if entry.attached || entry.idle_detached {
+                status.pending.push(entry.afdir.clone());
+            }
+        }
+        status
+    }
+
+    /// Prompt segment such as `af 2/3`, empty outside managed projects
+    pub fn segment(&self) -> String {
+        if self.total == 0 {
+            return String::new();
+        }
+        format!("af {}/{}", self.attached, self.total)
+    }
+}
+
+/// Whether `cwd` is inside the directory holding the entry's artifact directory
+pub fn in_project(entry: &Entry, cwd: &Path) -> bool {
+    entry
+        .afdir
+        .parent()
+        .is_some_and(|project| cwd.starts_with(project))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippets() {
+        let program = Path::new("/opt/bin/afpack");
+        let zsh = snippet(Shell::Zsh, program, false);
+        assert!(zsh.contains("  '/opt/bin/afpack' hook-exec\n"));
+        assert!(zsh.contains("chpwd_functions=(_afpack_hook $chpwd_functions)"));
+
+        let bash = snippet(Shell::Bash, program, true);
+        assert!(bash.contains("AFPACK_PROMPT=\"$('/opt/bin/afpack' hook-exec --prompt)\""));
+        assert!(bash.contains("PROMPT_COMMAND=\"_afpack_hook${PROMPT_COMMAND:+;$PROMPT_COMMAND}\""));
+
+        let fish = snippet(Shell::Fish, Path::new("/Users/o'neil/afpack"), true);
+        assert!(fish.starts_with("function _afpack_hook --on-variable PWD\n"));
+        assert!(
+            fish.contains("set -g AFPACK_PROMPT ('/Users/o'\\''neil/afpack' hook-exec --prompt)")
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("web/node_modules"), "'web/node_modules'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_status() {
+        let mut registry = Registry::default();
+        let mut node_modules = Entry::new("/p/node_modules", "/p/node_modules.asif", "10G");
+        node_modules.attached = true;
+        let mut target = Entry::new("/p/target", "/p/target.asif", "10G");
+        target.idle_detached = true;
+        let build = Entry::new("/p/.build", "/p/.build.asif", "10G");
+        let other = Entry::new("/q/node_modules", "/q/node_modules.asif", "10G");
+        for entry in [node_modules, target, build, other] {
+            registry.upsert(entry);
+        }
+
+        let status = HookStatus::check(&registry, Path::new("/p/src"), |_| false);
+        assert_eq!(
+            status.pending,
+            vec![PathBuf::from("/p/node_modules"), PathBuf::from("/p/target")]
+        );
+        assert_eq!(status.segment(), "af 0/3");
+
+        let status = HookStatus::check(&registry, Path::new("/p"), |afdir| {
+            afdir == Path::new("/p/node_modules")
+        });
+        assert_eq!(status.pending, vec![PathBuf::from("/p/target")]);
+        assert_eq!(status.segment(), "af 1/3");
+
+        let status = HookStatus::check(&registry, Path::new("/"), |_| false);
+        assert_eq!(status, HookStatus::default());
+        assert_eq!(status.segment(), "");
+    }
+}
@@ -3,6 +3,31 @@
 //! This crate provides utilities for working with Apple Sparse Image Format (ASIF)
 //! and includes a diskimage utility for managing disk images on macOS.
 
+pub mod activity;
+pub mod compression;
+pub mod config;
+pub mod daemon;
+pub mod diff;
 pub mod diskimage;
+pub mod doctor;
+pub mod ecosystem;
+pub mod estimate;
+pub mod git;
+pub mod hook;
+pub mod log;
+pub mod mount;
+pub mod output;
+pub mod plan;
+pub mod policy;
+pub mod registry;
+pub mod secret;
+pub mod service;
+pub mod snapshot;
+pub mod variant;
+pub mod worktree;
 
-pub use diskimage::*;
\ No newline at end of file
+#[cfg(test)]
+mod testutil;
+
+pub use diskimage::*;
+pub use mount::MountedImage;
\ No newline at end of file
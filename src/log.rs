@@ -0,0 +1,333 @@
+//! Diagnostics through `tracing`: the console and a rotating JSON log
+//!
+//! The console shows warnings, or debug output with `--verbose`; `AFPACK_LOG`
+//! replaces that filter with an `EnvFilter` directive. Debug output also goes
+//! to `<state dir>/logs/afpack.<date>.log`, one JSON object per line, with the
+//! time spent in each closed span. Each command runs in an `operation` span
+//! whose `started` and `finished` events `afpack log` reads back.
+
+use crate::diskimage::Result;
+use crate::registry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::span::EnteredSpan;
+use tracing::Subscriber;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Target of the events recording operations
+pub const OPERATION_TARGET: &str = "afpack::operation";
+
+/// Number of daily log files kept
+pub const KEEP_DAYS: usize = 14;
+
+const FILE_PREFIX: &str = "afpack";
+const FILE_SUFFIX: &str = "log";
+
+/// Messages shown by the output module and operation records go to the file only
+const CONSOLE_DIRECTIVES: &str = "afpack::output=off,afpack::operation=off";
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Directory of the log files
+pub fn log_dir() -> PathBuf {
+    registry::state_dir().join("logs")
+}
+
+/// Install the console and log file subscribers, once at startup
+///
+/// Without a writable state dir only the console is set up.
+pub fn init(verbose: bool) {
+    let console_filter = std::env::var("AFPACK_LOG")
+        .ok()
+        .and_then(|directives| EnvFilter::try_new(directives).ok())
+        .unwrap_or_else(|| {
+            let level = if verbose { "debug" } else { "warn" };
+            EnvFilter::new(format!("afpack={},{}", level, CONSOLE_DIRECTIVES))
+        });
+    let console = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .without_time()
+        .with_target(false)
+        .with_filter(console_filter);
+    let dir = log_dir();
+    let file = std::fs::create_dir_all(&dir)
+        .ok()
+        .and_then(|()| {
+            RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(FILE_PREFIX)
+                .filename_suffix(FILE_SUFFIX)
+                .max_log_files(KEEP_DAYS)
+                .build(&dir)
+                .ok()
+        })
+        .map(|appender| file_layer(appender).with_filter(EnvFilter::new("afpack=debug")));
+    let _ = tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .try_init();
+}
+
+/// JSON lines with span timings, as written to the log file
+fn file_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(writer)
+}
+
+/// Enter the span of a command and log that it started
+pub fn begin(command: &str, args: &[String], dry_run: bool) -> EnteredSpan {
+    STARTED.get_or_init(Instant::now);
+    let span = tracing::info_span!(target: OPERATION_TARGET, "operation", command).entered();
+    tracing::info!(
+        target: OPERATION_TARGET,
+        pid = std::process::id(),
+        command,
+        args = args.join(" "),
+        dry_run,
+        "started"
+    );
+    span
+}
+
+/// Log how the command begun with [`begin`] ended, `error` set if it failed
+pub fn finish(exit_code: i32, error: Option<&str>) {
+    let Some(started) = STARTED.get() else {
+        return;
+    };
+    let pid = std::process::id();
+    let duration_ms = started.elapsed().as_millis() as u64;
+    match error {
+        None => tracing::info!(
+            target: OPERATION_TARGET,
+            pid,
+            exit_code,
+            duration_ms,
+            "finished"
+        ),
+        Some(error) => tracing::error!(
+            target: OPERATION_TARGET,
+            pid,
+            exit_code,
+            duration_ms,
+            error,
+            "finished"
+        ),
+    }
+}
+
+/// How a logged operation ended
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub enum Outcome {
+    Succeeded {
+        duration_ms: u64,
+    },
+    Failed {
+        exit_code: i32,
+        duration_ms: u64,
+        error: String,
+    },
+    /// Still running, or the process died before logging the end
+    Unfinished,
+}
+
+impl Outcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Outcome::Succeeded { .. })
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Succeeded { duration_ms } => {
+                write!(f, "ok in {:.1}s", *duration_ms as f64 / 1000.0)
+            }
+            Outcome::Failed {
+                exit_code,
+                duration_ms,
+                error,
+            } => write!(
+                f,
+                "failed ({}) in {:.1}s: {}",
+                exit_code,
+                *duration_ms as f64 / 1000.0,
+                error
+            ),
+            Outcome::Unfinished => write!(f, "unfinished"),
+        }
+    }
+}
+
+/// A command recorded in the log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Operation {
+    /// RFC 3339 time the command started
+    pub timestamp: String,
+    pub pid: u32,
+    pub command: String,
+    pub args: String,
+    pub dry_run: bool,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 2026-10-18T12:00:01.123456Z -> 2026-10-18 12:00:01
+        let time: String = self.timestamp.chars().take(19).collect();
+        write!(
+            f,
+            "{}  {:<10} {}{}  {}",
+            time.replace('T', " "),
+            self.command,
+            if self.dry_run { "(dry run) " } else { "" },
+            self.outcome,
+            self.args
+        )
+    }
+}
+
+/// A line of the log file, only the fields of operation events
+#[derive(Deserialize)]
+struct Line {
+    timestamp: String,
+    target: String,
+    #[serde(default)]
+    fields: Fields,
+}
+
+#[derive(Default, Deserialize)]
+struct Fields {
+    message: Option<String>,
+    pid: Option<u32>,
+    command: Option<String>,
+    args: Option<String>,
+    dry_run: Option<bool>,
+    exit_code: Option<i32>,
+    duration_ms: Option<u64>,
+    error: Option<String>,
+}
+
+/// Operations in the default log directory, oldest first
+pub fn operations() -> Result<Vec<Operation>> {
+    operations_in(&log_dir())
+}
+
+/// Operations in the log files in `dir`, oldest first
+pub fn operations_in(dir: &Path) -> Result<Vec<Operation>> {
+    let mut files: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        name.starts_with(FILE_PREFIX) && name.ends_with(FILE_SUFFIX)
+                    })
+            })
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    // Dated names sort chronologically
+    files.sort();
+
+    let mut operations = Vec::new();
+    let mut running: HashMap<u32, usize> = HashMap::new();
+    for file in files {
+        for line in std::fs::read_to_string(&file)?.lines() {
+            let Ok(line) = serde_json::from_str::<Line>(line) else {
+                continue;
+            };
+            let (true, Some(pid)) = (line.target == OPERATION_TARGET, line.fields.pid) else {
+                continue;
+            };
+            let fields = line.fields;
+            match fields.message.as_deref() {
+                Some("started") => {
+                    running.insert(pid, operations.len());
+                    operations.push(Operation {
+                        timestamp: line.timestamp,
+                        pid,
+                        command: fields.command.unwrap_or_default(),
+                        args: fields.args.unwrap_or_default(),
+                        dry_run: fields.dry_run.unwrap_or(false),
+                        outcome: Outcome::Unfinished,
+                    });
+                }
+                Some("finished") => {
+                    let Some(i) = running.remove(&pid) else {
+                        continue;
+                    };
+                    let duration_ms = fields.duration_ms.unwrap_or(0);
+                    operations[i].outcome = match fields.error {
+                        None => Outcome::Succeeded { duration_ms },
+                        Some(error) => Outcome::Failed {
+                            exit_code: fields.exit_code.unwrap_or(1),
+                            duration_ms,
+                            error,
+                        },
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_operations_roundtrip() {
+        let dir = TempDir::new("log");
+        let appender = tracing_appender::rolling::never(&dir, "afpack.2026-10-18.log");
+        let subscriber = tracing_subscriber::registry().with(file_layer(appender));
+        tracing::subscriber::with_default(subscriber, || {
+            {
+                let _operation = begin("detach", &["afpack".into(), "detach".into()], false);
+                tracing::info_span!("step").in_scope(|| tracing::debug!("unmounting"));
+                finish(10, Some("/p/target is busy"));
+            }
+            let _operation = begin("pack", &["afpack".into()], true);
+        });
+        std::fs::write(dir.join("unrelated.txt"), "not a log").unwrap();
+
+        let operations = operations_in(&dir).unwrap();
+        assert_eq!(operations.len(), 2);
+        assert_eq!(operations[0].command, "detach");
+        assert_eq!(operations[0].args, "afpack detach");
+        assert!(matches!(
+            &operations[0].outcome,
+            Outcome::Failed { exit_code: 10, error, .. } if error == "/p/target is busy"
+        ));
+        assert!(operations[1].dry_run);
+        assert_eq!(operations[1].outcome, Outcome::Unfinished);
+    }
+
+    #[test]
+    fn test_missing_dir() {
+        assert!(operations_in(Path::new("/nonexistent/afpack/logs"))
+            .unwrap()
+            .is_empty());
+    }
+}
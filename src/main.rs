@@ -1,211 +1,2274 @@
-use applesauce::compressor::Kind;
-use applesauce::progress::{Progress, Task};
-use applesauce::FileCompressor;
-use clap::Parser;
-use std::path::Path;
-
-// NoProgress implementation for applesauce
-struct NoProgress;
-
-impl Task for NoProgress {
-    fn increment(&self, _amt: u64) {}
-    fn error(&self, _message: &str) {}
-}
-
-impl Progress for NoProgress {
-    type Task = NoProgress;
-
-    fn error(&self, _path: &Path, _message: &str) {
-        eprintln!("Error at {}: {}", _path.display(), _message);
-    }
-
-    fn file_task(&self, _path: &Path, _size: u64) -> Self::Task {
-        NoProgress
-    }
-}
-use std::process::{exit, Command};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::BTreeMap;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command, ExitStatus};
 use std::sync::OnceLock;
+use tracing::debug;
 
-pub mod diskimage;
-use diskimage::{AttachOptions, CreateFromOptions, DiskImage, DiskImageError, ResizeOptions};
+use afpack::activity::{self, Signal};
+use afpack::compression::{
+    self, Algorithm, CompressionPolicy, CompressionState, ProgressReporter, Schedule, Target,
+};
+use afpack::config::{self, Config, Disposal, Settings, Source};
+use afpack::daemon::{self, Client};
+use afpack::diff;
+use afpack::diskimage::{
+    self, AttachOptions, DetachOptions, DiskImage, DiskImageError, Encryption, ErrorKind,
+};
+use afpack::doctor::{self, Os, Status};
+use afpack::ecosystem::Ecosystem;
+use afpack::estimate::{self, EstimateOptions, Samples};
+use afpack::git;
+use afpack::hook::{self, HookStatus, Shell};
+use afpack::log;
+use afpack::output::{self, Event, OutputMode};
+use afpack::plan::{PackState, Plan, Step};
+use afpack::registry::{self, Entry, Registry, VariantRecord};
+use afpack::secret::PassphraseSource;
+use afpack::service::login_services;
+use afpack::snapshot::{self, SnapshotStore};
+use afpack::variant::{self, Variant, VariantBy};
+use afpack::worktree;
 
 // Global flags
 static DRY_RUN: OnceLock<bool> = OnceLock::new();
-static VERBOSE: OnceLock<bool> = OnceLock::new();
 
 fn is_dry_run() -> bool {
     *DRY_RUN.get().unwrap_or(&false)
 }
 
-// Verbose logging utility
-fn vlog(msg: &str) {
-    if *VERBOSE.get().unwrap_or(&false) {
-        println!("{}", msg);
-    }
+/// Print a line of human output, a message event in the machine modes
+macro_rules! say {
+    ($($arg:tt)*) => {
+        output::emit(Event::info(format!($($arg)*)))
+    };
+}
+
+/// Report a problem that does not stop the command
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        output::emit(Event::warning(format!($($arg)*)))
+    };
 }
 
+const EXIT_STATUS: &str = "\
+Exit status:
+  0   success
+  1   any other error
+  2   invalid arguments, options or configuration
+  10  a volume is busy, processes have files open on it
+  11  no space left
+  12  the image is already attached
+  13  unsupported image format
+  14  permission denied
+  15  image, directory or program not found
+
+`afpack exec` exits with the status of its command.";
+
 #[derive(Parser)]
 #[command(name = "afpack")]
 #[command(about = "CLI tool for managing large dependency folders using ASIF")]
 #[command(version = "0.1.0")]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(after_help = EXIT_STATUS)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Artifact directory (node_modules, target, .build, etc.)
     /// If not specified, will auto-detect common directories
     afdir: Option<String>,
 
-    /// Compression algorithm
-    #[arg(long, default_value = "none")]
-    #[arg(help = "Compression algorithm: none, lzfse, lzvn, zlib")]
-    compress: String,
+    #[command(flatten)]
+    compression: CompressArgs,
 
-    /// Maximum ASIF size
-    #[arg(long, default_value = "10G")]
-    maxsize: String,
+    /// Maximum ASIF size [default: 10G]
+    #[arg(long)]
+    maxsize: Option<String>,
 
-    /// Show what would be done without actually doing it
+    /// Encrypt new images
+    #[arg(long, value_enum)]
+    encrypt: Option<Encryption>,
+
+    /// Passphrase source of encrypted images: keychain, secret-service, pass, stdin or env:VAR
+    /// [default: keychain]
     #[arg(long)]
+    passphrase_from: Option<PassphraseSource>,
+
+    /// Detach the image after this many seconds without open files
+    ///
+    /// Checked by `afpack maintain`, scheduled with `afpack service install`, or by afpackd.
+    #[arg(long)]
+    detach_after: Option<u64>,
+
+    /// Show what would be done without actually doing it
+    #[arg(long, global = true)]
     dry_run: bool,
 
+    /// Send detach, remount and list to a running afpackd
+    #[arg(long, global = true)]
+    daemon: bool,
+
     /// Enable verbose output
-    #[arg(long, short)]
+    #[arg(long, short, global = true)]
     verbose: bool,
+
+    /// Print JSON lines: messages, commands run, progress, the result and errors
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Like --json, but messages go to stderr so stdout only carries data
+    #[arg(long, global = true, conflicts_with = "json")]
+    porcelain: bool,
+}
+
+/// Compression flags, each overriding the matching `compression.*` config key
+#[derive(Args)]
+struct CompressArgs {
+    /// Transparent compression algorithm [default: none]
+    #[arg(long, value_enum)]
+    compress: Option<Algorithm>,
+
+    /// Compression level (zlib only, 1-9) [default: 5]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=9))]
+    compress_level: Option<u32>,
+
+    /// Skip files saving less than this fraction of their size [default: 0.05]
+    #[arg(long)]
+    min_savings: Option<f64>,
+
+    /// Files compressed at once [default: one per CPU]
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    compress_threads: Option<usize>,
+
+    /// Compress the image file or the files inside the volume [default: image]
+    #[arg(long, value_enum)]
+    compress_target: Option<Target>,
+
+    /// When to compress the image file [default: on-pack]
+    #[arg(long, value_enum)]
+    compress_when: Option<Schedule>,
+
+    /// Seconds without writes before an idle image is compressed [default: 1800]
+    #[arg(long)]
+    idle_after: Option<u64>,
+
+    /// Decompress a compressed image before attaching it
+    #[arg(long)]
+    decompress_on_attach: bool,
+}
+
+impl CompressArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(algorithm) = self.compress {
+            config.set("compression.algorithm", algorithm.to_string(), Source::Cli);
+        }
+        if let Some(level) = self.compress_level {
+            config.set("compression.level", i64::from(level), Source::Cli);
+        }
+        if let Some(min_savings) = self.min_savings {
+            config.set("compression.min_savings", min_savings, Source::Cli);
+        }
+        if let Some(threads) = self.compress_threads {
+            config.set("compression.threads", threads as i64, Source::Cli);
+        }
+        if let Some(target) = self.compress_target {
+            config.set("compression.target", target.to_string(), Source::Cli);
+        }
+        if let Some(schedule) = self.compress_when {
+            config.set("compression.schedule", schedule.to_string(), Source::Cli);
+        }
+        if let Some(idle_after) = self.idle_after {
+            config.set("compression.idle_after", idle_after as i64, Source::Cli);
+        }
+        if self.decompress_on_attach {
+            config.set("compression.decompress_on_attach", true, Source::Cli);
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Attach the image matching the current branch or lockfile
+    Switch {
+        /// Managed artifact directories
+        #[arg(required = true)]
+        afdirs: Vec<String>,
+    },
+    /// Git integration
+    Git {
+        #[command(subcommand)]
+        command: GitCommands,
+    },
+    /// Take a copy-on-write snapshot of an artifact directory image
+    Snapshot {
+        /// Managed artifact directory
+        afdir: String,
+
+        /// Label to find the snapshot by later
+        #[arg(long)]
+        label: Option<String>,
+
+        /// Number of snapshots to retain, older ones are deleted
+        #[arg(
+            long,
+            default_value_t = 5,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        keep: usize,
+
+        /// List snapshots instead of taking one
+        #[arg(long)]
+        list: bool,
+    },
+    /// Restore an artifact directory image from a snapshot
+    Rollback {
+        /// Managed artifact directory
+        afdir: String,
+
+        /// Snapshot id or label, defaults to the most recent snapshot
+        snapshot: Option<String>,
+    },
+    /// Show files added, removed and modified between two images
+    ///
+    /// Each side is a directory, an image file, or `<afdir>@<snapshot id|label>`.
+    Diff { a: String, b: String },
+    /// Predict compressed size, savings and time for each algorithm
+    ///
+    /// Samples blocks from the image (or any file or directory) and compresses
+    /// them in memory, without touching the data on disk.
+    Estimate {
+        /// Managed artifact directory, image file or plain directory
+        path: String,
+
+        /// Sample the image file or the files inside the volume
+        #[arg(long, value_enum, default_value_t = Target::Image)]
+        target: Target,
+
+        /// Number of 64 KiB blocks to sample
+        #[arg(long, default_value_t = 256)]
+        samples: usize,
+
+        /// Compression level (zlib only, 1-9)
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..=9))]
+        level: u32,
+    },
+    /// Undo transparent compression of an image or artifact directory
+    Decompress {
+        /// Managed artifact directory, image file or plain directory
+        path: String,
+
+        /// Decompress the image file or the files inside the volume
+        #[arg(long, value_enum, default_value_t = Target::Image)]
+        target: Target,
+    },
+    /// Detach artifact directory images, compressing them if scheduled on detach
+    ///
+    /// While a volume is busy the processes holding it are listed and the
+    /// unmount is retried.
+    Detach {
+        /// Managed artifact directories
+        #[arg(required = true)]
+        afdirs: Vec<String>,
+
+        /// Retries while the volume is busy
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+
+        /// Send this signal to the processes holding the volume before retrying
+        #[arg(long, value_enum)]
+        signal: Option<Signal>,
+
+        /// Force the unmount once the retries are used up
+        #[arg(long)]
+        force: bool,
+    },
+    /// Reattach images that should be attached but are not mounted, e.g. after a reboot
+    Remount {
+        /// Managed artifact directories
+        #[arg(required_unless_present = "all")]
+        afdirs: Vec<String>,
+
+        /// Every directory the registry records as attached
+        #[arg(long, conflicts_with = "afdirs")]
+        all: bool,
+    },
+    /// Run a command with the images attached, detaching them when it exits
+    ///
+    /// Images that were already attached stay attached. The command's exit
+    /// status is passed through, e.g. `afpack exec -- npm test`.
+    Exec {
+        /// Managed artifact directories [default: the configured ones]
+        #[arg(long = "dir", short = 'd')]
+        afdirs: Vec<String>,
+
+        /// Leave the images attached after the command
+        #[arg(long)]
+        keep_attached: bool,
+
+        /// Compress the images after detaching them
+        ///
+        /// Uses the configured compression, or lzfse when it is off.
+        #[arg(long, conflicts_with = "keep_attached")]
+        compact: bool,
+
+        /// Copy the images this run detached into DIR, e.g. a CI cache directory
+        #[arg(long, value_name = "DIR", conflicts_with = "keep_attached")]
+        save_to: Option<PathBuf>,
+
+        /// Command and its arguments
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Print the shell snippet that attaches a project's images on `cd`
+    ///
+    /// Add `eval "$(afpack hook zsh)"` to ~/.zshrc, the bash equivalent to
+    /// ~/.bashrc, or `afpack hook fish | source` to config.fish.
+    Hook {
+        #[arg(value_enum)]
+        shell: Shell,
+
+        /// Keep the attached/managed count in $AFPACK_PROMPT for the prompt
+        #[arg(long)]
+        prompt: bool,
+    },
+    /// Attach the current project's images if needed, run by the shell hook
+    #[command(hide = true)]
+    HookExec {
+        /// Print the prompt segment
+        #[arg(long)]
+        prompt: bool,
+    },
+    /// Remount images at login and detach idle ones, with launchd agents or systemd user units
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommands,
+    },
+    /// Detach idle images and compress detached images whose idle compression is due
+    ///
+    /// `afpack service install` runs it every 5 minutes. Without that service
+    /// or afpackd, idle images are never detached.
+    Maintain,
+    /// List managed artifact directories
+    List,
+    /// Show the image, attach and compression state of an artifact directory
+    Status {
+        /// Managed artifact directory
+        afdir: String,
+    },
+    /// Verify that every configured directory complies with the project policy
+    Check,
+    /// Diagnose the OS, backends, filesystem, free space and registry
+    ///
+    /// Every failed check comes with a hint on how to fix it. Exits with 1 if
+    /// any check found an error.
+    Doctor,
+    /// Run a plan printed by `--dry-run --porcelain`
+    ///
+    /// e.g. `afpack --dry-run --porcelain > plan.json`, review it, then
+    /// `afpack apply plan.json`. The file may also hold just the plan object.
+    Apply {
+        /// Plan file, `-` for standard input
+        plan: String,
+    },
+    /// Show recent afpack commands and how they ended
+    ///
+    /// Read from the JSON log files in <state dir>/logs, which also hold the
+    /// debug output and step timings of each command.
+    Log {
+        /// Number of commands to show
+        #[arg(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+
+        /// Only failed and unfinished commands
+        #[arg(long)]
+        failed: bool,
+    },
+    /// Inspect the layered configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Share one read-only base image between git worktrees
+    Worktree {
+        #[command(subcommand)]
+        command: WorktreeCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum WorktreeCommands {
+    /// Attach the base image for the current lockfile with a private shadow
+    Attach {
+        /// Artifact directories in this worktree
+        #[arg(required = true)]
+        afdirs: Vec<String>,
+
+        /// Maximum ASIF size of a newly created base image [default: maxsize]
+        #[arg(long)]
+        maxsize: Option<String>,
+    },
+    /// List worktree shadows
+    List,
+    /// Delete shadows of removed worktrees and outdated lockfiles
+    Clean {
+        /// Also detach and discard the shadows of live worktrees
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceCommands {
+    /// Install and enable services running `afpack remount --all` at login and
+    /// `afpack maintain` every 5 minutes
+    ///
+    /// LaunchAgents on macOS, systemd user units and a timer on Linux.
+    Install,
+    /// Disable and remove the service
+    Uninstall,
+    /// Show whether the service is installed and loaded
+    Status,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the effective config and where each value comes from
+    Show,
+}
+
+#[derive(Subcommand)]
+enum GitCommands {
+    /// Install a post-checkout hook that runs `afpack switch`
+    InstallHooks {
+        /// Managed artifact directories, defaults to the configured dirs
+        afdirs: Vec<String>,
+
+        /// Keep one image per branch or per lockfile hash [default: hooks.variant_by]
+        #[arg(long, value_enum)]
+        by: Option<VariantBy>,
+    },
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     DRY_RUN.set(cli.dry_run).unwrap();
-    VERBOSE.set(cli.verbose).unwrap();
+    output::set_mode(if cli.porcelain {
+        OutputMode::Porcelain
+    } else if cli.json {
+        OutputMode::Json
+    } else {
+        OutputMode::Human
+    });
 
-    if !check_macos_compatibility() {
-        eprintln!("ASIF creation requires macOS 26 Tahoe or later");
-        exit(1);
+    // Runs on every cd, so it skips the version check and config loading
+    if let Some(Commands::HookExec { prompt }) = cli.command {
+        if let Err(e) = hook_exec(prompt) {
+            eprintln!("afpack: {}", e);
+        }
+        return;
     }
-    // Get artifact directory (must be specified)
-    let Some(afdir) = cli.afdir else {
-        eprintln!("Error: Artifact directory must be specified.");
-        exit(1);
-    };
-    vlog(&format!(
-        "Options:\n\tArtifact directory: {}\n\tCompression: {}\n\tMax size: {}\n\tDry run: {}",
-        afdir, cli.compress, cli.maxsize, cli.dry_run
-    ));
-    // Check macOS version compatibility
-    let asif_path = format!("{}.asif", afdir);
-
-    if !Path::new(&asif_path).exists() {
-        if let Err(e) = create_asif_image(&afdir, &asif_path, &cli.maxsize, &cli.compress) {
-            eprintln!("error create image: {}", e);
-            exit(1);
-        }
-        if cli.dry_run {
-            println!("[DRY RUN] removing {}", afdir);
-        } else {
-            trash::delete(&afdir).unwrap();
+
+    log::init(cli.verbose);
+    let command = matches.subcommand_name().unwrap_or("pack");
+    // `afpack log` would list itself as unfinished
+    let _operation = (command != "log").then(|| {
+        let args: Vec<String> = std::env::args().collect();
+        log::begin(command, &args, cli.dry_run)
+    });
+    run(cli);
+    log::finish(0, None);
+}
+
+/// Run the command selected on the command line
+fn run(cli: Cli) {
+    if let Some(Commands::Doctor) = cli.command {
+        doctor(&cli);
+        return;
+    }
+    if let Os::MacOs {
+        version: Some(version),
+    } = Os::detect()
+    {
+        if version < doctor::MIN_MACOS {
+            abort(
+                format!(
+                    "macOS {} is not supported, afpack needs macOS {} or later; run `afpack doctor` for details",
+                    version,
+                    doctor::MIN_MACOS
+                ),
+                ErrorKind::Other,
+                1,
+            );
         }
     }
 
-    if let Err(e) = DiskImage::attach(
-        &asif_path,
-        AttachOptions::new()
-            .with_dry_run(cli.dry_run)
-            .with_verbose(cli.verbose)
-            .with_mount_point(&afdir),
+    let config = load_config(&cli).unwrap_or_else(|e| {
+        fail("error loading config", &e);
+    });
+
+    // Running afpack on a project's images brings back its idle-detached ones
+    if matches!(
+        cli.command,
+        None | Some(
+            Commands::Switch { .. }
+                | Commands::Snapshot { .. }
+                | Commands::Rollback { .. }
+                | Commands::Exec { .. }
+                | Commands::Status { .. }
+                | Commands::Check
+        )
     ) {
-        eprintln!("Error attaching ASIF: {}", e);
-        exit(1);
+        if let Err(e) = std::env::current_dir()
+            .map_err(DiskImageError::from)
+            .and_then(|cwd| reattach_idle(&cwd))
+        {
+            warn!("could not reattach idle images: {}", e);
+        }
     }
-    vlog(&format!("attached {} -> {}", asif_path, afdir));
 
-    FileCompressor::new().recursive_compress(
-        std::iter::once(Path::new(&asif_path)),
-        applesauce::compressor::Kind::Lzfse,
-        1.0,
-        2,
-        &NoProgress,
-        true,
+    match cli.command {
+        Some(Commands::Switch { afdirs }) => {
+            for afdir in &afdirs {
+                if let Err(e) = switch_variant(afdir) {
+                    fail(&format!("error switching {}", afdir), &e);
+                }
+            }
+            done("switch");
+            return;
+        }
+        Some(Commands::Git {
+            command: GitCommands::InstallHooks { afdirs, by },
+        }) => {
+            let result = config.settings().and_then(|settings| {
+                let afdirs = if afdirs.is_empty() {
+                    settings.dirs.iter().map(|d| d.path().to_string()).collect()
+                } else {
+                    afdirs
+                };
+                install_hooks(&afdirs, by.unwrap_or(settings.hooks.variant_by))
+            });
+            if let Err(e) = result {
+                fail("error installing hooks", &e);
+            }
+            done("git");
+            return;
+        }
+        Some(Commands::Snapshot {
+            afdir,
+            label,
+            keep,
+            list,
+        }) => {
+            let result = if list {
+                snapshot_list(&afdir)
+            } else {
+                snapshot_create(&afdir, label.as_deref(), keep).map(|()| done("snapshot"))
+            };
+            if let Err(e) = result {
+                fail("error", &e);
+            }
+            return;
+        }
+        Some(Commands::Rollback { afdir, snapshot }) => {
+            if let Err(e) = rollback(&afdir, snapshot.as_deref()) {
+                fail(&format!("error rolling back {}", afdir), &e);
+            }
+            done("rollback");
+            return;
+        }
+        Some(Commands::Diff { a, b }) => {
+            if let Err(e) = diff_images(&a, &b) {
+                fail("error", &e);
+            }
+            return;
+        }
+        Some(Commands::Estimate {
+            path,
+            target,
+            samples,
+            level,
+        }) => {
+            let options = EstimateOptions::new()
+                .with_samples(samples)
+                .with_level(level);
+            if let Err(e) = estimate_compression(&path, target, &options) {
+                fail("error", &e);
+            }
+            return;
+        }
+        Some(Commands::Decompress { path, target }) => {
+            if let Err(e) = decompress(&path, target) {
+                fail(&format!("error decompressing {}", path), &e);
+            }
+            done("decompress");
+            return;
+        }
+        Some(Commands::Detach { afdirs, .. }) if cli.daemon => {
+            if let Err(e) = daemon_call(&afdirs, false, false) {
+                fail("error", &e);
+            }
+            done("detach");
+            return;
+        }
+        Some(Commands::Detach {
+            afdirs,
+            retries,
+            signal,
+            force,
+        }) => {
+            let mut options = DetachOptions::new().with_retries(retries).with_force(force);
+            if let Some(signal) = signal {
+                options = options.with_signal(signal);
+            }
+            for afdir in &afdirs {
+                if let Err(e) = detach(afdir, &options) {
+                    fail(&format!("error detaching {}", afdir), &e);
+                }
+            }
+            done("detach");
+            return;
+        }
+        Some(Commands::Remount { afdirs, all }) => {
+            let result = if cli.daemon {
+                daemon_call(&afdirs, all, true)
+            } else {
+                remount(&afdirs, all)
+            };
+            if let Err(e) = result {
+                fail("error", &e);
+            }
+            done("remount");
+            return;
+        }
+        Some(Commands::Exec {
+            afdirs,
+            keep_attached,
+            compact,
+            save_to,
+            command,
+        }) => {
+            let result = config.settings().and_then(|settings| {
+                let afdirs = if afdirs.is_empty() {
+                    settings.dirs.iter().map(|d| d.path().to_string()).collect()
+                } else {
+                    afdirs
+                };
+                exec(&afdirs, &command, keep_attached, compact, save_to)
+            });
+            match result {
+                Ok(status) => {
+                    if output::mode().is_machine() {
+                        output::emit(Event::result(
+                            "exec",
+                            serde_json::json!({ "code": status.code(), "signal": status.signal() }),
+                        ));
+                    }
+                    exit_with(status)
+                }
+                Err(e) => {
+                    fail("error", &e);
+                }
+            }
+        }
+        Some(Commands::Hook { shell, prompt }) => {
+            let program = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("afpack"));
+            print!("{}", hook::snippet(shell, &program, prompt));
+            return;
+        }
+        Some(Commands::HookExec { .. } | Commands::Doctor) => {
+            unreachable!("handled before loading the config")
+        }
+        Some(Commands::Service { command }) => {
+            if let Err(e) = service(command) {
+                fail("error", &e);
+            }
+            done("service");
+            return;
+        }
+        Some(Commands::Maintain) => {
+            if let Err(e) = maintain() {
+                fail("error", &e);
+            }
+            done("maintain");
+            return;
+        }
+        Some(Commands::List) => {
+            let result = if cli.daemon {
+                daemon_list()
+            } else {
+                list_entries()
+            };
+            if let Err(e) = result {
+                fail("error", &e);
+            }
+            return;
+        }
+        Some(Commands::Status { afdir }) => {
+            if let Err(e) = status(&afdir) {
+                fail("error", &e);
+            }
+            return;
+        }
+        Some(Commands::Check) => match check(&config) {
+            Ok(true) => return,
+            Ok(false) => {
+                log::finish(1, Some("policy violations"));
+                exit(1)
+            }
+            Err(e) => {
+                fail("error", &e);
+            }
+        },
+        Some(Commands::Apply { plan }) => {
+            if let Err(e) = apply(&plan) {
+                fail(&format!("error applying {}", plan), &e);
+            }
+            done("apply");
+            return;
+        }
+        Some(Commands::Log { limit, failed }) => {
+            if let Err(e) = show_log(limit, failed) {
+                fail("error reading the log", &e);
+            }
+            return;
+        }
+        Some(Commands::Config {
+            command: ConfigCommands::Show,
+        }) => {
+            if let Err(e) = config_show(&config) {
+                fail("error", &e);
+            }
+            return;
+        }
+        Some(Commands::Worktree { command }) => {
+            let result = match command {
+                WorktreeCommands::Attach { afdirs, maxsize } => {
+                    config.settings().and_then(|settings| {
+                        afdirs.iter().try_for_each(|afdir| {
+                            let maxsize = maxsize
+                                .as_deref()
+                                .unwrap_or_else(|| settings.maxsize_for(afdir));
+                            worktree_attach(afdir, maxsize, &settings)
+                        })
+                    })
+                }
+                .map(|()| done("worktree")),
+                WorktreeCommands::List => worktree_list(),
+                WorktreeCommands::Clean { all } => worktree_clean(all).map(|()| done("worktree")),
+            };
+            if let Err(e) = result {
+                fail("error", &e);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    let settings = config.settings().unwrap_or_else(|e| {
+        fail("error loading config", &e);
+    });
+    // Artifact directories given on the command line or declared in the config
+    let afdirs: Vec<String> = match cli.afdir {
+        Some(afdir) => vec![afdir],
+        None => settings.dirs.iter().map(|d| d.path().to_string()).collect(),
+    };
+    if afdirs.is_empty() {
+        abort(
+            format!(
+                "Error: Artifact directory must be specified or listed in {}.",
+                config::PROJECT_FILE
+            ),
+            ErrorKind::Other,
+            2,
+        );
+    }
+    // Without a configured algorithm a managed directory keeps the policy it was packed with
+    let keep_policy = config.source("compression.algorithm") == Some(&Source::Default);
+    let registry = Registry::load().unwrap_or_else(|e| {
+        fail("error loading registry", &e);
+    });
+    let mut plan = Plan::new();
+    for afdir in &afdirs {
+        match pack_plan(afdir, &settings, keep_policy, &registry) {
+            Ok(afdir_plan) => plan.extend(afdir_plan),
+            Err(e) => fail(&format!("error packing {}", afdir), &e),
+        }
+    }
+    if let Err(e) = execute(&plan) {
+        fail("error packing", &e);
+    }
+
+    if settings.hooks.post_checkout {
+        if let Err(e) = install_hooks(&afdirs, settings.hooks.variant_by) {
+            warn!("could not install git hooks: {}", e);
+        }
+    }
+    done("pack");
+}
+
+/// Report `e` with `context` and exit with its status
+fn fail(context: &str, e: &DiskImageError) -> ! {
+    abort(format!("{}: {}", context, e), e.kind(), exit_code(e))
+}
+
+/// Report an error and exit with `code`
+fn abort(message: String, kind: ErrorKind, code: i32) -> ! {
+    log::finish(code, Some(&message));
+    output::emit(Event::Error {
+        kind,
+        exit_code: code,
+        message,
+    });
+    exit(code)
+}
+
+/// Result event of a command without data to report
+fn done(command: &str) {
+    if output::mode().is_machine() {
+        output::emit(Event::result(command, serde_json::Value::Null));
+    }
+}
+
+/// Exit status for `e`, see EXIT_STATUS
+fn exit_code(e: &DiskImageError) -> i32 {
+    match e.kind() {
+        ErrorKind::Busy => 10,
+        ErrorKind::NoSpace => 11,
+        ErrorKind::AlreadyAttached => 12,
+        ErrorKind::UnsupportedFormat => 13,
+        ErrorKind::Permission => 14,
+        ErrorKind::NotFound => 15,
+        ErrorKind::Other => match e {
+            DiskImageError::Config(_)
+            | DiskImageError::Plan(_)
+            | DiskImageError::Validation(_)
+            | DiskImageError::InvalidSize(_) => 2,
+            _ => 1,
+        },
+    }
+}
+
+/// Plan moving an artifact directory into its image and attaching it in place
+fn pack_plan(
+    afdir: &str,
+    settings: &Settings,
+    keep_policy: bool,
+    registry: &Registry,
+) -> Result<Plan, DiskImageError> {
+    let maxsize = settings.maxsize_for(afdir);
+    debug!(
+        afdir,
+        compression = %settings.compression.algorithm,
+        maxsize,
+        "planning pack"
     );
+    // Reattach the selected variant when the directory is already managed
+    let afdir_abs = registry::absolute(afdir)?;
+    let entry = registry.get(&afdir_abs).cloned();
+    let image = match &entry {
+        Some(entry) => entry.image.clone(),
+        None => registry::absolute(format!("{}.asif", afdir))?,
+    };
+    let policy = match &entry {
+        Some(entry) if keep_policy => entry.compression.clone(),
+        _ => settings.compression.clone(),
+    };
+    if policy.target == Target::Source
+        && !matches!(policy.schedule, Schedule::OnPack | Schedule::Never)
+    {
+        return Err(DiskImageError::Config(
+            "the volume contents can only be compressed on pack".to_string(),
+        ));
+    }
+    let state = PackState {
+        image_exists: image.exists(),
+        afdir_exists: afdir_abs.exists(),
+        afdir: afdir_abs,
+        image,
+        maxsize: maxsize.to_string(),
+        entry,
+    };
+    Ok(Plan::pack(&state, settings, &policy))
+}
+
+/// Run `plan`, or show it in dry run
+fn execute(plan: &Plan) -> Result<(), DiskImageError> {
+    if !is_dry_run() {
+        return plan.run();
+    }
+    if output::mode().is_machine() {
+        output::emit(Event::result("plan", plan));
+    } else if !plan.is_empty() {
+        say!("[DRY RUN] Would:");
+        for (i, step) in plan.steps.iter().enumerate() {
+            say!("  {}. {}", i + 1, step);
+        }
+    }
+    Ok(())
+}
+
+/// Config layers plus the flags given on the command line
+fn load_config(cli: &Cli) -> Result<Config, DiskImageError> {
+    let mut config = Config::load(".")?;
+    if let Some(maxsize) = &cli.maxsize {
+        config.set("maxsize", maxsize.as_str(), Source::Cli);
+    }
+    if let Some(encryption) = cli.encrypt {
+        config.set("encryption", encryption.to_string(), Source::Cli);
+    }
+    if let Some(source) = &cli.passphrase_from {
+        config.set("passphrase_from", source.to_string(), Source::Cli);
+    }
+    if let Some(seconds) = cli.detach_after {
+        config.set("detach_after", seconds as i64, Source::Cli);
+    }
+    cli.compression.apply(&mut config);
+    Ok(config)
+}
+
+fn config_show(config: &Config) -> Result<(), DiskImageError> {
+    if output::mode().is_machine() {
+        let values: Vec<serde_json::Value> = config
+            .values()
+            .map(|(key, value, source)| {
+                serde_json::json!({ "key": key, "value": value, "source": source.to_string() })
+            })
+            .collect();
+        output::emit(Event::result(
+            "config",
+            serde_json::json!({ "values": values, "policy": config.policy() }),
+        ));
+        config.settings()?;
+        return Ok(());
+    }
+    print!("{}", config.render());
+    if let Some(policy) = config.policy() {
+        say!("\n[policy]");
+        say!("locked = {:?}", policy.locked);
+        if let Some(min) = &policy.min_maxsize {
+            say!("min_maxsize = {:?}", min);
+        }
+    }
+    config.settings()?;
+    Ok(())
 }
 
-fn check_macos_compatibility() -> bool {
-    // Simple check - in a real implementation, you'd parse the actual macOS version
-    let output = Command::new("sw_vers").arg("-productVersion").output();
+/// Report policy violations of the config and the managed directories, true if none
+fn check(config: &Config) -> Result<bool, DiskImageError> {
+    let settings = config.settings_unchecked()?;
+    let policy = config.policy().cloned().unwrap_or_default();
+    let registry = Registry::load()?;
 
-    if let Err(_) = output {
-        return false;
+    let mut problems: Vec<String> = policy
+        .config_violations(config)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    for dir in &settings.dirs {
+        let Some(entry) = registry.get(registry::absolute(dir.path())?) else {
+            problems.push(format!("{}: not managed by afpack", dir.path()));
+            continue;
+        };
+        if !entry.image.exists() {
+            problems.push(format!(
+                "{}: image {} is missing",
+                dir.path(),
+                entry.image.display()
+            ));
+        }
+        // The registry cannot tell the filesystem of adopted images, the volume can
+        let filesystem = if diskimage::is_mount_point(&entry.afdir) {
+            DiskImage::volume_filesystem(&entry.afdir).unwrap_or_else(|e| {
+                warn!("could not read the filesystem of {}: {}", dir.path(), e);
+                None
+            })
+        } else {
+            None
+        };
+        problems.extend(
+            policy
+                .entry_violations(config, entry, dir.path(), filesystem.as_ref())
+                .iter()
+                .map(ToString::to_string),
+        );
     }
 
-    let output = output.unwrap();
-    let version = String::from_utf8_lossy(&output.stdout);
-    // This is a simplified check - real implementation would parse version properly
-    !version.trim().is_empty()
+    if output::mode().is_machine() {
+        output::emit(Event::result(
+            "check",
+            serde_json::json!({ "ok": problems.is_empty(), "problems": problems }),
+        ));
+        return Ok(problems.is_empty());
+    }
+    if problems.is_empty() {
+        say!("ok: {} managed directories comply", settings.dirs.len());
+        return Ok(true);
+    }
+    for problem in &problems {
+        say!("{}", problem);
+    }
+    Ok(false)
 }
 
-fn apply_compression(compress: &str, path: &str) {
-    let compression_kind = match compress {
-        "lzfse" => Kind::Lzfse,
-        "lzvn" => Kind::Lzvn,
-        "zlib" => Kind::Zlib,
-        _ => {
-            eprintln!(
-                "Warning: Unknown compression type '{}', using default",
-                compress
+/// Run the environment checks and report them, exiting with 1 on errors
+fn doctor(cli: &Cli) {
+    let project = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+    let settings = load_config(cli).and_then(|config| config.settings());
+    let report = doctor::diagnose(&project, settings, Registry::load());
+
+    if output::mode().is_machine() {
+        output::emit(Event::result("doctor", &report));
+    } else {
+        say!("{}", report.os);
+        for check in &report.checks {
+            say!(
+                "{:<7} {:<18} {}",
+                format!("[{}]", check.status),
+                check.name,
+                check.detail
             );
-            Kind::default()
+            if let Some(fix) = &check.fix {
+                say!("{:<26} fix: {}", "", fix);
+            }
         }
+        say!(
+            "{} errors, {} warnings",
+            report.count(Status::Error),
+            report.count(Status::Warning)
+        );
+    }
+    if report.status() == Status::Error {
+        log::finish(1, Some("doctor found errors"));
+        exit(1);
+    }
+}
+
+fn apply_compression(policy: &CompressionPolicy, path: &Path) {
+    let plan = Plan {
+        steps: vec![Step::Compress {
+            path: path.to_path_buf(),
+            policy: policy.clone(),
+        }],
     };
+    if let Err(e) = execute(&plan) {
+        warn!("could not compress {}: {}", path.display(), e);
+    }
+}
+
+/// Print the last `limit` logged operations
+fn show_log(limit: usize, failed: bool) -> Result<(), DiskImageError> {
+    let mut operations = log::operations()?;
+    if failed {
+        operations.retain(|operation| !operation.outcome.is_success());
+    }
+    let operations = &operations[operations.len().saturating_sub(limit)..];
+    if output::mode().is_machine() {
+        output::emit(Event::result(
+            "log",
+            serde_json::json!({ "dir": log::log_dir(), "operations": operations }),
+        ));
+        return Ok(());
+    }
+    if operations.is_empty() {
+        say!("no operations logged in {}", log::log_dir().display());
+    }
+    for operation in operations {
+        say!("{}", operation);
+    }
+    Ok(())
+}
+
+/// Run the plan in `path`, read from standard input for `-`
+fn apply(path: &str) -> Result<(), DiskImageError> {
+    let json = if path == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path)?
+    };
+    execute(&Plan::parse(&json)?)
+}
+
+/// Record the current checkout as the first variant and install the hook
+fn install_hooks(afdirs: &[String], by: VariantBy) -> Result<(), DiskImageError> {
+    if afdirs.is_empty() {
+        return Err(DiskImageError::InvalidPath(
+            "no artifact directories given or configured".to_string(),
+        ));
+    }
+    let mut registry = Registry::load()?;
+    // The hook runs without the user's PATH setup, e.g. from a GUI git client
+    let program = std::env::current_exe()?;
+    let switch = format!("{} switch", hook::quote(&program.display().to_string()));
+    // One hook per repository, worktrees share theirs
+    let mut commands: BTreeMap<PathBuf, (PathBuf, String)> = BTreeMap::new();
+
+    for afdir in afdirs {
+        let afdir_abs = registry::absolute(afdir)?;
+        let entry = registry.get_mut(&afdir_abs).ok_or_else(|| {
+            DiskImageError::InvalidPath(format!("{} is not managed by afpack", afdir))
+        })?;
+
+        let current = Variant::current(&afdir_abs, by)?;
+        entry.variant_by = Some(by);
+        if entry.variant.is_none() {
+            entry.variants.insert(
+                current.key.clone(),
+                VariantRecord {
+                    label: current.label,
+                    image: entry.image.clone(),
+                    compressed: entry.compressed,
+                },
+            );
+            entry.variant = Some(current.key);
+        }
+
+        // Hooks run from the worktree root
+        let toplevel = git::toplevel(&afdir_abs)?;
+        let relative = afdir_abs.strip_prefix(&toplevel).unwrap_or(&afdir_abs);
+        let (_, command) = commands
+            .entry(git::hooks_dir(&afdir_abs)?)
+            .or_insert_with(|| (afdir_abs.clone(), switch.clone()));
+        command.push(' ');
+        command.push_str(&hook::quote(&relative.display().to_string()));
+    }
+
+    if is_dry_run() {
+        for (hooks, (_, command)) in &commands {
+            say!(
+                "[DRY RUN] Would install post-checkout hook in {}: {}",
+                hooks.display(),
+                command
+            );
+        }
+        return Ok(());
+    }
+    for (afdir, command) in commands.values() {
+        let hook = git::install_post_checkout_hook(afdir, command)?;
+        say!("installed {}", hook.display());
+    }
+    registry.save()?;
+    Ok(())
+}
+
+/// Detach the current variant and attach the one matching the checkout
+fn switch_variant(afdir: &str) -> Result<(), DiskImageError> {
+    let registry = Registry::load()?;
+    let afdir_abs = registry::absolute(afdir)?;
+    let entry = registry.get(&afdir_abs).ok_or_else(|| {
+        DiskImageError::InvalidPath(format!("{} is not managed by afpack", afdir))
+    })?;
+    let by = entry.variant_by.unwrap_or(VariantBy::Branch);
+
+    let current = Variant::current(&afdir_abs, by)?;
+    if entry.attached && entry.variant.as_deref() == Some(current.key.as_str()) {
+        debug!("{} already on {}", afdir, current.label);
+        return Ok(());
+    }
+
+    let target: PathBuf = entry
+        .variants
+        .get(&current.key)
+        .map(|v| v.image.clone())
+        .unwrap_or_else(|| variant::image_path(&afdir_abs, &current.key));
+
+    let mut plan = Plan::new();
+    if let Some(source) = &entry.passphrase_from {
+        plan.push(Step::Passphrase {
+            afdir: afdir_abs.clone(),
+            source: source.clone(),
+            provision: false,
+        });
+    }
+    let mut outgoing_compressed = entry.compressed;
+    if entry.attached {
+        let detach = Plan::detach(entry, &DetachOptions::new());
+        // What the compression achieved is recorded by the plan as it runs
+        outgoing_compressed |= detach
+            .steps
+            .iter()
+            .any(|step| matches!(step, Step::Compress { .. }));
+        plan.extend(detach);
+    }
+
+    let is_compressed = |image: &Path| {
+        entry
+            .variants
+            .values()
+            .any(|v| v.image == image && v.compressed)
+    };
+    let mut target_compressed = is_compressed(&target);
+    if !target.exists() {
+        match variant::nearest_ancestor(&afdir_abs, by, &entry.variants)? {
+            Some(ancestor) => {
+                // The clone is as compressed as the ancestor
+                target_compressed = is_compressed(&ancestor);
+                plan.push(Step::Clone {
+                    source: ancestor,
+                    destination: target.clone(),
+                });
+            }
+            None => {
+                debug!("no ancestor image, creating blank image");
+                plan.push(Step::CreateBlank {
+                    image: target.clone(),
+                    size: entry.maxsize.clone(),
+                    filesystem: entry.filesystem.clone().unwrap_or_default(),
+                    format: entry.format.clone().unwrap_or_default(),
+                    volume_name: None,
+                    owners: false,
+                    encryption: entry.encryption,
+                });
+            }
+        }
+    }
+    if target_compressed && entry.compression.decompress_on_attach {
+        plan.push(Step::Decompress {
+            image: target.clone(),
+        });
+    }
+    plan.push(Step::Attach {
+        image: target.clone(),
+        mount_point: afdir_abs.clone(),
+        shadow: None,
+        encrypted: entry.passphrase_from.is_some(),
+    });
 
-    let mut compressor = FileCompressor::new();
-    compressor.recursive_compress(
-        std::iter::once(Path::new(path)),
-        compression_kind,
-        1.0,
-        2,
-        &NoProgress,
-        true,
+    let mut next = entry.clone();
+    // The outgoing image keeps its state on its variant record
+    if let Some(record) = next.variants.values_mut().find(|v| v.image == entry.image) {
+        record.compressed = outgoing_compressed;
+    }
+    next.image = target.clone();
+    next.variant = Some(current.key.clone());
+    next.variants.insert(
+        current.key,
+        VariantRecord {
+            label: current.label.clone(),
+            image: target,
+            compressed: false,
+        },
     );
+    plan.push(Step::Register { entry: next });
+    execute(&plan)?;
+    if !is_dry_run() {
+        say!("{} -> {}", afdir, current.label);
+    }
+    Ok(())
 }
 
-fn create_asif_image(
-    afdir: &str,
-    asif_path: &str,
-    maxsize: &str,
-    compress: &str,
-) -> Result<(), DiskImageError> {
-    let dry_run = is_dry_run();
+/// Attach the shared base image for the current lockfile over a private shadow
+fn worktree_attach(afdir: &str, maxsize: &str, settings: &Settings) -> Result<(), DiskImageError> {
+    if settings.encryption.is_some() {
+        return Err(DiskImageError::Config(
+            "encrypted images cannot be shared between worktrees".to_string(),
+        ));
+    }
+    let registry = Registry::load()?;
+    let afdir_abs = registry::absolute(afdir)?;
 
-    // Check if source directory exists
-    if !Path::new(afdir).exists() {
-        // Create blank disk image with size if directory doesn't exist
-        vlog("resizing blank image");
+    let key = Variant::current(&afdir_abs, VariantBy::Lockfile)?.key;
+    let base = worktree::base_image_path(&afdir_abs, &key)?;
+    let shadow = worktree::shadow_path(&afdir_abs, &key)?;
 
-        let create_options = diskimage::CreateBlankOptions::new(
+    let mut plan = Plan::new();
+    if let Some(entry) = registry.get(&afdir_abs) {
+        if entry.attached && entry.shadow.as_ref() == Some(&shadow) {
+            debug!("{} already attached", afdir);
+            return Ok(());
+        }
+        if entry.attached {
+            plan.push(Step::detach_entry(&afdir_abs, &DetachOptions::new()));
+        }
+    }
+    if !base.exists() {
+        plan.extend(Plan::create_image(
+            &afdir_abs,
+            afdir_abs.exists(),
+            &base,
             maxsize,
-            diskimage::FileSystem::APFS,
-            diskimage::Format::ASIF,
+            settings,
+        ));
+    }
+    if afdir_abs.exists() {
+        plan.push(Step::Dispose {
+            path: afdir_abs.clone(),
+            disposal: Disposal::Trash,
+        });
+    }
+    plan.push(Step::Attach {
+        image: base.clone(),
+        mount_point: afdir_abs.clone(),
+        shadow: Some(shadow.clone()),
+        encrypted: false,
+    });
+    let mut entry = Entry::new(&afdir_abs, &base, maxsize);
+    entry.shadow = Some(shadow);
+    entry.detach_after = settings.detach_after;
+    plan.push(Step::Register { entry });
+    execute(&plan)?;
+    if !is_dry_run() {
+        say!("{} -> {} (shadowed)", afdir, base.display());
+    }
+    Ok(())
+}
+
+fn worktree_list() -> Result<(), DiskImageError> {
+    let registry = Registry::load()?;
+    if output::mode().is_machine() {
+        let shadows: Vec<serde_json::Value> = registry
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let shadow = entry.shadow.as_ref()?;
+                Some(serde_json::json!({
+                    "afdir": entry.afdir,
+                    "base": entry.image,
+                    "shadow": shadow,
+                    "size": std::fs::metadata(shadow).map(|m| m.len()).unwrap_or(0),
+                    "attached": entry.attached,
+                    "worktree_exists": worktree::worktree_exists(entry),
+                }))
+            })
+            .collect();
+        let stale = worktree::stale_shadows(&registry.entries);
+        output::emit(Event::result(
+            "worktree",
+            serde_json::json!({ "shadows": shadows, "stale": stale }),
+        ));
+        return Ok(());
+    }
+    for entry in &registry.entries {
+        let Some(shadow) = &entry.shadow else {
+            continue;
+        };
+        let size = std::fs::metadata(shadow).map(|m| m.len()).unwrap_or(0);
+        let state = if !worktree::worktree_exists(entry) {
+            "worktree removed"
+        } else if entry.attached {
+            "attached"
+        } else {
+            "detached"
+        };
+        say!("{} [{}]", entry.afdir.display(), state);
+        say!("    base:   {}", entry.image.display());
+        say!(
+            "    shadow: {} ({})",
+            shadow.display(),
+            diskimage::format_size(size)
+        );
+    }
+    for stale in worktree::stale_shadows(&registry.entries) {
+        say!("stale shadow: {}", stale.display());
+    }
+    Ok(())
+}
+
+fn worktree_clean(all: bool) -> Result<(), DiskImageError> {
+    let dry_run = is_dry_run();
+    let mut registry = Registry::load()?;
+    let mut remove = worktree::stale_shadows(&registry.entries);
+
+    let gone: Vec<PathBuf> = registry
+        .entries
+        .iter()
+        .filter(|e| e.shadow.is_some() && !worktree::worktree_exists(e))
+        .map(|e| e.afdir.clone())
+        .collect();
+
+    // Shadows of live worktrees go too, a failed detach keeps the rest as they are
+    let mut result = Ok(());
+    if all {
+        for entry in registry.entries.iter_mut() {
+            let Some(shadow) = entry.shadow.clone() else {
+                continue;
+            };
+            if !worktree::worktree_exists(entry) {
+                continue;
+            }
+            if let Err(e) = detach_entry(entry, &DetachOptions::new()) {
+                result = Err(e);
+                break;
+            }
+            entry.shadow = None;
+            remove.push(shadow);
+        }
+    }
+
+    for shadow in remove.iter().filter(|s| s.exists()) {
+        if dry_run {
+            say!("[DRY RUN] Would remove {}", shadow.display());
+        } else {
+            // hdiutil shadows are files, overlay shadows on Linux directories
+            if shadow.is_dir() {
+                std::fs::remove_dir_all(shadow)?;
+            } else {
+                std::fs::remove_file(shadow)?;
+            }
+            say!("removed {}", shadow.display());
+        }
+    }
+    if dry_run {
+        return result;
+    }
+    for afdir in gone {
+        registry.remove(&afdir);
+    }
+    registry.save()?;
+    result
+}
+
+/// Registry entry of a managed artifact directory
+fn managed_entry<'a>(
+    registry: &'a mut Registry,
+    afdir: &str,
+) -> Result<&'a mut Entry, DiskImageError> {
+    let afdir_abs = registry::absolute(afdir)?;
+    registry
+        .get_mut(&afdir_abs)
+        .ok_or_else(|| DiskImageError::InvalidPath(format!("{} is not managed by afpack", afdir)))
+}
+
+/// Detach an entry's image if attached, returning whether it was
+///
+/// The plan records it as detached in the registry file, `entry` is updated to match
+/// for callers saving their copy of the registry.
+fn detach_entry(entry: &mut Entry, options: &DetachOptions) -> Result<bool, DiskImageError> {
+    if !entry.attached {
+        return Ok(false);
+    }
+    execute(&Plan {
+        steps: vec![Step::detach_entry(&entry.afdir, options)],
+    })?;
+    if !is_dry_run() {
+        entry.attached = false;
+    }
+    Ok(true)
+}
+
+/// Attach an entry's image (and shadow) at its artifact directory
+fn attach_entry(entry: &mut Entry) -> Result<(), DiskImageError> {
+    execute(&Plan::attach(entry))?;
+    if !is_dry_run() {
+        entry.mark_attached(activity::now());
+    }
+    Ok(())
+}
+
+/// Run `step` on the image of `entry` while it is detached, then attach it again
+///
+/// The image is reattached even if `step` fails, unless it could not be detached.
+fn while_detached(entry: &Entry, step: Step) -> Result<(), DiskImageError> {
+    let mut plan = Plan::new();
+    let mut reattach = Plan::new();
+    if entry.attached {
+        plan.push(Step::detach_entry(&entry.afdir, &DetachOptions::new()));
+        reattach = Plan::attach(entry);
+        reattach.push(Step::Register {
+            entry: entry.clone(),
+        });
+    }
+    plan.push(step);
+    if is_dry_run() {
+        plan.extend(reattach);
+        return execute(&plan);
+    }
+    let result = execute(&plan);
+    if !reattach.is_empty() && !diskimage::is_mount_point(&entry.afdir) {
+        execute(&reattach)?;
+    }
+    result
+}
+
+/// Reattach images detached for being idle whose project contains `cwd`
+///
+/// An image that fails is reported and skipped, the others are still reattached.
+fn reattach_idle(cwd: &Path) -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let mut changed = false;
+    let mut failed = Vec::new();
+    for entry in &mut registry.entries {
+        if !entry.idle_detached || entry.attached || !hook::in_project(entry, cwd) {
+            continue;
+        }
+        debug!("reattaching idle {}", entry.afdir.display());
+        match attach_entry(entry) {
+            Ok(()) => changed = true,
+            Err(e) => {
+                warn!("{}: {}", entry.afdir.display(), e);
+                failed.push(e.kind());
+            }
+        }
+    }
+    if changed && !is_dry_run() {
+        registry.save()?;
+    }
+    if let Some(&kind) = failed.first() {
+        return Err(DiskImageError::Incomplete {
+            failed: failed.len(),
+            action: "reattached",
+            kind,
+        });
+    }
+    Ok(())
+}
+
+fn snapshot_create(afdir: &str, label: Option<&str>, keep: usize) -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let entry = managed_entry(&mut registry, afdir)?;
+    if entry.shadow.is_some() {
+        return Err(DiskImageError::InvalidPath(format!(
+            "{} is a shadowed worktree image, snapshot the base instead",
+            afdir
+        )));
+    }
+    // Detached so the clone is taken from a consistent image
+    while_detached(
+        entry,
+        Step::Snapshot {
+            image: entry.image.clone(),
+            label: label.map(str::to_string),
+            keep,
+        },
+    )
+}
+
+fn snapshot_list(afdir: &str) -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let entry = managed_entry(&mut registry, afdir)?;
+    let store = SnapshotStore::open(&entry.image)?;
+    if output::mode().is_machine() {
+        output::emit(Event::result("snapshot", &store.snapshots));
+        return Ok(());
+    }
+    for snapshot in &store.snapshots {
+        let size = std::fs::metadata(store.path(snapshot))
+            .map(|m| m.len())
+            .unwrap_or(0);
+        say!(
+            "{}  {}  {:>10}  {}",
+            snapshot.id,
+            snapshot::format_timestamp(snapshot.created),
+            diskimage::format_size(size),
+            snapshot.label.as_deref().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+fn rollback(afdir: &str, query: Option<&str>) -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let entry = managed_entry(&mut registry, afdir)?;
+    let store = SnapshotStore::open(&entry.image)?;
+    let snapshot = store.find(query).cloned().ok_or_else(|| {
+        DiskImageError::InvalidPath(format!(
+            "no snapshot {} for {}",
+            query.unwrap_or("(latest)"),
+            afdir
+        ))
+    })?;
+    while_detached(
+        entry,
+        Step::Restore {
+            image: entry.image.clone(),
+            snapshot: snapshot.id.clone(),
+        },
+    )?;
+    if !is_dry_run() {
+        say!("{} rolled back to {}", afdir, snapshot.id);
+    }
+    Ok(())
+}
+
+/// Resolve a diff argument to a directory or image file, plus the afdir it belongs to
+///
+/// Images, variant images and snapshots are matched against the registry, so
+/// the afdir is known even for a raw snapshot path.
+fn resolve_diff_side(arg: &str, registry: &Registry) -> Result<(PathBuf, PathBuf), DiskImageError> {
+    let path = PathBuf::from(arg);
+    if path.exists() {
+        let absolute = registry::absolute(&path)?;
+        let owner = registry.entries.iter().find(|entry| {
+            let mut images = std::iter::once(&entry.image)
+                .chain(entry.variants.values().map(|variant| &variant.image));
+            images.any(|image| {
+                absolute == *image
+                    || absolute.parent() == Some(SnapshotStore::dir_for(image).as_path())
+            }) || absolute == entry.afdir
+        });
+        let afdir = match owner {
+            Some(entry) => entry.afdir.clone(),
+            None => {
+                // node_modules@main.asif -> node_modules
+                let stem = path
+                    .file_stem()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                PathBuf::from(stem.split('@').next().unwrap_or_default())
+            }
+        };
+        return Ok((path, afdir));
+    }
+
+    let (afdir, query) = arg
+        .rsplit_once('@')
+        .ok_or_else(|| DiskImageError::InvalidPath(arg.to_string()))?;
+    let entry = registry.get(registry::absolute(afdir)?).ok_or_else(|| {
+        DiskImageError::InvalidPath(format!("{} is not managed by afpack", afdir))
+    })?;
+    let store = SnapshotStore::open(&entry.image)?;
+    let snapshot = store.find(Some(query)).ok_or_else(|| {
+        DiskImageError::InvalidPath(format!("no snapshot {} for {}", query, afdir))
+    })?;
+    Ok((store.path(snapshot), entry.afdir.clone()))
+}
+
+fn diff_images(a: &str, b: &str) -> Result<(), DiskImageError> {
+    let registry = Registry::load()?;
+    let sides = [
+        resolve_diff_side(a, &registry)?,
+        resolve_diff_side(b, &registry)?,
+    ];
+    let ecosystem = sides
+        .iter()
+        .map(|(_, afdir)| Ecosystem::detect(afdir))
+        .find(|ecosystem| *ecosystem != Ecosystem::Unknown)
+        .unwrap_or(Ecosystem::Unknown);
+
+    let mut roots = Vec::new();
+    let mut mounted = Vec::new();
+    let mut result = Ok(());
+    for (i, (path, _)) in sides.iter().enumerate() {
+        if path.is_dir() {
+            roots.push(path.clone());
+            continue;
+        }
+        let mount_point =
+            std::env::temp_dir().join(format!("afpack-diff-{}-{}", std::process::id(), i));
+        result = DiskImage::attach(
+            path,
+            AttachOptions::new()
+                .readonly()
+                .nobrowse()
+                .with_dry_run(is_dry_run())
+                .with_mount_point(mount_point.display().to_string()),
         )
-        .with_dry_run(dry_run)
-        .with_verbose(*VERBOSE.get().unwrap_or(&false));
-        DiskImage::create_blank(asif_path, create_options)?;
+        .map(|_| ());
+        if result.is_err() {
+            break;
+        }
+        mounted.push(mount_point.clone());
+        roots.push(mount_point);
+    }
+
+    let report = match result {
+        Ok(()) if is_dry_run() => None,
+        Ok(()) => Some(diff::diff_trees(&roots[0], &roots[1], ecosystem)),
+        Err(e) => Some(Err(e)),
+    };
+
+    for mount_point in &mounted {
+        if is_dry_run() {
+            say!(
+                "[DRY RUN] Would execute: diskutil unmount {}",
+                mount_point.display()
+            );
+            continue;
+        }
+        if let Err(e) = DiskImage::detach(mount_point, DetachOptions::new()) {
+            warn!("could not detach {}: {}", mount_point.display(), e);
+        }
+        let _ = std::fs::remove_dir(mount_point);
+    }
+
+    let Some(report) = report else {
+        return Ok(());
+    };
+    let report = report?;
+    if output::mode().is_machine() {
+        output::emit(Event::result("diff", &report));
     } else {
-        // Create disk image from existing directory
-        let create_options = CreateFromOptions::new(diskimage::Format::ASIF)
-            .with_dry_run(dry_run)
-            .with_verbose(*VERBOSE.get().unwrap_or(&false));
-        vlog("creating disk image from existing directory");
-        DiskImage::create_from(afdir, asif_path, create_options)?;
-
-        if compress != "none" {
-            vlog("Applying compression");
-            apply_compression(compress, afdir);
-        }
-        // sleep 3 sec:
-        std::thread::sleep(std::time::Duration::from_secs(3));
-        // Only resize when creating from existing directory
-        let resize_options = ResizeOptions::new(maxsize)
-            .with_dry_run(dry_run)
-            .with_verbose(*VERBOSE.get().unwrap_or(&false));
-        vlog("resizing disk image");
-        DiskImage::resize(asif_path, resize_options)?;
+        print!("{}", report.render());
+        if report.is_empty() {
+            say!("no differences");
+        }
+    }
+    Ok(())
+}
+
+fn estimate_compression(
+    path: &str,
+    target: Target,
+    options: &EstimateOptions,
+) -> Result<(), DiskImageError> {
+    let path = compression_path(path, target)?;
+    debug!(
+        "sampling {} blocks from {}",
+        options.samples,
+        path.display()
+    );
+    let samples = Samples::read(&path, options)?;
+    let estimates = estimate::estimate_all(&samples, options);
+
+    if output::mode().is_machine() {
+        output::emit(Event::result("estimate", &estimates));
+        return Ok(());
+    }
+    let usage = compression::disk_usage(&path);
+    say!(
+        "{}: {} logical, {} allocated, sampled {}",
+        path.display(),
+        diskimage::format_size(usage.logical),
+        diskimage::format_size(usage.allocated),
+        diskimage::format_size(samples.sampled_size())
+    );
+    say!(
+        "{:<10}{:>12}{:>10}{:>12}",
+        "ALGORITHM",
+        "SIZE",
+        "SAVINGS",
+        "TIME"
+    );
+    for estimate in &estimates {
+        say!(
+            "{:<10}{:>12}{:>9.0}%{:>11.1}s",
+            estimate.algorithm,
+            diskimage::format_size(estimate.predicted_size),
+            estimate.savings * 100.0,
+            estimate.predicted_time.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// The image of a managed afdir unless the volume is asked for, otherwise `path` itself
+fn compression_path(path: &str, target: Target) -> Result<PathBuf, DiskImageError> {
+    let registry = Registry::load()?;
+    Ok(match registry.get(registry::absolute(path)?) {
+        Some(entry) if target == Target::Image => entry.image.clone(),
+        _ => PathBuf::from(path),
+    })
+}
+
+fn decompress(path: &str, target: Target) -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let path = compression_path(path, target)?;
+    if is_dry_run() {
+        say!("[DRY RUN] Would decompress {}", path.display());
+        return Ok(());
+    }
+
+    debug!("decompressing {}", path.display());
+    let progress =
+        ProgressReporter::new(std::iter::once(path.as_path())).with_operation("decompress");
+    let stats = compression::decompress(std::iter::once(path.as_path()), &progress);
+    progress.finish();
+    let usage = compression::disk_usage(&path);
+    say!(
+        "decompressed {} files, {}: {} logical, {} allocated",
+        stats.files,
+        path.display(),
+        diskimage::format_size(usage.logical),
+        diskimage::format_size(usage.allocated)
+    );
+
+    let image = registry::absolute(&path)?;
+    let mut changed = false;
+    for entry in &mut registry.entries {
+        if entry.image == image {
+            entry.compressed = false;
+            changed = true;
+        }
+        for record in entry.variants.values_mut().filter(|v| v.image == image) {
+            record.compressed = false;
+            changed = true;
+        }
+    }
+    if changed {
+        registry.save()?;
+    }
+    Ok(())
+}
+
+/// Compression state for display, "unknown" where the filesystem cannot tell
+fn describe_compression(path: &Path) -> String {
+    CompressionState::inspect(path)
+        .map(|state| state.to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn list_entries() -> Result<(), DiskImageError> {
+    let registry = Registry::load()?;
+    if output::mode().is_machine() {
+        output::emit(Event::result("list", &registry.entries));
+        return Ok(());
+    }
+    if registry.entries.is_empty() {
+        say!("no managed artifact directories");
+        return Ok(());
+    }
+    for entry in &registry.entries {
+        say!(
+            "{}  {}  {}  {}",
+            entry.afdir.display(),
+            if entry.attached {
+                "attached"
+            } else {
+                "detached"
+            },
+            entry.image.display(),
+            describe_compression(&entry.image)
+        );
+    }
+    Ok(())
+}
+
+fn status(afdir: &str) -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let entry = managed_entry(&mut registry, afdir)?;
+    let usage = compression::disk_usage(&entry.image);
+    if output::mode().is_machine() {
+        output::emit(Event::result(
+            "status",
+            serde_json::json!({
+                "entry": entry,
+                "mounted": diskimage::is_mount_point(&entry.afdir),
+                "usage": usage,
+                "compression": describe_compression(&entry.image),
+            }),
+        ));
+        return Ok(());
+    }
+    say!(
+        "{} [{}]",
+        entry.afdir.display(),
+        if entry.attached {
+            "attached"
+        } else {
+            "detached"
+        }
+    );
+    say!("    image:       {}", entry.image.display());
+    say!(
+        "    size:        {} logical, {} allocated (max {})",
+        diskimage::format_size(usage.logical),
+        diskimage::format_size(usage.allocated),
+        entry.maxsize
+    );
+    say!("    compression: {}", describe_compression(&entry.image));
+    if let (Some(by), Some(variant)) = (entry.variant_by, &entry.variant) {
+        say!("    variant:     {} ({})", variant, by);
+    }
+    if let Some(shadow) = &entry.shadow {
+        say!("    shadow:      {}", shadow.display());
+    }
+    Ok(())
+}
+
+fn detach(afdir: &str, options: &DetachOptions) -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let entry = managed_entry(&mut registry, afdir)?;
+    if !entry.attached {
+        say!("{} is not attached", afdir);
+        return Ok(());
+    }
+    execute(&Plan::detach(entry, options))
+}
+
+/// Reattach `afdirs`, or with `all` every entry marked attached, unless already mounted
+fn remount(afdirs: &[String], all: bool) -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let wanted = afdirs
+        .iter()
+        .map(|afdir| {
+            let afdir_abs = registry::absolute(afdir)?;
+            match registry.get(&afdir_abs) {
+                Some(_) => Ok(afdir_abs),
+                None => Err(DiskImageError::InvalidPath(format!(
+                    "{} is not managed by afpack",
+                    afdir
+                ))),
+            }
+        })
+        .collect::<Result<Vec<PathBuf>, DiskImageError>>()?;
+
+    let mut failed = Vec::new();
+    for entry in &mut registry.entries {
+        let selected = if all {
+            entry.attached
+        } else {
+            wanted.contains(&entry.afdir)
+        };
+        if !selected || diskimage::is_mount_point(&entry.afdir) {
+            continue;
+        }
+        // The project may live on a volume that is not mounted yet
+        if !entry.image.exists() {
+            warn!(
+                "{}: image {} is missing",
+                entry.afdir.display(),
+                entry.image.display()
+            );
+            failed.push(ErrorKind::NotFound);
+            continue;
+        }
+        match attach_entry(entry) {
+            Ok(()) => say!("{} -> {}", entry.afdir.display(), entry.image.display()),
+            Err(e) => {
+                warn!("{}: {}", entry.afdir.display(), e);
+                failed.push(e.kind());
+            }
+        }
+    }
+    if !is_dry_run() {
+        registry.save()?;
+    }
+    if let Some(&kind) = failed.first() {
+        return Err(DiskImageError::Incomplete {
+            failed: failed.len(),
+            action: "remounted",
+            kind,
+        });
+    }
+    Ok(())
+}
+
+/// Images attached by `afpack exec`, detached again when dropped
+struct ScopedAttach {
+    /// Directories that were not mounted before
+    afdirs: Vec<PathBuf>,
+    compact: bool,
+    /// Directory the detached images are copied into
+    save_to: Option<PathBuf>,
+}
+
+impl ScopedAttach {
+    /// Attach the images of `afdirs` that are not mounted
+    fn attach(
+        afdirs: &[String],
+        compact: bool,
+        save_to: Option<PathBuf>,
+    ) -> Result<Self, DiskImageError> {
+        let mut registry = Registry::load()?;
+        let mut scope = Self {
+            afdirs: Vec::new(),
+            compact,
+            save_to,
+        };
+        for afdir in afdirs {
+            let entry = managed_entry(&mut registry, afdir)?;
+            if diskimage::is_mount_point(&entry.afdir) {
+                debug!("{} is already attached", entry.afdir.display());
+                continue;
+            }
+            attach_entry(entry)?;
+            if !is_dry_run() {
+                scope.afdirs.push(entry.afdir.clone());
+                // Saved right away so an error below still detaches it
+                registry.save()?;
+            }
+        }
+        Ok(scope)
+    }
+
+    /// Leave the images attached
+    fn keep(&mut self) {
+        self.afdirs.clear();
+    }
+
+    fn detach(&self) -> Result<(), DiskImageError> {
+        let registry = Registry::load()?;
+        for afdir in &self.afdirs {
+            let Some(entry) = registry.get(afdir) else {
+                continue;
+            };
+            let mut plan = Plan::detach(entry, &DetachOptions::new());
+            if self.compact && !entry.compressed && !entry.compression.runs_on(Schedule::OnDetach) {
+                let policy = if entry.compression.is_enabled() {
+                    entry.compression.clone()
+                } else {
+                    CompressionPolicy::new(Algorithm::Lzfse)
+                };
+                plan.push(Step::Compress {
+                    path: entry.image.clone(),
+                    policy,
+                });
+            }
+            if let (Some(dir), Some(name)) = (&self.save_to, entry.image.file_name()) {
+                // ditto creates the directory
+                plan.push(Step::Copy {
+                    source: entry.image.clone(),
+                    destination: dir.join(name),
+                });
+            }
+            if let Err(e) = execute(&plan) {
+                warn!("{}: {}", afdir.display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ScopedAttach {
+    fn drop(&mut self) {
+        if self.afdirs.is_empty() {
+            return;
+        }
+        if let Err(e) = self.detach() {
+            warn!("could not detach images: {}", e);
+        }
+    }
+}
+
+/// Run `command` with the images of `afdirs` attached, returning its exit status
+fn exec(
+    afdirs: &[String],
+    command: &[String],
+    keep_attached: bool,
+    compact: bool,
+    save_to: Option<PathBuf>,
+) -> Result<ExitStatus, DiskImageError> {
+    // Installed before attaching, so a signal during the attach still lets
+    // the attached images be detached
+    let mut signals = Signals::new([SIGINT, SIGQUIT, SIGTERM, SIGHUP])?;
+    let mut scope = ScopedAttach::attach(afdirs, compact, save_to)?;
+    if keep_attached {
+        scope.keep();
+    }
+    if is_dry_run() {
+        say!("[DRY RUN] Would run: {}", command.join(" "));
+        return Ok(ExitStatus::default());
+    }
+    if let Some(signal) = signals.pending().next() {
+        debug!(
+            "got signal {} while attaching, not running the command",
+            signal
+        );
+        return Ok(ExitStatus::from_raw(signal));
+    }
+    debug!("running {}", command.join(" "));
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .spawn()
+        .map_err(|error| DiskImageError::Spawn {
+            program: command[0].clone(),
+            error,
+        })?;
+    let forwarding = forward_signals(signals, child.id());
+    let status = child.wait()?;
+    forwarding.close();
+    Ok(status)
+}
+
+/// Forward termination signals caught by `signals` to the child `pid`
+///
+/// Keeping the handlers installed also lets afpack outlive the signal and
+/// detach the images. Signals typed at the terminal already reach the child
+/// when afpack runs in the foreground, so SIGINT and SIGQUIT are not sent twice.
+fn forward_signals(mut signals: Signals, pid: u32) -> signal_hook::iterator::Handle {
+    let handle = signals.handle();
+    // SAFETY: plain libc queries without pointers
+    let foreground = unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() };
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if foreground && matches!(signal, SIGINT | SIGQUIT) {
+                continue;
+            }
+            // SAFETY: kill takes no pointers, a stale pid only makes it fail
+            unsafe {
+                libc::kill(pid as libc::pid_t, signal);
+            }
+        }
+    });
+    handle
+}
+
+/// Exit with the same status as a finished child process
+fn exit_with(status: ExitStatus) -> ! {
+    let error = format!("command {}", status);
+    log::finish(
+        status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or(libc::SIGTERM)),
+        (!status.success()).then_some(error.as_str()),
+    );
+    if let Some(code) = status.code() {
+        exit(code);
+    }
+    // Killed by a signal: die the same way once the images are detached
+    let signal = status.signal().unwrap_or(libc::SIGTERM);
+    let _ = signal_hook::low_level::emulate_default_handler(signal);
+    exit(128 + signal);
+}
+
+/// Attach the current project's pending images, printing the prompt segment if asked
+fn hook_exec(prompt: bool) -> Result<(), DiskImageError> {
+    let cwd = std::env::current_dir()?;
+    let mut registry = Registry::load()?;
+    let mut status = HookStatus::check(&registry, &cwd, |afdir| diskimage::is_mount_point(afdir));
+    if !status.pending.is_empty() {
+        for afdir in std::mem::take(&mut status.pending) {
+            let Some(entry) = registry.get_mut(&afdir) else {
+                continue;
+            };
+            // Reading stdin would hang the prompt
+            if entry.passphrase_from == Some(PassphraseSource::Stdin) {
+                eprintln!(
+                    "afpack: run `afpack remount {}` to attach it",
+                    afdir.display()
+                );
+                continue;
+            }
+            match attach_entry(entry) {
+                Ok(()) => status.attached += 1,
+                Err(e) => eprintln!("afpack: attaching {}: {}", afdir.display(), e),
+            }
+        }
+        if !is_dry_run() {
+            registry.save()?;
+        }
+    }
+    if prompt {
+        print!("{}", status.segment());
+    }
+    Ok(())
+}
+
+/// Attach or detach `afdirs` through afpackd, with `all` every vanished volume
+fn daemon_call(afdirs: &[String], all: bool, attach: bool) -> Result<(), DiskImageError> {
+    let mut client = Client::connect(&daemon::socket_path())?;
+    let mut targets = afdirs
+        .iter()
+        .map(registry::absolute)
+        .collect::<Result<Vec<PathBuf>, DiskImageError>>()?;
+    if all {
+        targets.extend(
+            client
+                .status(None)?
+                .into_iter()
+                .filter(|s| s.attached && !s.mounted)
+                .map(|s| s.afdir),
+        );
+    }
+    for afdir in &targets {
+        let action = if attach { "attach" } else { "detach" };
+        if is_dry_run() {
+            say!(
+                "[DRY RUN] Would ask afpackd to {} {}",
+                action,
+                afdir.display()
+            );
+            continue;
+        }
+        if attach {
+            client.attach(afdir)?;
+        } else {
+            client.detach(afdir)?;
+        }
+        debug!("afpackd: {} {}", action, afdir.display());
+    }
+    Ok(())
+}
+
+fn daemon_list() -> Result<(), DiskImageError> {
+    let mut client = Client::connect(&daemon::socket_path())?;
+    let statuses = client.status(None)?;
+    if output::mode().is_machine() {
+        output::emit(Event::result("list", &statuses));
+        return Ok(());
+    }
+    for status in statuses {
+        let state = match (status.attached, status.mounted) {
+            (true, true) => "attached",
+            (true, false) => "vanished",
+            (false, true) => "mounted, not recorded",
+            (false, false) => "detached",
+        };
+        say!(
+            "{}  {}  {}",
+            status.afdir.display(),
+            state,
+            status.image.display()
+        );
+    }
+    Ok(())
+}
+
+fn service(command: ServiceCommands) -> Result<(), DiskImageError> {
+    for service in login_services()? {
+        match command {
+            ServiceCommands::Install => {
+                service.install(is_dry_run())?;
+                if !is_dry_run() {
+                    say!("installed {}", service.path().display());
+                }
+            }
+            ServiceCommands::Uninstall => service.uninstall(is_dry_run())?,
+            ServiceCommands::Status => {
+                say!("{}: {}", service.name(), service.status()?);
+                say!("  definition: {}", service.path().display());
+                say!("  log: {}", service.log());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Detach idle images and compress detached images whose idle compression is due
+///
+/// An image that fails is reported and skipped, the others are still handled.
+fn maintain() -> Result<(), DiskImageError> {
+    let mut registry = Registry::load()?;
+    let now = activity::now();
+    let mut failed = Vec::new();
+    let mut idle = Vec::new();
+    for entry in &mut registry.entries {
+        if !entry.attached || entry.detach_after.is_none() {
+            continue;
+        }
+        match activity::processes_using(&entry.afdir) {
+            Ok(processes) if entry.idle_detach_due(!processes.is_empty(), now) => {
+                idle.push(entry.clone());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("{}: {}", entry.afdir.display(), e);
+                failed.push(e.kind());
+            }
+        }
+    }
+    // Records when each image was last seen in use
+    if !is_dry_run() {
+        registry.save()?;
+    }
+    for entry in &idle {
+        debug!("{} is idle, detaching", entry.afdir.display());
+        if let Err(e) = execute(&Plan::idle_detach(entry)) {
+            warn!("{}: {}", entry.afdir.display(), e);
+            failed.push(e.kind());
+        }
+    }
+
+    for entry in &Registry::load()?.entries {
+        let due = entry.compression.runs_on(Schedule::Idle)
+            && !entry.attached
+            && !entry.compressed
+            && entry.compression.is_idle(&entry.image);
+        if due {
+            debug!("{} has been idle", entry.image.display());
+            apply_compression(&entry.compression, &entry.image);
+        }
+    }
+    if let Some(&kind) = failed.first() {
+        return Err(DiskImageError::Incomplete {
+            failed: failed.len(),
+            action: "checked or detached",
+            kind,
+        });
     }
     Ok(())
 }
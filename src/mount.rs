@@ -0,0 +1,343 @@
+use crate::diskimage::{
+    AttachOptions, CommandRunner, DiskImage, DiskImageError, Result, SystemRunner,
+};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Size and free space of a mounted volume, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct VolumeUsage {
+    pub total: u64,
+    pub used: u64,
+    pub available: u64,
+}
+
+/// An attached disk image, detached again when dropped
+///
+/// ```no_run
+/// use afpack::{AttachOptions, MountedImage};
+///
+/// let volume = MountedImage::attach(
+///     "node_modules.asif",
+///     AttachOptions::new().with_mount_point("node_modules"),
+/// )?;
+/// println!("{} free", volume.usage()?.available);
+/// # Ok::<(), afpack::DiskImageError>(())
+/// ```
+pub struct MountedImage<'r> {
+    runner: &'r dyn CommandRunner,
+    image: PathBuf,
+    mount_point: PathBuf,
+    /// Whole-disk device the image is attached as, e.g. `/dev/disk5`
+    disk: Option<String>,
+    /// Device node of the volume, e.g. `/dev/disk6s1`
+    device: Option<String>,
+    readonly: bool,
+    attached: bool,
+    dry_run: bool,
+    verbose: bool,
+}
+
+impl MountedImage<'static> {
+    /// Attach a disk image
+    pub fn attach<P: AsRef<Path>>(image_path: P, options: AttachOptions) -> Result<Self> {
+        MountedImage::attach_with(&SystemRunner, image_path, options)
+    }
+}
+
+impl<'r> MountedImage<'r> {
+    /// Attach a disk image, running commands through `runner`
+    pub fn attach_with<P: AsRef<Path>>(
+        runner: &'r dyn CommandRunner,
+        image_path: P,
+        options: AttachOptions,
+    ) -> Result<Self> {
+        let image = image_path.as_ref().to_path_buf();
+        let requested = options.mount_point.as_ref().map(PathBuf::from);
+        let (readonly, dry_run, verbose) = (options.readonly, options.dry_run, options.verbose);
+        let program = options.program();
+        let output = DiskImage::attach_with(runner, &image, options)?;
+        let attached = parse_attach(&output);
+        let mut volume = Self {
+            runner,
+            image,
+            mount_point: PathBuf::new(),
+            disk: attached.disk,
+            device: attached.volume,
+            readonly,
+            attached: true,
+            dry_run,
+            verbose,
+        };
+        match requested.or(attached.mount_point) {
+            Some(mount_point) => volume.mount_point = mount_point,
+            None if dry_run => {}
+            None => {
+                // Without a mount point there is nothing to unmount, eject the disk if known
+                volume.attached = volume.disk.is_some();
+                return Err(DiskImageError::UnexpectedOutput {
+                    program: program.to_string(),
+                    output,
+                });
+            }
+        }
+        Ok(volume)
+    }
+
+    /// Directory the volume is mounted at
+    pub fn path(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// The attached image file
+    pub fn image(&self) -> &Path {
+        &self.image
+    }
+
+    /// Device node of the volume, unknown in dry run
+    pub fn device(&self) -> Option<&str> {
+        self.device.as_deref()
+    }
+
+    /// Whole-disk device ejected on detach, unknown in dry run
+    pub fn disk(&self) -> Option<&str> {
+        self.disk.as_deref()
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// Size and free space of the volume
+    pub fn usage(&self) -> Result<VolumeUsage> {
+        let args = vec![
+            "-P".to_string(),
+            "-k".to_string(),
+            self.mount_point.display().to_string(),
+        ];
+        let output = self.runner.run("df", &args, None)?;
+        parse_df(&output).ok_or(DiskImageError::UnexpectedOutput {
+            program: "df".to_string(),
+            output,
+        })
+    }
+
+    /// Unmount the volume and mount it again read-only at the same place
+    pub fn remount_readonly(&mut self) -> Result<()> {
+        let device = self.device.clone().ok_or_else(|| {
+            DiskImageError::InvalidPath(format!(
+                "device of {} is unknown",
+                self.mount_point.display()
+            ))
+        })?;
+        let mount_point = self.mount_point.display().to_string();
+        self.diskutil(&["unmount", &mount_point])?;
+        self.diskutil(&["mount", "readOnly", "-mountPoint", &mount_point, &device])?;
+        self.readonly = true;
+        Ok(())
+    }
+
+    /// Detach the image now, reporting failure unlike dropping it
+    pub fn detach(mut self) -> Result<()> {
+        self.attached = false;
+        self.eject()
+    }
+
+    /// Keep the image attached after the handle is gone, returning its mount point
+    pub fn leak(mut self) -> PathBuf {
+        self.attached = false;
+        std::mem::take(&mut self.mount_point)
+    }
+
+    /// Eject the whole image by its disk, or unmount the volume if the disk is unknown
+    fn eject(&self) -> Result<()> {
+        match &self.disk {
+            Some(disk) => self.diskutil(&["eject", disk]),
+            None => self.diskutil(&["unmount", &self.mount_point.display().to_string()]),
+        }
+    }
+
+    fn diskutil(&self, args: &[&str]) -> Result<()> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        DiskImage::run(
+            self.runner,
+            "diskutil",
+            &args,
+            None,
+            self.dry_run,
+            self.verbose,
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for MountedImage<'_> {
+    fn drop(&mut self) {
+        if self.attached {
+            let _ = self.eject();
+        }
+    }
+}
+
+impl std::fmt::Debug for MountedImage<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MountedImage")
+            .field("image", &self.image)
+            .field("mount_point", &self.mount_point)
+            .field("disk", &self.disk)
+            .field("device", &self.device)
+            .field("readonly", &self.readonly)
+            .finish()
+    }
+}
+
+/// Devices listed by hdiutil/diskutil attach
+#[derive(Debug, Default, PartialEq)]
+struct Attached {
+    /// First whole-disk device, what detaching the image ejects
+    disk: Option<String>,
+    /// Device of the mounted volume, or the first device if none is mounted
+    volume: Option<String>,
+    mount_point: Option<PathBuf>,
+}
+
+/// Disk, volume device and mount point in hdiutil/diskutil attach output
+///
+/// Each line is a tab separated `device  [content hint]  [mount point]`, only
+/// the volume's line has a mount point.
+fn parse_attach(output: &str) -> Attached {
+    let mut attached = Attached::default();
+    for line in output.lines() {
+        let fields: Vec<&str> = line
+            .split('\t')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .collect();
+        let Some(device) = fields.first().filter(|f| f.starts_with("/dev/")) else {
+            continue;
+        };
+        if attached.disk.is_none() && is_whole_disk(device) {
+            attached.disk = Some(device.to_string());
+        }
+        if let Some(mount_point) = fields[1..]
+            .last()
+            .filter(|f| f.starts_with('/') && !f.starts_with("/dev/"))
+        {
+            attached.volume = Some(device.to_string());
+            attached.mount_point = Some(PathBuf::from(mount_point));
+            break;
+        }
+        attached.volume.get_or_insert_with(|| device.to_string());
+    }
+    if attached.disk.is_none() {
+        attached.disk = attached.volume.clone();
+    }
+    attached
+}
+
+/// `/dev/disk5` but not its slice `/dev/disk5s1`
+fn is_whole_disk(device: &str) -> bool {
+    device
+        .strip_prefix("/dev/disk")
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Parse `df -P -k`, whose columns before the capacity are blocks, used and available
+fn parse_df(output: &str) -> Option<VolumeUsage> {
+    let fields: Vec<&str> = output.lines().nth(1)?.split_whitespace().collect();
+    let capacity = fields.iter().position(|f| f.ends_with('%'))?;
+    let kib = |i: usize| -> Option<u64> { fields.get(i)?.parse::<u64>().ok().map(|k| k * 1024) };
+    Some(VolumeUsage {
+        total: kib(capacity.checked_sub(3)?)?,
+        used: kib(capacity - 2)?,
+        available: kib(capacity - 1)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::FakeRunner;
+
+    const HDIUTIL_ATTACH: &str = "/dev/disk5          \tGUID_partition_scheme          \t\n\
+        /dev/disk5s1        \tApple_APFS                     \t\n\
+        /dev/disk6          \tEF57347C-0000-11AA-AA11-0030654\t\n\
+        /dev/disk6s1        \t41504653-0000-11AA-AA11-0030654\t/Users/dev/p/node_modules\n";
+
+    /// Answers attach and df, recording every command
+    fn runner() -> FakeRunner {
+        FakeRunner::replying(|program, args, _| {
+            Ok(match (program, args[0].as_str()) {
+                ("hdiutil", "attach") => HDIUTIL_ATTACH.to_string(),
+                ("df", _) => "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+                    /dev/disk6s1 10485760 2097152 8388608 21% /Users/dev/p/node_modules\n"
+                    .to_string(),
+                _ => String::new(),
+            })
+        })
+    }
+
+    #[test]
+    fn test_attach_and_drop() {
+        let runner = runner();
+        let options = AttachOptions::new().nobrowse();
+        {
+            let volume =
+                MountedImage::attach_with(&runner, "/p/node_modules.asif", options).unwrap();
+            assert_eq!(volume.path(), Path::new("/Users/dev/p/node_modules"));
+            assert_eq!(volume.device(), Some("/dev/disk6s1"));
+            assert_eq!(volume.disk(), Some("/dev/disk5"));
+            assert_eq!(
+                volume.usage().unwrap(),
+                VolumeUsage {
+                    total: 10 << 30,
+                    used: 2 << 30,
+                    available: 8 << 30,
+                }
+            );
+        }
+        assert_eq!(runner.last().unwrap(), "diskutil eject /dev/disk5");
+    }
+
+    #[test]
+    fn test_remount_readonly_and_leak() {
+        let runner = runner();
+        let options = AttachOptions::new().hidden_at("/p/target");
+        let mut volume = MountedImage::attach_with(&runner, "/p/target.asif", options).unwrap();
+        assert_eq!(volume.path(), Path::new("/p/target"));
+        volume.remount_readonly().unwrap();
+        assert!(volume.is_readonly());
+        assert_eq!(volume.leak(), PathBuf::from("/p/target"));
+        assert_eq!(
+            runner.calls()[1..],
+            [
+                "diskutil unmount /p/target",
+                "diskutil mount readOnly -mountPoint /p/target /dev/disk6s1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_attach() {
+        assert_eq!(parse_attach(""), Attached::default());
+        assert_eq!(
+            parse_attach("/dev/disk7\tGUID_partition_scheme\t\n"),
+            Attached {
+                disk: Some("/dev/disk7".to_string()),
+                volume: Some("/dev/disk7".to_string()),
+                mount_point: None,
+            }
+        );
+        assert_eq!(
+            parse_attach("/dev/disk7s1\tApple_HFS\t/Volumes/Untitled 1\n"),
+            Attached {
+                disk: Some("/dev/disk7s1".to_string()),
+                volume: Some("/dev/disk7s1".to_string()),
+                mount_point: Some(PathBuf::from("/Volumes/Untitled 1")),
+            }
+        );
+        assert!(is_whole_disk("/dev/disk12"));
+        assert!(!is_whole_disk("/dev/disk12s1"));
+        assert!(!is_whole_disk("/dev/disk"));
+    }
+}
@@ -0,0 +1,280 @@
+//! Output of the afpack CLI for people (`human`) and programs (`--json`, `--porcelain`)
+//!
+//! In the machine modes every line on stdout is a [`Record`]: a JSON object with
+//! the schema `version` and an `event` tag naming one of the [`Event`] variants.
+//!
+//! ```text
+//! {"version":1,"event":"command","program":"diskutil","args":["unmount","/p/node_modules"],"dry_run":false}
+//! {"version":1,"event":"progress","operation":"compress","path":"/p/node_modules.asif","done":1048576,"total":8388608}
+//! {"version":1,"event":"result","command":"detach","data":{"detached":["/p/node_modules"]}}
+//! {"version":1,"event":"error","kind":"busy","exit_code":10,"message":"..."}
+//! ```
+//!
+//! `--json` also emits the human messages as `message` events, `--porcelain`
+//! leaves them on stderr so stdout only carries data.
+
+use crate::diskimage::ErrorKind;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Version of the [`Record`] schema, bumped when a field changes meaning or is removed
+pub const SCHEMA_VERSION: u32 = 1;
+
+static MODE: OnceLock<OutputMode> = OnceLock::new();
+
+/// How the CLI reports what it does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputMode {
+    #[default]
+    Human,
+    /// JSON lines, messages included
+    Json,
+    /// JSON lines of data only, messages go to stderr
+    Porcelain,
+}
+
+impl OutputMode {
+    pub fn is_machine(&self) -> bool {
+        *self != OutputMode::Human
+    }
+}
+
+impl std::fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputMode::Human => write!(f, "human"),
+            OutputMode::Json => write!(f, "json"),
+            OutputMode::Porcelain => write!(f, "porcelain"),
+        }
+    }
+}
+
+/// Importance of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Level {
+    Info,
+    /// Only shown with `--verbose`
+    Verbose,
+    Warning,
+}
+
+/// Something afpack reports while running a command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A line of the human output
+    Message { level: Level, text: String },
+    /// An external command about to run, or that would run in dry run
+    Command {
+        program: String,
+        args: Vec<String>,
+        dry_run: bool,
+    },
+    /// Bytes processed so far by a long operation on `path`
+    Progress {
+        operation: String,
+        path: PathBuf,
+        done: u64,
+        total: u64,
+    },
+    /// Outcome of the command, `data` depends on the command
+    Result {
+        command: String,
+        data: serde_json::Value,
+    },
+    /// The command failed and afpack exits with `exit_code`
+    Error {
+        kind: ErrorKind,
+        exit_code: i32,
+        message: String,
+    },
+}
+
+impl Event {
+    pub fn info(text: impl Into<String>) -> Self {
+        Event::Message {
+            level: Level::Info,
+            text: text.into(),
+        }
+    }
+
+    pub fn verbose(text: impl Into<String>) -> Self {
+        Event::Message {
+            level: Level::Verbose,
+            text: text.into(),
+        }
+    }
+
+    pub fn warning(text: impl Into<String>) -> Self {
+        Event::Message {
+            level: Level::Warning,
+            text: text.into(),
+        }
+    }
+
+    /// Result event of `command` with `data` serialized
+    pub fn result(command: impl Into<String>, data: impl Serialize) -> Self {
+        Event::Result {
+            command: command.into(),
+            data: serde_json::to_value(data).unwrap_or(serde_json::Value::Null),
+        }
+    }
+
+    /// The line shown in human output, `None` for events only programs care about
+    pub fn human(&self) -> Option<String> {
+        match self {
+            Event::Message {
+                level: Level::Warning,
+                text,
+            } => Some(format!("warning: {}", text)),
+            Event::Message { text, .. } => Some(text.clone()),
+            Event::Command {
+                program,
+                args,
+                dry_run,
+            } => {
+                let prefix = if *dry_run {
+                    "[DRY RUN] Would execute:"
+                } else {
+                    "[VERBOSE] Executing:"
+                };
+                Some(format!("{} {} {}", prefix, program, args.join(" ")))
+            }
+            Event::Error { message, .. } => Some(message.clone()),
+            Event::Progress { .. } | Event::Result { .. } => None,
+        }
+    }
+
+    /// Whether the human line belongs on stderr
+    fn is_diagnostic(&self) -> bool {
+        matches!(
+            self,
+            Event::Error { .. }
+                | Event::Message {
+                    level: Level::Warning,
+                    ..
+                }
+        )
+    }
+}
+
+/// One line of machine output
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub version: u32,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Record {
+    pub fn new(event: Event) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            event,
+        }
+    }
+}
+
+/// Select the output mode, once at startup
+pub fn set_mode(mode: OutputMode) {
+    let _ = MODE.set(mode);
+}
+
+/// The selected output mode, human unless set
+pub fn mode() -> OutputMode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// Report `event` in the selected output mode, and in the log
+pub fn emit(event: Event) {
+    match &event {
+        Event::Message {
+            level: Level::Warning,
+            text,
+        } => tracing::warn!("{}", text),
+        Event::Message {
+            level: Level::Verbose,
+            text,
+        } => tracing::debug!("{}", text),
+        Event::Message { text, .. } => tracing::info!("{}", text),
+        Event::Error {
+            kind,
+            exit_code,
+            message,
+        } => tracing::error!(%kind, exit_code, "{}", message),
+        _ => {}
+    }
+    let mode = mode();
+    // Porcelain keeps stdout for data, messages stay human on stderr
+    let machine = !matches!(
+        (&event, mode),
+        (_, OutputMode::Human) | (Event::Message { .. }, OutputMode::Porcelain)
+    );
+    if machine {
+        if let Ok(line) = serde_json::to_string(&Record::new(event)) {
+            let mut stdout = std::io::stdout().lock();
+            let _ = writeln!(stdout, "{}", line);
+            let _ = stdout.flush();
+        }
+    } else if let Some(line) = event.human() {
+        if event.is_diagnostic() || mode == OutputMode::Porcelain {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_json() {
+        let record = Record::new(Event::Command {
+            program: "diskutil".into(),
+            args: vec!["unmount".into(), "/p/node_modules".into()],
+            dry_run: true,
+        });
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":1,"event":"command","program":"diskutil","args":["unmount","/p/node_modules"],"dry_run":true}"#
+        );
+        assert_eq!(serde_json::from_str::<Record>(&json).unwrap(), record);
+
+        let error: Record = serde_json::from_str(
+            r#"{"version":1,"event":"error","kind":"no-space","exit_code":11,"message":"full"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            error.event,
+            Event::Error {
+                kind: ErrorKind::NoSpace,
+                exit_code: 11,
+                message: "full".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_human_lines() {
+        let command = Event::Command {
+            program: "diskutil".into(),
+            args: vec!["unmount".into(), "/p/target".into()],
+            dry_run: true,
+        };
+        assert_eq!(
+            command.human().as_deref(),
+            Some("[DRY RUN] Would execute: diskutil unmount /p/target")
+        );
+        assert_eq!(
+            Event::warning("stale shadow").human().as_deref(),
+            Some("warning: stale shadow")
+        );
+        assert_eq!(Event::result("list", Vec::<String>::new()).human(), None);
+    }
+}
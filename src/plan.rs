@@ -0,0 +1,1115 @@
+//! Operations as an ordered list of typed steps, built before anything runs
+//!
+//! `afpack --dry-run` shows the [`Plan`] instead of running it, `--json` and
+//! `--porcelain` emit it as a `plan` result, and `afpack apply plan.json` runs
+//! a saved one. Paths in a plan are absolute so it can be applied from anywhere.
+//!
+//! ```text
+//! {"steps":[{"step":"create-blank","image":"/p/node_modules.asif","size":"10G",...},
+//!           {"step":"attach","image":"/p/node_modules.asif","mount_point":"/p/node_modules",...}]}
+//! ```
+
+use crate::activity::{self, Signal};
+use crate::compression::{self, CompressionPolicy, CompressionStats, ProgressReporter, Schedule};
+use crate::config::{Disposal, Settings};
+use crate::diskimage::{
+    self, AttachOptions, CommandRunner, CreateBlankOptions, DetachOptions, DiskImage,
+    DiskImageError, Encryption, FileSystem, Format, ResizeOptions, Result, SystemRunner,
+};
+use crate::output::{self, Event, Record};
+use crate::registry::{Entry, Registry};
+use crate::secret::{Passphrase, PassphraseSource};
+use crate::snapshot::SnapshotStore;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// One operation of a plan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "kebab-case")]
+pub enum Step {
+    /// Get the passphrase used by the encrypted steps after it
+    Passphrase {
+        afdir: PathBuf,
+        source: PassphraseSource,
+        /// Generate and store one if the source has none yet
+        provision: bool,
+    },
+    /// Undo transparent compression of an image file
+    Decompress { image: PathBuf },
+    /// New empty image, filled by a `Copy` step for an existing directory
+    CreateBlank {
+        image: PathBuf,
+        size: String,
+        filesystem: FileSystem,
+        format: Format,
+        volume_name: Option<String>,
+        owners: bool,
+        encryption: Option<Encryption>,
+    },
+    Resize {
+        image: PathBuf,
+        size: String,
+        encrypted: bool,
+    },
+    /// Get rid of the original directory once its contents are in the image
+    Dispose { path: PathBuf, disposal: Disposal },
+    Attach {
+        image: PathBuf,
+        mount_point: PathBuf,
+        shadow: Option<PathBuf>,
+        encrypted: bool,
+    },
+    /// Honour file ownership on a new volume, a warning if it fails
+    EnableOwnership { mount_point: PathBuf },
+    /// Copy the contents of a directory into a mounted volume with ditto
+    Copy {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+    /// Unmount a volume and remove its mount point
+    Detach { mount_point: PathBuf },
+    /// Detach the image of a managed directory and record it as detached
+    DetachEntry {
+        afdir: PathBuf,
+        /// Attempts after the first while the volume is busy
+        retries: u32,
+        /// Sent to the blocking processes before each retry
+        signal: Option<Signal>,
+        force: bool,
+        /// Detached for being idle, so the shell hook reattaches it
+        idle: bool,
+    },
+    /// Copy an image file, a copy-on-write clone where the filesystem allows
+    Clone {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+    /// Clone an image into its snapshot store, keeping the `keep` most recent
+    Snapshot {
+        image: PathBuf,
+        label: Option<String>,
+        keep: usize,
+    },
+    /// Replace an image with a clone of one of its snapshots
+    Restore { image: PathBuf, snapshot: String },
+    /// Record the directory as managed and attached
+    Register { entry: Entry },
+    /// Compress a path, recording a registered image as compressed
+    Compress {
+        path: PathBuf,
+        policy: CompressionPolicy,
+    },
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Passphrase {
+                afdir,
+                source,
+                provision,
+            } => write!(
+                f,
+                "{} the passphrase of {} from {}",
+                if *provision { "provision" } else { "fetch" },
+                afdir.display(),
+                source
+            ),
+            Step::Decompress { image } => write!(f, "decompress {}", image.display()),
+            Step::CreateBlank {
+                image,
+                size,
+                filesystem,
+                format,
+                encryption,
+                ..
+            } => {
+                write!(
+                    f,
+                    "create blank {} {} {} image {}",
+                    size,
+                    filesystem,
+                    format,
+                    image.display()
+                )?;
+                write_encryption(f, encryption)
+            }
+            Step::Resize { image, size, .. } => write!(f, "resize {} to {}", image.display(), size),
+            Step::Dispose { path, disposal } => match disposal {
+                Disposal::Trash => write!(f, "move {} to the trash", path.display()),
+                Disposal::Delete => write!(f, "delete {}", path.display()),
+                Disposal::Keep => write!(f, "rename {} to {}.orig", path.display(), path.display()),
+            },
+            Step::Attach {
+                image,
+                mount_point,
+                shadow,
+                ..
+            } => {
+                write!(f, "attach {} at {}", image.display(), mount_point.display())?;
+                if let Some(shadow) = shadow {
+                    write!(f, " with shadow {}", shadow.display())?;
+                }
+                Ok(())
+            }
+            Step::EnableOwnership { mount_point } => {
+                write!(f, "enable ownership on {}", mount_point.display())
+            }
+            Step::Copy {
+                source,
+                destination,
+            } => write!(
+                f,
+                "copy {} into {}",
+                source.display(),
+                destination.display()
+            ),
+            Step::Detach { mount_point } => write!(f, "detach {}", mount_point.display()),
+            Step::DetachEntry {
+                afdir,
+                retries,
+                signal,
+                force,
+                ..
+            } => {
+                write!(f, "detach {} ({} retries", afdir.display(), retries)?;
+                if let Some(signal) = signal {
+                    write!(f, ", sending SIG{}", signal)?;
+                }
+                if *force {
+                    write!(f, ", then force")?;
+                }
+                write!(f, ")")
+            }
+            Step::Clone {
+                source,
+                destination,
+            } => write!(f, "clone {} to {}", source.display(), destination.display()),
+            Step::Snapshot { image, label, keep } => {
+                write!(f, "snapshot {}", image.display())?;
+                if let Some(label) = label {
+                    write!(f, " as {}", label)?;
+                }
+                write!(f, ", keeping {}", keep)
+            }
+            Step::Restore { image, snapshot } => {
+                write!(f, "restore {} from snapshot {}", image.display(), snapshot)
+            }
+            Step::Register { entry } => write!(f, "register {}", entry.afdir.display()),
+            Step::Compress { path, policy } => write!(
+                f,
+                "compress {} with {} (level {}, min savings {})",
+                path.display(),
+                policy.algorithm,
+                policy.level,
+                policy.min_savings
+            ),
+        }
+    }
+}
+
+fn write_encryption(
+    f: &mut std::fmt::Formatter<'_>,
+    encryption: &Option<Encryption>,
+) -> std::fmt::Result {
+    match encryption {
+        Some(encryption) => write!(f, ", encrypted with {}", encryption),
+        None => Ok(()),
+    }
+}
+
+/// What `pack` found for one artifact directory
+#[derive(Debug, Clone)]
+pub struct PackState {
+    /// Absolute path of the artifact directory
+    pub afdir: PathBuf,
+    /// Absolute path of its image, the registered one if managed
+    pub image: PathBuf,
+    pub maxsize: String,
+    /// The registry entry of a managed directory
+    pub entry: Option<Entry>,
+    pub image_exists: bool,
+    pub afdir_exists: bool,
+}
+
+/// Ordered steps of an operation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, step: Step) {
+        self.steps.push(step);
+    }
+
+    pub fn extend(&mut self, other: Plan) {
+        self.steps.extend(other.steps);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Create `image` for `afdir`, with a copy of its contents if it exists
+    ///
+    /// The contents are copied into a blank image mounted at a staging
+    /// directory, so the volume always has the configured filesystem and name.
+    pub fn create_image(
+        afdir: &Path,
+        afdir_exists: bool,
+        image: &Path,
+        maxsize: &str,
+        settings: &Settings,
+    ) -> Self {
+        let mut plan = Plan::new();
+        if settings.encryption.is_some() {
+            plan.push(Step::Passphrase {
+                afdir: afdir.to_path_buf(),
+                source: settings.passphrase_from.clone(),
+                provision: true,
+            });
+        }
+        plan.push(Step::CreateBlank {
+            image: image.to_path_buf(),
+            size: maxsize.to_string(),
+            filesystem: settings.filesystem.clone(),
+            format: settings.format.clone(),
+            volume_name: settings.volume_name.clone(),
+            owners: settings.owners,
+            encryption: settings.encryption,
+        });
+        if !afdir_exists {
+            return plan;
+        }
+        let staging = staging_path(image);
+        plan.push(Step::Attach {
+            image: image.to_path_buf(),
+            mount_point: staging.clone(),
+            shadow: None,
+            encrypted: settings.encryption.is_some(),
+        });
+        // Without ownership on, the copied files would all belong to the user
+        if settings.owners {
+            plan.push(Step::EnableOwnership {
+                mount_point: staging.clone(),
+            });
+        }
+        plan.push(Step::Copy {
+            source: afdir.to_path_buf(),
+            destination: staging.clone(),
+        });
+        plan.push(Step::Detach {
+            mount_point: staging,
+        });
+        plan
+    }
+
+    /// Move an artifact directory into its image and attach it in place
+    pub fn pack(state: &PackState, settings: &Settings, policy: &CompressionPolicy) -> Self {
+        let mut plan = Plan::new();
+        let created = !state.image_exists;
+        let entry = state.entry.as_ref();
+        if policy.decompress_on_attach && entry.is_some_and(|e| e.compressed) {
+            plan.push(Step::Decompress {
+                image: state.image.clone(),
+            });
+        }
+
+        let encrypted = if created {
+            plan.extend(Plan::create_image(
+                &state.afdir,
+                state.afdir_exists,
+                &state.image,
+                &state.maxsize,
+                settings,
+            ));
+            if state.afdir_exists {
+                plan.push(Step::Dispose {
+                    path: state.afdir.clone(),
+                    disposal: settings.disposal,
+                });
+            }
+            settings.encryption.is_some()
+        } else if let Some(source) = entry.and_then(|e| e.passphrase_from.clone()) {
+            plan.push(Step::Passphrase {
+                afdir: state.afdir.clone(),
+                source,
+                provision: false,
+            });
+            true
+        } else {
+            false
+        };
+
+        plan.push(Step::Attach {
+            image: state.image.clone(),
+            mount_point: state.afdir.clone(),
+            shadow: None,
+            encrypted,
+        });
+        if created && settings.owners {
+            plan.push(Step::EnableOwnership {
+                mount_point: state.afdir.clone(),
+            });
+        }
+
+        let mut entry = entry
+            .cloned()
+            .unwrap_or_else(|| Entry::new(&state.afdir, &state.image, &state.maxsize));
+        entry.compression = policy.clone();
+        entry.detach_after = settings.detach_after;
+        if created {
+            entry.format = Some(settings.format.clone());
+            entry.filesystem = Some(settings.filesystem.clone());
+            entry.encryption = settings.encryption;
+            entry.passphrase_from = settings
+                .encryption
+                .map(|_| settings.passphrase_from.clone());
+        }
+        plan.push(Step::Register { entry });
+
+        if policy.runs_on(Schedule::OnPack) {
+            plan.push(Step::Compress {
+                path: policy.target_path(&state.image, &state.afdir).to_path_buf(),
+                policy: policy.clone(),
+            });
+        }
+        plan
+    }
+
+    /// Detach a managed directory, compressing its image if the policy runs on detach
+    pub fn detach(entry: &Entry, options: &DetachOptions) -> Self {
+        let mut plan = Plan::new();
+        plan.push(Step::detach_entry(&entry.afdir, options));
+        if entry.compression.runs_on(Schedule::OnDetach) && !entry.compressed {
+            plan.push(Step::Compress {
+                path: entry.image.clone(),
+                policy: entry.compression.clone(),
+            });
+        }
+        plan
+    }
+
+    /// Like [`Plan::detach`], for an image that has been idle
+    pub fn idle_detach(entry: &Entry) -> Self {
+        let mut plan = Plan::detach(entry, &DetachOptions::new());
+        if let Some(Step::DetachEntry { idle, .. }) = plan.steps.first_mut() {
+            *idle = true;
+        }
+        plan
+    }
+
+    /// Attach the registered image (and shadow) of an entry at its directory
+    ///
+    /// The registry is left alone, push a `Register` step to record it.
+    pub fn attach(entry: &Entry) -> Self {
+        let mut plan = Plan::new();
+        if let Some(source) = &entry.passphrase_from {
+            plan.push(Step::Passphrase {
+                afdir: entry.afdir.clone(),
+                source: source.clone(),
+                provision: false,
+            });
+        }
+        plan.push(Step::Attach {
+            image: entry.image.clone(),
+            mount_point: entry.afdir.clone(),
+            shadow: entry.shadow.clone(),
+            encrypted: entry.passphrase_from.is_some(),
+        });
+        plan
+    }
+
+    /// Parse a plan, or the JSON lines of `--dry-run --porcelain` holding a `plan` result
+    pub fn parse(json: &str) -> Result<Self> {
+        let records = json
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Record>(line).ok());
+        for record in records {
+            if let Event::Result { command, data } = record.event {
+                if command == "plan" {
+                    return serde_json::from_value(data)
+                        .map_err(|e| DiskImageError::Plan(e.to_string()));
+                }
+            }
+        }
+        serde_json::from_str(json).map_err(|e| DiskImageError::Plan(e.to_string()))
+    }
+
+    /// Run the steps in order, stopping at the first failure
+    pub fn run(&self) -> Result<()> {
+        self.run_with(&SystemRunner, &Registry::default_path())
+    }
+
+    /// Run the steps through `runner`, registering directories in the registry at `registry`
+    pub fn run_with(&self, runner: &dyn CommandRunner, registry: &Path) -> Result<()> {
+        let mut passphrase: Option<Passphrase> = None;
+        for (i, step) in self.steps.iter().enumerate() {
+            let _span = tracing::info_span!("step", index = i + 1, step = %step).entered();
+            tracing::debug!("{}", step);
+            step.run(runner, registry, &mut passphrase)
+                .inspect_err(|e| tracing::debug!("{} failed: {}", step, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Step {
+    /// Detach the image of the managed `afdir` with `options`
+    pub fn detach_entry(afdir: &Path, options: &DetachOptions) -> Self {
+        Step::DetachEntry {
+            afdir: afdir.to_path_buf(),
+            retries: options.retries,
+            signal: options.signal,
+            force: options.force,
+            idle: false,
+        }
+    }
+
+    fn run(
+        &self,
+        runner: &dyn CommandRunner,
+        registry: &Path,
+        passphrase: &mut Option<Passphrase>,
+    ) -> Result<()> {
+        match self {
+            Step::Passphrase {
+                afdir,
+                source,
+                provision,
+            } => {
+                *passphrase = Some(if *provision {
+                    source.provision_with(runner, afdir)?
+                } else {
+                    source.fetch_with(runner, afdir)?
+                });
+            }
+            Step::Decompress { image } => {
+                let progress = ProgressReporter::hidden();
+                compression::decompress(std::iter::once(image.as_path()), &progress);
+                check_compression(image, &progress)?;
+                mark_compressed(registry, image, false)?;
+            }
+            Step::CreateBlank {
+                image,
+                size,
+                filesystem,
+                format,
+                volume_name,
+                owners,
+                encryption,
+            } => {
+                create_parent(image)?;
+                let mut options =
+                    CreateBlankOptions::new(size.as_str(), filesystem.clone(), format.clone())
+                        .with_owners(*owners);
+                if let Some(name) = volume_name {
+                    options = options.with_volume_name(name);
+                }
+                if let (Some(encryption), Some(passphrase)) = (
+                    encryption,
+                    self.secret(passphrase.as_ref(), encryption.is_some())?,
+                ) {
+                    options = options.with_encryption(*encryption, passphrase);
+                }
+                DiskImage::create_blank_with(runner, image, options)?;
+            }
+            Step::Resize {
+                image,
+                size,
+                encrypted,
+            } => {
+                let mut options = ResizeOptions::new(size.as_str());
+                if let Some(passphrase) = self.secret(passphrase.as_ref(), *encrypted)? {
+                    options = options.with_passphrase(passphrase);
+                }
+                DiskImage::resize_with(runner, image, options)?;
+            }
+            Step::Dispose { path, disposal } => {
+                if !path.exists() {
+                    return Ok(());
+                }
+                match disposal {
+                    Disposal::Trash => trash::delete(path).map_err(std::io::Error::other)?,
+                    Disposal::Delete => std::fs::remove_dir_all(path)?,
+                    Disposal::Keep => std::fs::rename(path, format!("{}.orig", path.display()))?,
+                }
+            }
+            Step::Attach {
+                image,
+                mount_point,
+                shadow,
+                encrypted,
+            } => {
+                let mut options =
+                    AttachOptions::new().with_mount_point(mount_point.display().to_string());
+                if let Some(shadow) = shadow {
+                    create_parent(shadow)?;
+                    options = options.with_shadow(shadow.display().to_string());
+                }
+                if let Some(passphrase) = self.secret(passphrase.as_ref(), *encrypted)? {
+                    options = options.with_passphrase(passphrase);
+                }
+                DiskImage::attach_with(runner, image, options)?;
+            }
+            Step::EnableOwnership { mount_point } => {
+                let args = vec![
+                    "enableOwnership".to_string(),
+                    mount_point.display().to_string(),
+                ];
+                if let Err(e) = DiskImage::run(runner, "diskutil", &args, None, false, false) {
+                    output::emit(Event::warning(format!(
+                        "could not enable ownership on {}: {}",
+                        mount_point.display(),
+                        e
+                    )));
+                }
+            }
+            Step::Copy {
+                source,
+                destination,
+            } => {
+                // ditto copies the contents of a directory into an existing one
+                let args = vec![
+                    source.display().to_string(),
+                    destination.display().to_string(),
+                ];
+                DiskImage::run(runner, "ditto", &args, None, false, false)?;
+            }
+            Step::Detach { mount_point } => {
+                DiskImage::detach_with(runner, mount_point, DetachOptions::new())?;
+                // Empty once unmounted; diskutil may have removed it already
+                let _ = std::fs::remove_dir(mount_point);
+            }
+            Step::DetachEntry {
+                afdir,
+                retries,
+                signal,
+                force,
+                idle,
+            } => {
+                let mut options = DetachOptions::new()
+                    .with_retries(*retries)
+                    .with_force(*force);
+                if let Some(signal) = signal {
+                    options = options.with_signal(*signal);
+                }
+                DiskImage::detach_with(runner, afdir, options)?;
+                update_registry(registry, |registry_file| {
+                    let entry = registry_file.get_mut(afdir)?;
+                    entry.attached = false;
+                    entry.idle_detached = *idle;
+                    Some(())
+                })?;
+            }
+            Step::Clone {
+                source,
+                destination,
+            } => {
+                // std::fs::copy uses clonefile on APFS, so this is copy-on-write
+                std::fs::copy(source, destination)?;
+            }
+            Step::Snapshot { image, label, keep } => {
+                let mut store = SnapshotStore::open(image)?;
+                let snapshot = store.create(image, label.as_deref())?;
+                output::emit(Event::info(format!(
+                    "snapshot {} of {}",
+                    snapshot.id,
+                    image.display()
+                )));
+                for removed in store.prune(*keep)? {
+                    tracing::debug!("pruned snapshot {}", removed.id);
+                }
+            }
+            Step::Restore { image, snapshot } => {
+                let store = SnapshotStore::open(image)?;
+                let snapshot = store.find(Some(snapshot)).ok_or_else(|| {
+                    DiskImageError::InvalidPath(format!(
+                        "no snapshot {} of {}",
+                        snapshot,
+                        image.display()
+                    ))
+                })?;
+                store.restore(snapshot, image)?;
+            }
+            Step::Register { entry } => {
+                let mut registry_file = Registry::load_from(registry)?;
+                let mut entry = entry.clone();
+                if let Some(recorded) = registry_file.get(&entry.afdir) {
+                    entry.keep_compressed_from(recorded);
+                }
+                entry.mark_attached(activity::now());
+                registry_file.upsert(entry);
+                registry_file.save_to(registry)?;
+            }
+            Step::Compress { path, policy } => {
+                // Nothing may have been worth compressing, or the policy compresses nothing
+                if compress(path, policy)?.compressed_files > 0 {
+                    mark_compressed(registry, path, true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The passphrase fetched by an earlier step, if this step is `encrypted`
+    fn secret(
+        &self,
+        passphrase: Option<&Passphrase>,
+        encrypted: bool,
+    ) -> Result<Option<Passphrase>> {
+        match (encrypted, passphrase) {
+            (false, _) => Ok(None),
+            (true, Some(passphrase)) => Ok(Some(passphrase.clone())),
+            (true, None) => Err(DiskImageError::Plan(format!(
+                "no passphrase step before \"{}\"",
+                self
+            ))),
+        }
+    }
+}
+
+/// Change the registry at `path` with `f`, saving it unless `f` returns `None`
+fn update_registry(path: &Path, f: impl FnOnce(&mut Registry) -> Option<()>) -> Result<()> {
+    let mut registry = Registry::load_from(path)?;
+    if f(&mut registry).is_some() {
+        registry.save_to(path)?;
+    }
+    Ok(())
+}
+
+/// Record whether `image` is compressed on the entries and variants using it
+fn mark_compressed(registry: &Path, image: &Path, compressed: bool) -> Result<()> {
+    update_registry(registry, |registry| {
+        let mut changed = false;
+        for entry in &mut registry.entries {
+            if entry.image == image {
+                entry.compressed = compressed;
+                changed = true;
+            }
+            for record in entry.variants.values_mut().filter(|v| v.image == image) {
+                record.compressed = compressed;
+                changed = true;
+            }
+        }
+        changed.then_some(())
+    })
+}
+
+/// Where a new image is mounted while a directory is copied into it
+pub fn staging_path(image: &Path) -> PathBuf {
+    image.with_extension("staging")
+}
+
+/// Create the directory an image or shadow file goes in
+fn create_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => Ok(std::fs::create_dir_all(parent)?),
+        _ => Ok(()),
+    }
+}
+
+/// Fail with the files applesauce reported errors for
+fn check_compression(path: &Path, progress: &ProgressReporter) -> Result<()> {
+    let errors = progress.errors();
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(DiskImageError::Compression {
+        path: path.to_path_buf(),
+        errors,
+    })
+}
+
+fn compress(path: &Path, policy: &CompressionPolicy) -> Result<CompressionStats> {
+    let progress = ProgressReporter::new(std::iter::once(path));
+    let stats = policy.compress(std::iter::once(path), &progress);
+    progress.finish();
+    check_compression(path, &progress)?;
+    output::emit(Event::info(format!(
+        "compressed {} of {} files, {} -> {} ({:.0}% saved)",
+        stats.compressed_files,
+        stats.files,
+        diskimage::format_size(stats.total_size),
+        diskimage::format_size(stats.size_after),
+        stats.savings() * 100.0
+    )));
+    let usage = compression::disk_usage(path);
+    output::emit(Event::info(format!(
+        "{}: {} logical, {} allocated",
+        path.display(),
+        diskimage::format_size(usage.logical),
+        diskimage::format_size(usage.allocated)
+    )));
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Algorithm;
+    use crate::diskimage::ErrorKind;
+    use crate::testutil::{FakeRunner, TempDir};
+
+    /// Records every command, answering the keychain lookup
+    fn runner() -> FakeRunner {
+        FakeRunner::replying(|program, _, _| {
+            Ok(if program == "security" {
+                "s3cret\n"
+            } else {
+                ""
+            }
+            .to_string())
+        })
+    }
+
+    fn state(image_exists: bool, afdir_exists: bool) -> PackState {
+        PackState {
+            afdir: PathBuf::from("/p/node_modules"),
+            image: PathBuf::from("/p/node_modules.asif"),
+            maxsize: "10G".to_string(),
+            entry: None,
+            image_exists,
+            afdir_exists,
+        }
+    }
+
+    fn kinds(plan: &Plan) -> Vec<String> {
+        plan.steps
+            .iter()
+            .map(|step| {
+                let value = serde_json::to_value(step).unwrap();
+                value["step"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pack_existing_directory() {
+        let settings = Settings {
+            owners: true,
+            disposal: Disposal::Keep,
+            ..Settings::default()
+        };
+        let policy = CompressionPolicy::new(Algorithm::Lzfse);
+        let plan = Plan::pack(&state(false, true), &settings, &policy);
+        assert_eq!(
+            kinds(&plan),
+            [
+                "create-blank",
+                "attach",
+                "enable-ownership",
+                "copy",
+                "detach",
+                "dispose",
+                "attach",
+                "enable-ownership",
+                "register",
+                "compress"
+            ]
+        );
+        assert_eq!(
+            plan.steps[3].to_string(),
+            "copy /p/node_modules into /p/node_modules.staging"
+        );
+        assert_eq!(
+            plan.steps[5].to_string(),
+            "rename /p/node_modules to /p/node_modules.orig"
+        );
+        let Step::Register { entry } = &plan.steps[8] else {
+            panic!("expected register, got {}", plan.steps[8]);
+        };
+        assert_eq!(entry.image, PathBuf::from("/p/node_modules.asif"));
+        assert_eq!(entry.compression, policy);
+    }
+
+    #[test]
+    fn test_pack_existing_directory_case_sensitive() {
+        let settings = Settings {
+            filesystem: FileSystem::APFSCaseSensitive,
+            volume_name: Some("deps".into()),
+            ..Settings::default()
+        };
+        let plan = Plan::pack(
+            &state(false, true),
+            &settings,
+            &CompressionPolicy::default(),
+        );
+        assert_eq!(
+            plan.steps[0],
+            Step::CreateBlank {
+                image: PathBuf::from("/p/node_modules.asif"),
+                size: "10G".into(),
+                filesystem: FileSystem::APFSCaseSensitive,
+                format: Format::default(),
+                volume_name: Some("deps".into()),
+                owners: false,
+                encryption: None,
+            }
+        );
+        let Some(Step::Register { entry }) = plan.steps.last() else {
+            panic!("expected register last");
+        };
+        assert_eq!(entry.filesystem, Some(FileSystem::APFSCaseSensitive));
+
+        let dir = TempDir::new("plan-copy");
+        let plan = Plan::create_image(
+            &dir.join("node_modules"),
+            true,
+            &dir.join("node_modules.asif"),
+            "10G",
+            &settings,
+        );
+        let runner = runner();
+        plan.run_with(&runner, &dir.join("registry.json")).unwrap();
+        let calls = runner.calls();
+        let staging = dir.join("node_modules.staging");
+        assert!(calls[0].starts_with(
+            "diskutil image create blank --fs Case-sensitive APFS --format ASIF --size 10G"
+        ));
+        assert!(calls[1].contains(&format!("--mountPoint {} ", staging.display())));
+        assert_eq!(
+            calls[2],
+            format!(
+                "ditto {} {}",
+                dir.join("node_modules").display(),
+                staging.display()
+            )
+        );
+        assert!(calls[3].contains(&format!("unmount {}", staging.display())));
+    }
+
+    #[test]
+    fn test_pack_managed_encrypted() {
+        let mut entry = Entry::new("/p/node_modules", "/p/node_modules@main.asif", "10G");
+        entry.passphrase_from = Some(PassphraseSource::Keychain);
+        entry.compressed = true;
+        let mut state = state(true, false);
+        state.image = entry.image.clone();
+        state.entry = Some(entry);
+        let mut policy = CompressionPolicy::new(Algorithm::Lzfse);
+        policy.decompress_on_attach = true;
+        policy.schedule = Schedule::OnDetach;
+
+        let plan = Plan::pack(&state, &Settings::default(), &policy);
+        assert_eq!(
+            kinds(&plan),
+            ["decompress", "passphrase", "attach", "register"]
+        );
+        assert_eq!(
+            plan.steps[2],
+            Step::Attach {
+                image: PathBuf::from("/p/node_modules@main.asif"),
+                mount_point: PathBuf::from("/p/node_modules"),
+                shadow: None,
+                encrypted: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_detach_entry() {
+        let mut entry = Entry::new("/p/node_modules", "/p/node_modules.asif", "10G");
+        entry.compression =
+            CompressionPolicy::new(Algorithm::Lzfse).with_schedule(Schedule::OnDetach);
+        let options = DetachOptions::new().with_retries(0).force();
+        let plan = Plan::detach(&entry, &options);
+        assert_eq!(kinds(&plan), ["detach-entry", "compress"]);
+        assert_eq!(
+            plan.steps[0].to_string(),
+            "detach /p/node_modules (0 retries, then force)"
+        );
+        entry.compressed = true;
+        let plan = Plan::idle_detach(&entry);
+        assert!(matches!(
+            plan.steps[..],
+            [Step::DetachEntry { idle: true, .. }]
+        ));
+
+        let json = serde_json::to_string(&Step::DetachEntry {
+            afdir: entry.afdir.clone(),
+            retries: 3,
+            signal: Some(Signal::Term),
+            force: false,
+            idle: false,
+        })
+        .unwrap();
+        assert!(json.contains(r#""signal":"term""#), "{}", json);
+    }
+
+    #[test]
+    fn test_run_snapshot_and_restore() {
+        let dir = TempDir::new("plan-snap");
+        let image = dir.join("node_modules.asif");
+        std::fs::write(&image, "v1").unwrap();
+        let registry = dir.join("registry.json");
+        let mut entry = Entry::new(dir.join("node_modules"), &image, "10G");
+        entry.attached = true;
+        let mut registry_file = Registry::default();
+        registry_file.upsert(entry.clone());
+        registry_file.save_to(&registry).unwrap();
+
+        let runner = runner();
+        let mut plan = Plan::idle_detach(&entry);
+        plan.push(Step::Snapshot {
+            image: image.clone(),
+            label: Some("before".into()),
+            keep: 5,
+        });
+        plan.run_with(&runner, &registry).unwrap();
+        assert!(runner.calls()[0].contains("unmount"));
+        let detached = Registry::load_from(&registry).unwrap();
+        let detached = detached.get(&entry.afdir).unwrap();
+        assert!(!detached.attached && detached.idle_detached);
+
+        let snapshot = SnapshotStore::open(&image)
+            .unwrap()
+            .find(Some("before"))
+            .unwrap()
+            .id
+            .clone();
+        std::fs::write(&image, "v2").unwrap();
+        let mut plan = Plan {
+            steps: vec![Step::Restore {
+                image: image.clone(),
+                snapshot,
+            }],
+        };
+        plan.extend(Plan::attach(&entry));
+        plan.push(Step::Register {
+            entry: entry.clone(),
+        });
+        plan.run_with(&runner, &registry).unwrap();
+        assert_eq!(std::fs::read_to_string(&image).unwrap(), "v1");
+        assert!(runner.last().unwrap().contains("attach"));
+        assert!(
+            Registry::load_from(&registry)
+                .unwrap()
+                .get(&entry.afdir)
+                .unwrap()
+                .attached
+        );
+
+        let missing = Plan {
+            steps: vec![Step::Restore {
+                image: image.clone(),
+                snapshot: "nope".into(),
+            }],
+        };
+        assert!(missing.run_with(&runner, &registry).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let settings = Settings::default();
+        let plan = Plan::pack(
+            &state(false, false),
+            &settings,
+            &CompressionPolicy::default(),
+        );
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(Plan::parse(&json).unwrap(), plan);
+
+        let lines = [
+            Record::new(Event::info("packing")),
+            Record::new(Event::result("plan", &plan)),
+            Record::new(Event::result("pack", serde_json::Value::Null)),
+        ]
+        .iter()
+        .map(|record| serde_json::to_string(record).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+        assert_eq!(Plan::parse(&lines).unwrap(), plan);
+        assert!(Plan::parse(r#"{"steps":[{"step":"reboot"}]}"#).is_err());
+    }
+
+    #[test]
+    fn test_compress_marks_only_compressed_images() {
+        use applesauce::progress::Progress;
+
+        let dir = TempDir::new("plan-compress");
+        let image = dir.join("node_modules.asif");
+        std::fs::write(&image, "").unwrap();
+        let registry = dir.join("registry.json");
+        let mut entries = Registry::default();
+        entries.upsert(Entry::new(dir.join("node_modules"), &image, "10G"));
+        entries.save_to(&registry).unwrap();
+
+        // Nothing gets compressed without an algorithm
+        let plan = Plan {
+            steps: vec![Step::Compress {
+                path: image.clone(),
+                policy: CompressionPolicy::default(),
+            }],
+        };
+        plan.run_with(&runner(), &registry).unwrap();
+        assert!(!Registry::load_from(&registry).unwrap().entries[0].compressed);
+
+        let progress = ProgressReporter::hidden();
+        assert!(check_compression(&image, &progress).is_ok());
+        progress.error(&image, "No space left on device");
+        let err = check_compression(&image, &progress).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NoSpace);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Could not compress {0}, {0}: No space left on device",
+                image.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_run_encrypted_blank() {
+        let dir = TempDir::new("plan");
+        let settings = Settings {
+            encryption: Some(Encryption::AES256),
+            ..Settings::default()
+        };
+        let mut plan = Plan::create_image(
+            &dir.join("target"),
+            false,
+            &dir.join("target.asif"),
+            "20G",
+            &settings,
+        );
+        plan.push(Step::Attach {
+            image: dir.join("target.asif"),
+            mount_point: dir.join("target"),
+            shadow: None,
+            encrypted: true,
+        });
+
+        let runner = runner();
+        plan.run_with(&runner, &dir.join("registry.json")).unwrap();
+        let calls = runner.calls();
+        assert!(calls[0].starts_with("security find-generic-password"));
+        assert!(
+            calls[1].starts_with("diskutil image create blank --fs apfs --format ASIF --size 20G")
+        );
+        assert_eq!(runner.stdin()[1].as_deref(), Some("s3cret"));
+        assert!(calls[2].starts_with("hdiutil attach -mountpoint"));
+    }
+
+    #[test]
+    fn test_run_needs_passphrase() {
+        let plan = Plan {
+            steps: vec![Step::Resize {
+                image: PathBuf::from("/p/target.asif"),
+                size: "20G".into(),
+                encrypted: true,
+            }],
+        };
+        let runner = runner();
+        assert!(matches!(
+            plan.run_with(&runner, Path::new("/nonexistent/registry.json")),
+            Err(DiskImageError::Plan(_))
+        ));
+        assert!(runner.calls().is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+use crate::config::{Config, Source};
+use crate::diskimage::{self, DiskImageError, FileSystem, Result};
+use crate::registry::Entry;
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+/// `[policy]` section of a project `.afpack.toml`
+///
+/// Only honoured in the project file, so a user config cannot unlock itself.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Config keys that only the project file may set, e.g. `filesystem`
+    pub locked: Vec<String>,
+    /// Smallest `maxsize` any managed directory may use
+    pub min_maxsize: Option<String>,
+}
+
+/// A setting or managed directory that does not comply with the policy
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub subject: String,
+    pub key: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} is {}, policy requires {}",
+            self.subject, self.key, self.actual, self.expected
+        )
+    }
+}
+
+impl Policy {
+    /// Overrides of locked keys and sizes below the minimum
+    pub fn config_violations(&self, config: &Config) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for key in &self.locked {
+            let Some(expected) = locked_value(config, key) else {
+                continue;
+            };
+            for (value, source) in config.assignments(key) {
+                if matches!(source, Source::User(_) | Source::Env(_) | Source::Cli)
+                    && !same_value(key, value, expected)
+                {
+                    violations.push(Violation {
+                        subject: source.to_string(),
+                        key: key.clone(),
+                        expected: expected.to_string(),
+                        actual: value.to_string(),
+                    });
+                }
+            }
+        }
+
+        if let Ok(settings) = config.settings_unchecked() {
+            let sizes = std::iter::once(("maxsize".to_string(), settings.maxsize.clone())).chain(
+                settings.dirs.iter().filter_map(|dir| {
+                    dir.maxsize()
+                        .map(|size| (format!("{} maxsize", dir.path()), size.to_string()))
+                }),
+            );
+            for (key, size) in sizes {
+                if let Some(violation) = self.check_size("config", &key, &size) {
+                    violations.push(violation);
+                }
+            }
+        }
+        violations
+    }
+
+    /// Differences between a managed directory and the locked settings
+    ///
+    /// `filesystem` is that of the attached volume, as the registry cannot tell
+    /// it for adopted images; a locked filesystem is reported as unknown without
+    /// it. Other keys the registry does not know for the entry are skipped.
+    pub fn entry_violations(
+        &self,
+        config: &Config,
+        entry: &Entry,
+        dir: &str,
+        filesystem: Option<&FileSystem>,
+    ) -> Vec<Violation> {
+        let subject = entry.afdir.display().to_string();
+        let mut violations = Vec::new();
+        let mut actual = entry_values(entry);
+        if let Some(Ok(filesystem)) = filesystem.map(Value::try_from) {
+            actual.insert("filesystem".into(), filesystem);
+        }
+        for key in &self.locked {
+            let Some(actual) = actual.get(key) else {
+                if key == "filesystem" {
+                    if let Some(expected) = config.get(key) {
+                        violations.push(Violation {
+                            subject: subject.clone(),
+                            key: key.clone(),
+                            expected: expected.to_string(),
+                            actual: "unknown (attach the image to check)".to_string(),
+                        });
+                    }
+                }
+                continue;
+            };
+            let expected = match key.as_str() {
+                "maxsize" => match config.settings_unchecked() {
+                    Ok(settings) => Value::String(settings.maxsize_for(dir).to_string()),
+                    Err(_) => continue,
+                },
+                _ => match config.get(key) {
+                    Some(value) => value.clone(),
+                    None => continue,
+                },
+            };
+            if !same_value(key, actual, &expected) {
+                violations.push(Violation {
+                    subject: subject.clone(),
+                    key: key.clone(),
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                });
+            }
+        }
+        if let Some(violation) = self.check_size(&subject, "maxsize", &entry.maxsize) {
+            violations.push(violation);
+        }
+        violations
+    }
+
+    fn check_size(&self, subject: &str, key: &str, size: &str) -> Option<Violation> {
+        let min = self.min_maxsize.as_deref()?;
+        let too_small = match (diskimage::parse_size(size), diskimage::parse_size(min)) {
+            (Some(size), Some(min)) => size < min,
+            _ => true,
+        };
+        too_small.then(|| Violation {
+            subject: subject.to_string(),
+            key: key.to_string(),
+            expected: format!(">= {}", min),
+            actual: size.to_string(),
+        })
+    }
+}
+
+/// Refuse a config whose layers conflict with its policy
+pub fn enforce(config: &Config) -> Result<()> {
+    let Some(policy) = config.policy() else {
+        return Ok(());
+    };
+    let violations = policy.config_violations(config);
+    if violations.is_empty() {
+        return Ok(());
+    }
+    let messages: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+    Err(DiskImageError::Policy(messages.join("; ")))
+}
+
+/// Whether `key` has the same setting in both, comparing sizes by byte count
+fn same_value(key: &str, a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) if key == "maxsize" => {
+            diskimage::parse_size(a) == diskimage::parse_size(b)
+        }
+        _ => a == b,
+    }
+}
+
+/// Value a locked key must have: the project's, or the default if it sets none
+fn locked_value<'a>(config: &'a Config, key: &'a str) -> Option<&'a Value> {
+    let assignments: Vec<(&Value, &Source)> = config.assignments(key).collect();
+    assignments
+        .iter()
+        .rev()
+        .find(|(_, source)| matches!(source, Source::Project(_)))
+        .or_else(|| {
+            assignments
+                .iter()
+                .find(|(_, source)| *source == &Source::Default)
+        })
+        .map(|(value, _)| *value)
+}
+
+/// Settings recorded for an entry, keyed like the config
+fn entry_values(entry: &Entry) -> Table {
+    let mut values = Table::new();
+    values.insert("maxsize".into(), Value::String(entry.maxsize.clone()));
+    if let Some(Ok(format)) = entry.format.as_ref().map(Value::try_from) {
+        values.insert("format".into(), format);
+    }
+    if let Some(Ok(encryption)) = entry.encryption.as_ref().map(Value::try_from) {
+        values.insert("encryption".into(), encryption);
+    }
+    if let Ok(Value::Table(compression)) = Value::try_from(&entry.compression) {
+        for (key, value) in compression {
+            values.insert(format!("compression.{}", key), value);
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diskimage::FileSystem;
+    use std::path::PathBuf;
+
+    fn project_config(toml: &str) -> Config {
+        let mut config = Config::new();
+        let table: Table = toml::from_str(toml).unwrap();
+        config
+            .merge_table(table, &Source::Project(PathBuf::from("/repo/.afpack.toml")))
+            .unwrap();
+        config
+    }
+
+    #[test]
+    fn test_locked_override_is_refused() {
+        let mut config = project_config(
+            r#"
+maxsize = "20G"
+[policy]
+locked = ["maxsize", "filesystem"]
+min_maxsize = "20G"
+"#,
+        );
+        assert!(enforce(&config).is_ok());
+        // The same size in other units is no override
+        config.set("maxsize", "20GB", Source::Cli);
+        assert!(enforce(&config).is_ok());
+
+        config.set("maxsize", "30G", Source::Cli);
+        config.merge_env([("AFPACK_FILESYSTEM".to_string(), "exfat".to_string())]);
+        let Err(DiskImageError::Policy(message)) = enforce(&config) else {
+            panic!("expected a policy violation");
+        };
+        assert!(message.contains("command line: maxsize is \"30G\", policy requires \"20G\""));
+        assert!(message.contains("$AFPACK_FILESYSTEM: filesystem is \"exfat\""));
+    }
+
+    #[test]
+    fn test_min_maxsize() {
+        let config = project_config(
+            r#"
+dirs = [{ path = "node_modules", maxsize = "5G" }]
+[policy]
+min_maxsize = "20G"
+"#,
+        );
+        let violations = config.policy().unwrap().config_violations(&config);
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[1].key, "node_modules maxsize");
+    }
+
+    #[test]
+    fn test_entry_violations() {
+        let config = project_config(
+            r#"
+maxsize = "20G"
+filesystem = "apfs"
+[compression]
+algorithm = "lzfse"
+[policy]
+locked = ["maxsize", "filesystem", "compression.algorithm", "format"]
+"#,
+        );
+        let policy = config.policy().unwrap();
+
+        let mut entry = Entry::new("/repo/node_modules", "/repo/node_modules.asif", "20GB");
+        // The registry's record is not trusted, the volume's filesystem is
+        entry.filesystem = Some(FileSystem::APFS);
+        let violations = policy.entry_violations(
+            &config,
+            &entry,
+            "node_modules",
+            Some(&FileSystem::APFSCaseSensitive),
+        );
+        let keys: Vec<&str> = violations.iter().map(|v| v.key.as_str()).collect();
+        // format is unknown for this entry and maxsize matches in other units
+        assert_eq!(keys, vec!["filesystem", "compression.algorithm"]);
+        assert_eq!(violations[0].actual, "\"apfs-case-sensitive\"");
+
+        let violations = policy.entry_violations(&config, &entry, "node_modules", None);
+        assert_eq!(
+            violations[0].to_string(),
+            "/repo/node_modules: filesystem is unknown (attach the image to check), \
+             policy requires \"apfs\""
+        );
+    }
+
+    #[test]
+    fn test_unknown_locked_key() {
+        let mut config = Config::new();
+        let table: Table = toml::from_str("[policy]\nlocked = [\"filesytem\"]").unwrap();
+        let Err(DiskImageError::Config(message)) =
+            config.merge_table(table, &Source::Project(PathBuf::from("/repo/.afpack.toml")))
+        else {
+            panic!("expected a config error");
+        };
+        assert_eq!(
+            message,
+            "[policy]: locked key \"filesytem\" is not a config key"
+        );
+    }
+
+    #[test]
+    fn test_policy_only_in_project() {
+        let mut config = Config::new();
+        let table: Table = toml::from_str("[policy]\nlocked = [\"maxsize\"]").unwrap();
+        assert!(config
+            .merge_table(table, &Source::User(PathBuf::from("config.toml")))
+            .is_err());
+    }
+}
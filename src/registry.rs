@@ -0,0 +1,319 @@
+use crate::compression::CompressionPolicy;
+use crate::diskimage::{DiskImageError, Encryption, FileSystem, Format, Result};
+use crate::secret::PassphraseSource;
+use crate::variant::VariantBy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// An artifact directory managed by afpack
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Absolute path of the artifact directory (the mount point)
+    pub afdir: PathBuf,
+    /// Image currently selected for the artifact directory
+    pub image: PathBuf,
+    /// Maximum image size used when creating new images
+    pub maxsize: String,
+    /// Image format the image was created with, unknown for adopted images
+    #[serde(default)]
+    pub format: Option<Format>,
+    /// Filesystem the image was created with, unknown for adopted images
+    #[serde(default)]
+    pub filesystem: Option<FileSystem>,
+    /// Encryption the image was created with
+    #[serde(default)]
+    pub encryption: Option<Encryption>,
+    /// Where the passphrase comes from, set for encrypted images
+    #[serde(default)]
+    pub passphrase_from: Option<PassphraseSource>,
+    /// Whether the image is believed to be attached at `afdir`
+    #[serde(default)]
+    pub attached: bool,
+    /// What selects the image, `None` for a single image
+    #[serde(default)]
+    pub variant_by: Option<VariantBy>,
+    /// Key of the currently selected variant
+    #[serde(default)]
+    pub variant: Option<String>,
+    /// Known variants by key
+    #[serde(default)]
+    pub variants: BTreeMap<String, VariantRecord>,
+    /// Private shadow file when `image` is a shared read-only base
+    #[serde(default)]
+    pub shadow: Option<PathBuf>,
+    /// Compression applied to the image
+    #[serde(default)]
+    pub compression: CompressionPolicy,
+    /// Whether the detached image file is compressed
+    ///
+    /// Cleared on every read-write attach, since writes decompress it.
+    #[serde(default)]
+    pub compressed: bool,
+    /// Seconds without open files after which the image is detached
+    #[serde(default)]
+    pub detach_after: Option<u64>,
+    /// When files on the volume were last seen open (or it was attached), Unix seconds
+    #[serde(default)]
+    pub last_used: Option<u64>,
+    /// Detached for being idle, reattached the next time afpack runs in the project
+    #[serde(default)]
+    pub idle_detached: bool,
+}
+
+/// An image created for one variant of an artifact directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VariantRecord {
+    /// Branch name or lockfile hash the variant was created for
+    pub label: String,
+    pub image: PathBuf,
+    /// Whether the image file is compressed, kept while another variant is attached
+    #[serde(default)]
+    pub compressed: bool,
+}
+
+impl Entry {
+    pub fn new(afdir: impl Into<PathBuf>, image: impl Into<PathBuf>, maxsize: &str) -> Self {
+        Self {
+            afdir: afdir.into(),
+            image: image.into(),
+            maxsize: maxsize.to_string(),
+            format: None,
+            filesystem: None,
+            encryption: None,
+            passphrase_from: None,
+            attached: false,
+            variant_by: None,
+            variant: None,
+            variants: BTreeMap::new(),
+            shadow: None,
+            compression: CompressionPolicy::default(),
+            compressed: false,
+            detach_after: None,
+            last_used: None,
+            idle_detached: false,
+        }
+    }
+
+    /// Record whether the volume is `busy` at `now`, true if it has been idle for `detach_after`
+    pub fn idle_detach_due(&mut self, busy: bool, now: u64) -> bool {
+        let Some(after) = self.detach_after else {
+            return false;
+        };
+        if busy || self.last_used.is_none() {
+            self.last_used = Some(now);
+            return false;
+        }
+        now.saturating_sub(self.last_used.unwrap_or(now)) >= after
+    }
+
+    /// Take the compression state `recorded` has for the images of the variants
+    ///
+    /// Compress and Decompress steps record what they did as they run, which a
+    /// copy of the entry made before them cannot know.
+    pub fn keep_compressed_from(&mut self, recorded: &Entry) {
+        let state = |image: &Path| {
+            recorded
+                .variants
+                .values()
+                .find(|v| v.image == image)
+                .map(|v| v.compressed)
+                .or((recorded.image == image).then_some(recorded.compressed))
+        };
+        for record in self.variants.values_mut() {
+            if let Some(compressed) = state(&record.image) {
+                record.compressed = compressed;
+            }
+        }
+    }
+
+    /// Mark the entry attached by the user or afpack, not idle
+    pub fn mark_attached(&mut self, now: u64) {
+        self.attached = true;
+        self.compressed = false;
+        self.idle_detached = false;
+        self.last_used = Some(now);
+    }
+}
+
+/// Persistent list of managed artifact directories
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Registry {
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+}
+
+impl Registry {
+    /// Default registry location inside the state directory
+    pub fn default_path() -> PathBuf {
+        state_dir().join("registry.json")
+    }
+
+    /// Load the registry from its default location
+    pub fn load() -> Result<Self> {
+        Self::load_from(Self::default_path())
+    }
+
+    /// Save the registry to its default location
+    pub fn save(&self) -> Result<()> {
+        self.save_to(Self::default_path())
+    }
+
+    /// Load a registry file, a missing file is an empty registry
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = std::fs::read_to_string(path)?;
+        serde_json::from_str(&data)
+            .map_err(|e| DiskImageError::Registry(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Atomically write a registry file
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string_pretty(self)
+            .map_err(|e| DiskImageError::Registry(e.to_string()))?;
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, afdir: P) -> Option<&Entry> {
+        let afdir = afdir.as_ref();
+        self.entries.iter().find(|e| e.afdir == afdir)
+    }
+
+    pub fn get_mut<P: AsRef<Path>>(&mut self, afdir: P) -> Option<&mut Entry> {
+        let afdir = afdir.as_ref();
+        self.entries.iter_mut().find(|e| e.afdir == afdir)
+    }
+
+    /// Remove the entry of an artifact directory
+    pub fn remove<P: AsRef<Path>>(&mut self, afdir: P) -> Option<Entry> {
+        let afdir = afdir.as_ref();
+        let index = self.entries.iter().position(|e| e.afdir == afdir)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Insert an entry, replacing the one for the same afdir
+    pub fn upsert(&mut self, entry: Entry) {
+        match self.get_mut(&entry.afdir) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+}
+
+/// Directory holding afpack state (registry, logs)
+///
+/// `$AFPACK_STATE_DIR`, else `$XDG_STATE_HOME/afpack`, else `~/.local/state/afpack`.
+pub fn state_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("AFPACK_STATE_DIR") {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = std::env::var_os("XDG_STATE_HOME") {
+        return PathBuf::from(dir).join("afpack");
+    }
+    let home = std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    home.join(".local").join("state").join("afpack")
+}
+
+/// Absolute form of an artifact directory, used as the registry key
+pub fn absolute<P: AsRef<Path>>(afdir: P) -> Result<PathBuf> {
+    Ok(std::path::absolute(afdir.as_ref())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = TempDir::new("registry");
+        let path = dir.join("registry.json");
+
+        let mut registry = Registry::load_from(&path).unwrap();
+        assert!(registry.entries.is_empty());
+
+        let mut entry = Entry::new("/p/node_modules", "/p/node_modules.asif", "10G");
+        entry.variant_by = Some(VariantBy::Branch);
+        entry.compressed = true;
+        entry.variants.insert(
+            "main".into(),
+            VariantRecord {
+                label: "main".into(),
+                image: "/p/node_modules.asif".into(),
+                compressed: false,
+            },
+        );
+        registry.upsert(entry.clone());
+        registry.save_to(&path).unwrap();
+
+        let loaded = Registry::load_from(&path).unwrap();
+        assert_eq!(loaded.get("/p/node_modules"), Some(&entry));
+
+        // Records from before per-variant compression read as uncompressed
+        let old: VariantRecord =
+            serde_json::from_str(r#"{"label": "main", "image": "/p/nm@main.asif"}"#).unwrap();
+        assert!(!old.compressed);
+    }
+
+    #[test]
+    fn test_upsert_replaces() {
+        let mut registry = Registry::default();
+        registry.upsert(Entry::new("/p/target", "/p/target.asif", "10G"));
+        registry.upsert(Entry::new("/p/target", "/p/target@main.asif", "20G"));
+        assert_eq!(registry.entries.len(), 1);
+        assert_eq!(registry.entries[0].maxsize, "20G");
+    }
+
+    #[test]
+    fn test_keep_compressed_from() {
+        let record = |image: &str, compressed| VariantRecord {
+            label: image.into(),
+            image: image.into(),
+            compressed,
+        };
+        let mut recorded = Entry::new("/p/nm", "/p/nm@main.asif", "10G");
+        recorded.compressed = true;
+        recorded
+            .variants
+            .insert("dev".into(), record("/p/nm@dev.asif", false));
+
+        let mut next = Entry::new("/p/nm", "/p/nm@dev.asif", "10G");
+        next.variants
+            .insert("main".into(), record("/p/nm@main.asif", false));
+        next.variants
+            .insert("dev".into(), record("/p/nm@dev.asif", true));
+        next.variants
+            .insert("new".into(), record("/p/nm@new.asif", true));
+        next.keep_compressed_from(&recorded);
+        assert!(next.variants["main"].compressed);
+        assert!(!next.variants["dev"].compressed);
+        // Not known to the registry yet
+        assert!(next.variants["new"].compressed);
+    }
+
+    #[test]
+    fn test_idle_detach_due() {
+        let mut entry = Entry::new("/p/target", "/p/target.asif", "10G");
+        assert!(!entry.idle_detach_due(false, 1000));
+
+        entry.detach_after = Some(600);
+        // An entry from before idle tracking starts its clock now
+        assert!(!entry.idle_detach_due(false, 1000));
+        assert!(!entry.idle_detach_due(false, 1599));
+        assert!(!entry.idle_detach_due(true, 1599));
+        assert!(!entry.idle_detach_due(false, 2000));
+        assert!(entry.idle_detach_due(false, 2199));
+    }
+}
@@ -0,0 +1,409 @@
+use crate::diskimage::{CommandRunner, DiskImageError, ErrorKind, Result, SystemRunner};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::path::Path;
+
+/// Keychain and Secret Service name passphrases are stored under
+///
+/// There is one item per artifact directory, so its variants and snapshots
+/// share a passphrase.
+pub const KEYCHAIN_SERVICE: &str = "afpack";
+
+/// Folder of the pass store holding one entry per artifact directory
+pub const PASS_PREFIX: &str = "afpack";
+
+/// Passphrase of an encrypted image, kept out of `Debug` output
+#[derive(Clone, PartialEq)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+    pub fn new(passphrase: impl Into<String>) -> Self {
+        Self(passphrase.into())
+    }
+
+    /// Random passphrase for a new image whose secret lives in a store
+    pub fn generate() -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        Ok(Self(bytes.iter().map(|b| format!("{:02x}", b)).collect()))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Passphrase(..)")
+    }
+}
+
+/// Where the passphrase of an encrypted image comes from
+///
+/// Written `"keychain"`, `"secret-service"`, `"pass"`, `"stdin"` or `"env:VAR"`
+/// in the config. The stores work unattended, e.g. from the git hook.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PassphraseSource {
+    /// macOS keychain, generated and stored when the image is created
+    #[default]
+    Keychain,
+    /// Secret Service (GNOME Keyring, KWallet) through `secret-tool`, like the keychain
+    SecretService,
+    /// The `pass` password store, like the keychain
+    Pass,
+    /// First line of standard input
+    Stdin,
+    /// Environment variable
+    Env(String),
+}
+
+impl std::fmt::Display for PassphraseSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PassphraseSource::Keychain => write!(f, "keychain"),
+            PassphraseSource::SecretService => write!(f, "secret-service"),
+            PassphraseSource::Pass => write!(f, "pass"),
+            PassphraseSource::Stdin => write!(f, "stdin"),
+            PassphraseSource::Env(var) => write!(f, "env:{}", var),
+        }
+    }
+}
+
+impl std::str::FromStr for PassphraseSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "keychain" => Ok(PassphraseSource::Keychain),
+            "secret-service" => Ok(PassphraseSource::SecretService),
+            "pass" => Ok(PassphraseSource::Pass),
+            "stdin" => Ok(PassphraseSource::Stdin),
+            _ => match s.strip_prefix("env:") {
+                Some(var) if !var.is_empty() => Ok(PassphraseSource::Env(var.to_string())),
+                _ => Err(format!(
+                    "unknown passphrase source {:?}, expected keychain, secret-service, \
+                     pass, stdin or env:VAR",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for PassphraseSource {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PassphraseSource> for String {
+    fn from(source: PassphraseSource) -> Self {
+        source.to_string()
+    }
+}
+
+impl PassphraseSource {
+    /// Whether new passphrases are generated and stored here
+    pub fn is_store(&self) -> bool {
+        matches!(
+            self,
+            PassphraseSource::Keychain | PassphraseSource::SecretService | PassphraseSource::Pass
+        )
+    }
+
+    /// Passphrase of the images of `afdir`
+    pub fn fetch(&self, afdir: &Path) -> Result<Passphrase> {
+        self.fetch_with(&SystemRunner, afdir)
+    }
+
+    pub fn fetch_with(&self, runner: &dyn CommandRunner, afdir: &Path) -> Result<Passphrase> {
+        match self {
+            PassphraseSource::Keychain
+            | PassphraseSource::SecretService
+            | PassphraseSource::Pass => {
+                let (program, args) = match self {
+                    PassphraseSource::Keychain => ("security", keychain_args(afdir)),
+                    PassphraseSource::SecretService => {
+                        ("secret-tool", secret_tool_args("lookup", afdir))
+                    }
+                    _ => ("pass", vec!["show".into(), pass_entry(afdir)]),
+                };
+                let output = runner.run(program, &args, None).map_err(|e| {
+                    // the store ran but has no item, or could not be started at all
+                    let kind = match e {
+                        DiskImageError::Command(_) => ErrorKind::NotFound,
+                        e => e.kind(),
+                    };
+                    DiskImageError::Passphrase {
+                        message: format!("no passphrase for {} in {}", afdir.display(), self),
+                        kind,
+                    }
+                })?;
+                // pass entries may carry more lines after the password
+                let passphrase = output.lines().next().unwrap_or_default();
+                if passphrase.is_empty() {
+                    return Err(DiskImageError::Passphrase {
+                        message: format!("empty passphrase for {} in {}", afdir.display(), self),
+                        kind: ErrorKind::NotFound,
+                    });
+                }
+                Ok(Passphrase::new(passphrase))
+            }
+            PassphraseSource::Stdin => {
+                if std::io::stdin().is_terminal() {
+                    eprint!("Passphrase for {}: ", afdir.display());
+                    std::io::stderr().flush()?;
+                }
+                let mut line = String::new();
+                std::io::stdin().lock().read_line(&mut line)?;
+                let line = line.trim_end_matches(['\r', '\n']);
+                if line.is_empty() {
+                    return Err(DiskImageError::Passphrase {
+                        message: "empty passphrase on stdin".to_string(),
+                        kind: ErrorKind::NotFound,
+                    });
+                }
+                Ok(Passphrase::new(line))
+            }
+            PassphraseSource::Env(var) => match std::env::var(var) {
+                Ok(value) if !value.is_empty() => Ok(Passphrase::new(value)),
+                _ => Err(DiskImageError::Passphrase {
+                    message: format!("${} is not set", var),
+                    kind: ErrorKind::NotFound,
+                }),
+            },
+        }
+    }
+
+    /// Passphrase for a new image of `afdir`, generated and stored if it lives in a store
+    ///
+    /// The passphrase is handed to the store on stdin, never as an argument.
+    pub fn provision(&self, afdir: &Path) -> Result<Passphrase> {
+        self.provision_with(&SystemRunner, afdir)
+    }
+
+    pub fn provision_with(&self, runner: &dyn CommandRunner, afdir: &Path) -> Result<Passphrase> {
+        if !self.is_store() {
+            return self.fetch_with(runner, afdir);
+        }
+        if let Ok(existing) = self.fetch_with(runner, afdir) {
+            return Ok(existing);
+        }
+        let passphrase = Passphrase::generate()?;
+        match self {
+            PassphraseSource::Keychain => {
+                // add-generic-password only takes the password as an argument, so
+                // the command goes through security's interactive mode on stdin
+                let command = format!(
+                    "add-generic-password -U -s {} -a {} -w {}\n",
+                    security_quote(KEYCHAIN_SERVICE),
+                    security_quote(&afdir.display().to_string()),
+                    security_quote(passphrase.expose())
+                );
+                runner.run("security", &["-i".to_string()], Some(&command))?;
+            }
+            PassphraseSource::SecretService => {
+                let mut args = secret_tool_args("store", afdir);
+                args.insert(1, format!("--label=afpack {}", afdir.display()));
+                runner.run("secret-tool", &args, Some(passphrase.expose()))?;
+            }
+            _ => {
+                let args = vec![
+                    "insert".into(),
+                    "--multiline".into(),
+                    "--force".into(),
+                    pass_entry(afdir),
+                ];
+                runner.run("pass", &args, Some(&format!("{}\n", passphrase.expose())))?;
+            }
+        }
+        // security -i reports failed commands but still exits 0
+        match self.fetch_with(runner, afdir) {
+            Ok(stored) if stored == passphrase => Ok(passphrase),
+            _ => Err(DiskImageError::Passphrase {
+                message: format!(
+                    "could not store the passphrase for {} in {}",
+                    afdir.display(),
+                    self
+                ),
+                kind: ErrorKind::Other,
+            }),
+        }
+    }
+}
+
+/// Arguments reading the keychain item of `afdir`
+fn keychain_args(afdir: &Path) -> Vec<String> {
+    vec![
+        "find-generic-password".into(),
+        "-s".into(),
+        KEYCHAIN_SERVICE.into(),
+        "-a".into(),
+        afdir.display().to_string(),
+        "-w".into(),
+    ]
+}
+
+/// `secret-tool` arguments addressing the item of `afdir`
+fn secret_tool_args(command: &str, afdir: &Path) -> Vec<String> {
+    vec![
+        command.to_string(),
+        "service".into(),
+        KEYCHAIN_SERVICE.into(),
+        "afdir".into(),
+        afdir.display().to_string(),
+    ]
+}
+
+/// Name of the pass entry of `afdir`, e.g. `afpack/Users/me/p/vendor`
+fn pass_entry(afdir: &Path) -> String {
+    format!(
+        "{}/{}",
+        PASS_PREFIX,
+        afdir.display().to_string().trim_start_matches('/')
+    )
+}
+
+/// `s` as one word for security's interactive mode
+fn security_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diskimage::CommandError;
+    use crate::testutil::FakeRunner;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Password store that starts out empty and keeps what it is given
+    fn store() -> (FakeRunner, Rc<RefCell<Option<String>>>) {
+        let stored = Rc::new(RefCell::new(None));
+        let kept = Rc::clone(&stored);
+        let runner = FakeRunner::replying(move |program, args, stdin| match args[0].as_str() {
+            "find-generic-password" | "lookup" | "show" => kept
+                .borrow()
+                .clone()
+                .map(|s| format!("{}\n", s))
+                .ok_or_else(|| CommandError::new(program, args, Some(44), "", "not found").into()),
+            _ => {
+                let stdin = stdin.unwrap_or_default();
+                // security -i gets the whole command, the secret is its last word
+                let secret = match program {
+                    "security" => stdin.trim_end().rsplit(' ').next().unwrap(),
+                    _ => stdin.trim_end(),
+                };
+                *kept.borrow_mut() = Some(secret.trim_matches('"').to_string());
+                Ok(String::new())
+            }
+        });
+        (runner, stored)
+    }
+
+    #[test]
+    fn test_source_roundtrip() {
+        for s in ["keychain", "secret-service", "pass", "stdin", "env:SDK_KEY"] {
+            assert_eq!(s.parse::<PassphraseSource>().unwrap().to_string(), s);
+        }
+        assert!("env:".parse::<PassphraseSource>().is_err());
+        assert!("gpg".parse::<PassphraseSource>().is_err());
+    }
+
+    #[test]
+    fn test_keychain_provision_stores_new_passphrase() {
+        let (keychain, _) = store();
+        let passphrase = PassphraseSource::Keychain
+            .provision_with(&keychain, Path::new("/p/vendor"))
+            .unwrap();
+        assert_eq!(passphrase.expose().len(), 64);
+        assert_eq!(format!("{:?}", passphrase), "Passphrase(..)");
+
+        let calls = keychain.argv();
+        assert_eq!(
+            calls[0],
+            vec![
+                "security",
+                "find-generic-password",
+                "-s",
+                "afpack",
+                "-a",
+                "/p/vendor",
+                "-w"
+            ]
+        );
+        assert_eq!(calls[1], vec!["security", "-i"]);
+        assert_eq!(calls[2], calls[0]);
+        assert!(calls
+            .iter()
+            .flatten()
+            .all(|a| !a.contains(passphrase.expose())));
+        let count = calls.len();
+
+        // the item is reused from then on
+        let again = PassphraseSource::Keychain
+            .provision_with(&keychain, Path::new("/p/vendor"))
+            .unwrap();
+        assert_eq!(again, passphrase);
+        assert_eq!(keychain.calls().len(), count + 1);
+    }
+
+    #[test]
+    fn test_store_provision_keeps_secret_out_of_argv() {
+        for (source, lookup, insert) in [
+            (
+                PassphraseSource::SecretService,
+                "secret-tool lookup service afpack afdir /p/vendor",
+                "secret-tool store --label=afpack /p/vendor service afpack afdir /p/vendor",
+            ),
+            (
+                PassphraseSource::Pass,
+                "pass show afpack/p/vendor",
+                "pass insert --multiline --force afpack/p/vendor",
+            ),
+        ] {
+            let (runner, stored) = store();
+            let passphrase = source
+                .provision_with(&runner, Path::new("/p/vendor"))
+                .unwrap();
+            assert_eq!(runner.calls(), vec![lookup, insert, lookup]);
+            assert_eq!(stored.borrow().as_deref(), Some(passphrase.expose()));
+        }
+    }
+
+    #[test]
+    fn test_provision_fails_when_nothing_was_stored() {
+        let deaf = FakeRunner::new();
+        let err = PassphraseSource::Keychain
+            .provision_with(&deaf, Path::new("/p/vendor"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "could not store the passphrase for /p/vendor in keychain"
+        );
+        assert_eq!(err.kind(), ErrorKind::Other);
+
+        let (empty, _) = store();
+        let err = PassphraseSource::Pass
+            .fetch_with(&empty, Path::new("/p/vendor"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no passphrase for /p/vendor in pass");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_env_source() {
+        std::env::set_var("AFPACK_TEST_SECRET", "hunter2");
+        let source = PassphraseSource::Env("AFPACK_TEST_SECRET".into());
+        let passphrase = source.fetch(Path::new("/p/vendor")).unwrap();
+        assert_eq!(passphrase.expose(), "hunter2");
+        let unset = PassphraseSource::Env("AFPACK_TEST_UNSET".into())
+            .fetch(Path::new("/p/vendor"))
+            .unwrap_err();
+        assert_eq!(unset.kind(), ErrorKind::NotFound);
+    }
+}
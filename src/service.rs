@@ -0,0 +1,653 @@
+use crate::diskimage::{CommandRunner, Result, SystemRunner};
+use crate::output::{self, Event};
+use crate::registry;
+use std::path::{Path, PathBuf};
+
+/// launchd label of the login agent
+pub const LABEL: &str = "afpack.remount";
+
+/// launchd label of the agent running `afpack maintain`
+pub const MAINTAIN_LABEL: &str = "afpack.maintain";
+
+/// Name of the systemd user unit
+pub const UNIT: &str = "afpack-remount.service";
+
+/// Name of the systemd user unit running `afpack maintain`, started by its timer
+pub const MAINTAIN_UNIT: &str = "afpack-maintain.service";
+
+/// Seconds between `afpack maintain` runs, which detach idle images
+pub const MAINTAIN_INTERVAL: u64 = 300;
+
+/// Service running afpack in the user's session
+///
+/// A LaunchAgent on macOS and a systemd user unit everywhere else, see
+/// [`login_services`].
+pub trait LoginService {
+    /// Label or unit name
+    fn name(&self) -> &str;
+    /// Where the definition is written
+    fn path(&self) -> &Path;
+    /// Where the service's output ends up
+    fn log(&self) -> String;
+
+    /// Write the definition and enable it, replacing a previously loaded one
+    fn install(&self, dry_run: bool) -> Result<()> {
+        self.install_with(&SystemRunner, dry_run)
+    }
+
+    fn install_with(&self, runner: &dyn CommandRunner, dry_run: bool) -> Result<()>;
+
+    /// Disable the service and remove its definition
+    fn uninstall(&self, dry_run: bool) -> Result<()> {
+        self.uninstall_with(&SystemRunner, dry_run)
+    }
+
+    fn uninstall_with(&self, runner: &dyn CommandRunner, dry_run: bool) -> Result<()>;
+
+    fn status(&self) -> Result<ServiceStatus> {
+        self.status_with(&SystemRunner)
+    }
+
+    fn status_with(&self, runner: &dyn CommandRunner) -> Result<ServiceStatus>;
+}
+
+/// Services of the running platform: remount at login and periodic maintain
+pub fn login_services() -> Result<Vec<Box<dyn LoginService>>> {
+    if cfg!(target_os = "macos") {
+        Ok(vec![
+            Box::new(LaunchAgent::new()?),
+            Box::new(LaunchAgent::maintain()?),
+        ])
+    } else {
+        Ok(vec![
+            Box::new(SystemdUnit::new()?),
+            Box::new(SystemdUnit::maintain()?),
+        ])
+    }
+}
+
+/// LaunchAgent running afpack at login or every `interval` seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaunchAgent {
+    pub label: String,
+    /// afpack binary the agent runs
+    pub program: PathBuf,
+    pub arguments: Vec<String>,
+    /// Seconds between runs, `None` to run once at login
+    pub interval: Option<u64>,
+    /// Where the plist is written
+    pub path: PathBuf,
+    /// Registry directory, passed on so the agent sees the same registry
+    pub state_dir: PathBuf,
+    /// File receiving the agent's output
+    pub log: PathBuf,
+}
+
+/// Whether the definition exists and whether launchd or systemd has it loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServiceStatus {
+    pub installed: bool,
+    pub loaded: bool,
+}
+
+impl std::fmt::Display for ServiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.installed, self.loaded) {
+            (true, true) => write!(f, "installed, loaded"),
+            (true, false) => write!(f, "installed, not loaded"),
+            (false, true) => write!(f, "not installed, still loaded"),
+            (false, false) => write!(f, "not installed"),
+        }
+    }
+}
+
+impl LaunchAgent {
+    /// Agent running `afpack remount --all` at login
+    pub fn new() -> Result<Self> {
+        Self::running(LABEL, &["remount", "--all"], None, "remount.log")
+    }
+
+    /// Agent running `afpack maintain` every [`MAINTAIN_INTERVAL`] seconds
+    pub fn maintain() -> Result<Self> {
+        Self::running(
+            MAINTAIN_LABEL,
+            &["maintain"],
+            Some(MAINTAIN_INTERVAL),
+            "maintain.log",
+        )
+    }
+
+    /// Agent for the running afpack binary in `~/Library/LaunchAgents`
+    fn running(label: &str, arguments: &[&str], interval: Option<u64>, log: &str) -> Result<Self> {
+        let home = std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        let state_dir = registry::state_dir();
+        Ok(Self {
+            label: label.to_string(),
+            program: std::env::current_exe()?,
+            arguments: arguments.iter().map(|a| a.to_string()).collect(),
+            interval,
+            path: home
+                .join("Library")
+                .join("LaunchAgents")
+                .join(format!("{}.plist", label)),
+            log: state_dir.join(log),
+            state_dir,
+        })
+    }
+
+    /// Property list launchd loads the agent from
+    pub fn plist(&self) -> String {
+        let log = xml_escape(&self.log);
+        let arguments: String = std::iter::once(xml_escape(&self.program))
+            .chain(self.arguments.iter().map(|a| xml_escape(Path::new(a))))
+            .map(|a| format!("\t\t<string>{}</string>\n", a))
+            .collect();
+        let schedule = match self.interval {
+            Some(interval) => format!(
+                "\t<key>StartInterval</key>\n\t<integer>{}</integer>\n",
+                interval
+            ),
+            None => "\t<key>RunAtLoad</key>\n\t<true/>\n".to_string(),
+        };
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Label</key>
+	<string>{}</string>
+	<key>ProgramArguments</key>
+	<array>
+{}	</array>
+	<key>EnvironmentVariables</key>
+	<dict>
+		<key>AFPACK_STATE_DIR</key>
+		<string>{}</string>
+	</dict>
+{}	<key>StandardOutPath</key>
+	<string>{}</string>
+	<key>StandardErrorPath</key>
+	<string>{}</string>
+</dict>
+</plist>
+"#,
+            xml_escape(Path::new(&self.label)),
+            arguments,
+            xml_escape(&self.state_dir),
+            schedule,
+            log,
+            log
+        )
+    }
+
+    fn bootout_args(&self, domain: &str) -> Vec<String> {
+        vec!["bootout".to_string(), format!("{}/{}", domain, self.label)]
+    }
+}
+
+impl LoginService for LaunchAgent {
+    fn name(&self) -> &str {
+        &self.label
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn log(&self) -> String {
+        self.log.display().to_string()
+    }
+
+    fn install_with(&self, runner: &dyn CommandRunner, dry_run: bool) -> Result<()> {
+        if dry_run {
+            output::emit(Event::info(format!(
+                "[DRY RUN] Would write {}",
+                self.path.display()
+            )));
+            output::emit(Event::info(format!(
+                "[DRY RUN] Would load {} with launchctl",
+                self.label
+            )));
+            return Ok(());
+        }
+        std::fs::create_dir_all(self.path.parent().unwrap_or(Path::new(".")))?;
+        std::fs::create_dir_all(&self.state_dir)?;
+        std::fs::write(&self.path, self.plist())?;
+
+        let domain = gui_domain(runner)?;
+        // bootstrap fails if the label is already loaded
+        let _ = runner.run("launchctl", &self.bootout_args(&domain), None);
+        runner.run(
+            "launchctl",
+            &[
+                "bootstrap".to_string(),
+                domain,
+                self.path.display().to_string(),
+            ],
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn uninstall_with(&self, runner: &dyn CommandRunner, dry_run: bool) -> Result<()> {
+        if dry_run {
+            output::emit(Event::info(format!(
+                "[DRY RUN] Would unload {} with launchctl",
+                self.label
+            )));
+            output::emit(Event::info(format!(
+                "[DRY RUN] Would remove {}",
+                self.path.display()
+            )));
+            return Ok(());
+        }
+        let domain = gui_domain(runner)?;
+        let _ = runner.run("launchctl", &self.bootout_args(&domain), None);
+        if self.path.exists() {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn status_with(&self, runner: &dyn CommandRunner) -> Result<ServiceStatus> {
+        let domain = gui_domain(runner)?;
+        let target = format!("{}/{}", domain, self.label);
+        Ok(ServiceStatus {
+            installed: self.path.exists(),
+            loaded: runner
+                .run("launchctl", &["print".to_string(), target], None)
+                .is_ok(),
+        })
+    }
+}
+
+/// systemd user unit running afpack at login, or every `interval` seconds
+/// from a timer next to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemdUnit {
+    pub name: String,
+    pub description: String,
+    /// afpack binary the unit runs
+    pub program: PathBuf,
+    pub arguments: Vec<String>,
+    /// Seconds between runs, `None` to run once at login
+    pub interval: Option<u64>,
+    /// Where the unit file is written
+    pub path: PathBuf,
+    /// Registry directory, passed on so the unit sees the same registry
+    pub state_dir: PathBuf,
+}
+
+impl SystemdUnit {
+    /// Unit running `afpack remount --all` at login
+    pub fn new() -> Result<Self> {
+        Self::running(
+            UNIT,
+            "Remount afpack disk images",
+            &["remount", "--all"],
+            None,
+        )
+    }
+
+    /// Unit running `afpack maintain` every [`MAINTAIN_INTERVAL`] seconds
+    pub fn maintain() -> Result<Self> {
+        Self::running(
+            MAINTAIN_UNIT,
+            "Detach idle afpack disk images",
+            &["maintain"],
+            Some(MAINTAIN_INTERVAL),
+        )
+    }
+
+    /// Unit for the running afpack binary in `$XDG_CONFIG_HOME/systemd/user`
+    fn running(
+        name: &str,
+        description: &str,
+        arguments: &[&str],
+        interval: Option<u64>,
+    ) -> Result<Self> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                std::env::var_os("HOME")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(std::env::temp_dir)
+                    .join(".config")
+            });
+        Ok(Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            program: std::env::current_exe()?,
+            arguments: arguments.iter().map(|a| a.to_string()).collect(),
+            interval,
+            path: config_dir.join("systemd").join("user").join(name),
+            state_dir: registry::state_dir(),
+        })
+    }
+
+    /// Unit file systemd loads the service from
+    ///
+    /// A login unit keeps its oneshot active after it ran with `RemainAfterExit`,
+    /// so `is-active` tells whether this session was remounted. A timed unit is
+    /// installed through its timer instead.
+    pub fn unit(&self) -> String {
+        let mut exec = systemd_quote(&self.program.display().to_string());
+        for argument in &self.arguments {
+            exec.push(' ');
+            exec.push_str(argument);
+        }
+        let mut unit = format!(
+            "[Unit]\nDescription={}\n\n[Service]\nType=oneshot\n",
+            self.description
+        );
+        if self.interval.is_none() {
+            unit.push_str("RemainAfterExit=yes\n");
+        }
+        unit.push_str(&format!(
+            "Environment={}\n",
+            systemd_quote(&format!("AFPACK_STATE_DIR={}", self.state_dir.display()))
+        ));
+        // ExecStart expands $VAR, Environment does not
+        unit.push_str(&format!("ExecStart={}\n", exec.replace('$', "$$")));
+        if self.interval.is_none() {
+            unit.push_str("\n[Install]\nWantedBy=default.target\n");
+        }
+        unit
+    }
+
+    /// Timer starting a timed unit every `interval` seconds
+    pub fn timer(&self) -> Option<String> {
+        self.interval.map(|interval| {
+            format!(
+                "[Unit]\nDescription={}\n\n[Timer]\nOnStartupSec={}\nOnUnitActiveSec={}\n\n\
+                 [Install]\nWantedBy=timers.target\n",
+                self.description, interval, interval
+            )
+        })
+    }
+
+    /// Where the timer is written, next to the unit
+    pub fn timer_path(&self) -> PathBuf {
+        self.path.with_extension("timer")
+    }
+
+    /// Unit systemctl enables: the timer of a timed unit, else the unit itself
+    fn enabled_unit(&self) -> String {
+        match self.interval {
+            Some(_) => Path::new(&self.name)
+                .with_extension("timer")
+                .display()
+                .to_string(),
+            None => self.name.clone(),
+        }
+    }
+
+    fn systemctl(&self, runner: &dyn CommandRunner, args: &[&str]) -> Result<String> {
+        let mut argv = vec!["--user".to_string()];
+        argv.extend(args.iter().map(|a| a.to_string()));
+        runner.run("systemctl", &argv, None)
+    }
+}
+
+impl LoginService for SystemdUnit {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn log(&self) -> String {
+        format!("journalctl --user -u {}", self.name)
+    }
+
+    fn install_with(&self, runner: &dyn CommandRunner, dry_run: bool) -> Result<()> {
+        if dry_run {
+            output::emit(Event::info(format!(
+                "[DRY RUN] Would write {}",
+                self.path.display()
+            )));
+            output::emit(Event::info(format!(
+                "[DRY RUN] Would enable {} with systemctl --user",
+                self.enabled_unit()
+            )));
+            return Ok(());
+        }
+        std::fs::create_dir_all(self.path.parent().unwrap_or(Path::new(".")))?;
+        std::fs::create_dir_all(&self.state_dir)?;
+        std::fs::write(&self.path, self.unit())?;
+        if let Some(timer) = self.timer() {
+            std::fs::write(self.timer_path(), timer)?;
+        }
+
+        self.systemctl(runner, &["daemon-reload"])?;
+        // --now runs it right away, like RunAtLoad does for the agent
+        self.systemctl(runner, &["enable", "--now", &self.enabled_unit()])?;
+        Ok(())
+    }
+
+    fn uninstall_with(&self, runner: &dyn CommandRunner, dry_run: bool) -> Result<()> {
+        if dry_run {
+            output::emit(Event::info(format!(
+                "[DRY RUN] Would disable {} with systemctl --user",
+                self.enabled_unit()
+            )));
+            output::emit(Event::info(format!(
+                "[DRY RUN] Would remove {}",
+                self.path.display()
+            )));
+            return Ok(());
+        }
+        let _ = self.systemctl(runner, &["disable", "--now", &self.enabled_unit()]);
+        for path in [self.path.clone(), self.timer_path()] {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        self.systemctl(runner, &["daemon-reload"])?;
+        Ok(())
+    }
+
+    fn status_with(&self, runner: &dyn CommandRunner) -> Result<ServiceStatus> {
+        // is-active exits non-zero for anything but "active"
+        Ok(ServiceStatus {
+            installed: self.path.exists(),
+            loaded: self
+                .systemctl(runner, &["is-active", "--quiet", &self.enabled_unit()])
+                .is_ok(),
+        })
+    }
+}
+
+/// launchd domain of the current user's login session, `gui/<uid>`
+fn gui_domain(runner: &dyn CommandRunner) -> Result<String> {
+    let uid = runner.run("id", &["-u".to_string()], None)?;
+    Ok(format!("gui/{}", uid.trim()))
+}
+
+/// `s` as one word of a unit file setting, with specifiers escaped
+fn systemd_quote(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%")
+    )
+}
+
+fn xml_escape(path: &Path) -> String {
+    path.display()
+        .to_string()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{FakeRunner, TempDir};
+
+    /// Records every command, answering `id -u`
+    fn runner() -> FakeRunner {
+        FakeRunner::replying(|program, _, _| {
+            Ok(if program == "id" { "501\n" } else { "" }.to_string())
+        })
+    }
+
+    fn agent(dir: &Path) -> LaunchAgent {
+        LaunchAgent {
+            label: LABEL.to_string(),
+            program: PathBuf::from("/opt/R&D/bin/afpack"),
+            arguments: vec!["remount".into(), "--all".into()],
+            interval: None,
+            path: dir.join("LaunchAgents").join("afpack.remount.plist"),
+            state_dir: dir.join("state"),
+            log: dir.join("state").join("remount.log"),
+        }
+    }
+
+    fn unit(dir: &Path) -> SystemdUnit {
+        SystemdUnit {
+            name: UNIT.to_string(),
+            description: "Remount afpack disk images".to_string(),
+            program: PathBuf::from("/opt/100% \"R&D\"/bin/afpack"),
+            arguments: vec!["remount".into(), "--all".into()],
+            interval: None,
+            path: dir.join("systemd").join("user").join(UNIT),
+            state_dir: dir.join("state"),
+        }
+    }
+
+    #[test]
+    fn test_plist() {
+        let plist = agent(Path::new("/Users/me")).plist();
+        assert!(plist.contains("<string>afpack.remount</string>"));
+        assert!(plist.contains(
+            "<string>/opt/R&amp;D/bin/afpack</string>\n\t\t<string>remount</string>\n\t\t<string>--all</string>"
+        ));
+        assert!(plist.contains("<key>RunAtLoad</key>\n\t<true/>"));
+        assert!(plist.contains("<string>/Users/me/state/remount.log</string>"));
+
+        let maintain = LaunchAgent {
+            label: MAINTAIN_LABEL.to_string(),
+            arguments: vec!["maintain".into()],
+            interval: Some(300),
+            ..agent(Path::new("/Users/me"))
+        }
+        .plist();
+        assert!(maintain.contains(
+            "<string>/opt/R&amp;D/bin/afpack</string>\n\t\t<string>maintain</string>\n\t</array>"
+        ));
+        assert!(maintain.contains("<key>StartInterval</key>\n\t<integer>300</integer>"));
+        assert!(!maintain.contains("RunAtLoad"));
+    }
+
+    #[test]
+    fn test_install_and_uninstall() {
+        let dir = TempDir::new("service");
+        let agent = agent(&dir);
+        let launchctl = runner();
+
+        agent.install_with(&launchctl, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&agent.path).unwrap(), agent.plist());
+        assert_eq!(
+            launchctl.last().unwrap(),
+            format!("launchctl bootstrap gui/501 {}", agent.path.display())
+        );
+        assert_eq!(
+            agent.status_with(&launchctl).unwrap(),
+            ServiceStatus {
+                installed: true,
+                loaded: true
+            }
+        );
+
+        agent.uninstall_with(&launchctl, false).unwrap();
+        assert!(!agent.path.exists());
+        assert!(launchctl
+            .calls()
+            .contains(&"launchctl bootout gui/501/afpack.remount".to_string()));
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let unit = unit(Path::new("/home/me")).unit();
+        assert!(unit.contains("ExecStart=\"/opt/100%% \\\"R&D\\\"/bin/afpack\" remount --all\n"));
+        assert!(unit.contains("Environment=\"AFPACK_STATE_DIR=/home/me/state\"\n"));
+        assert!(unit.contains("Type=oneshot\nRemainAfterExit=yes\n"));
+        assert!(unit.ends_with("[Install]\nWantedBy=default.target\n"));
+    }
+
+    #[test]
+    fn test_systemd_install_and_uninstall() {
+        let dir = TempDir::new("systemd");
+        let unit = unit(&dir);
+        let systemctl = runner();
+        let argv = |args: &[&str]| -> Vec<String> {
+            ["systemctl", "--user"]
+                .iter()
+                .chain(args)
+                .map(|a| a.to_string())
+                .collect()
+        };
+
+        unit.install_with(&systemctl, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&unit.path).unwrap(), unit.unit());
+        assert_eq!(
+            systemctl.argv(),
+            vec![argv(&["daemon-reload"]), argv(&["enable", "--now", UNIT])]
+        );
+        assert_eq!(
+            unit.status_with(&systemctl).unwrap(),
+            ServiceStatus {
+                installed: true,
+                loaded: true
+            }
+        );
+        assert_eq!(
+            systemctl.argv().pop().unwrap(),
+            argv(&["is-active", "--quiet", UNIT])
+        );
+
+        unit.uninstall_with(&systemctl, false).unwrap();
+        assert!(!unit.path.exists());
+        assert!(systemctl
+            .argv()
+            .contains(&argv(&["disable", "--now", UNIT])));
+    }
+
+    #[test]
+    fn test_systemd_timer() {
+        let dir = TempDir::new("timer");
+        let unit = SystemdUnit {
+            name: MAINTAIN_UNIT.to_string(),
+            arguments: vec!["maintain".into()],
+            interval: Some(300),
+            path: dir.join(MAINTAIN_UNIT),
+            ..unit(&dir)
+        };
+        let service = unit.unit();
+        assert!(service.ends_with("/bin/afpack\" maintain\n"));
+        assert!(!service.contains("RemainAfterExit"));
+        assert!(unit
+            .timer()
+            .unwrap()
+            .contains("[Timer]\nOnStartupSec=300\nOnUnitActiveSec=300\n"));
+        assert!(unit.timer().unwrap().ends_with("WantedBy=timers.target\n"));
+
+        let systemctl = runner();
+        unit.install_with(&systemctl, false).unwrap();
+        assert!(dir.join("afpack-maintain.timer").exists());
+        assert_eq!(
+            systemctl.last().unwrap(),
+            "systemctl --user enable --now afpack-maintain.timer"
+        );
+        unit.uninstall_with(&systemctl, false).unwrap();
+        assert!(!dir.join("afpack-maintain.timer").exists());
+        assert!(!unit.path.exists());
+    }
+}
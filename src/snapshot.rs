@@ -0,0 +1,199 @@
+use crate::diskimage::{DiskImageError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MANIFEST: &str = "snapshots.json";
+
+/// A point-in-time copy of an image file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Unique id derived from the creation time, e.g. `20261018-131610`
+    pub id: String,
+    /// Creation time in seconds since the Unix epoch
+    pub created: u64,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+impl Snapshot {
+    pub fn file_name(&self) -> String {
+        format!("{}.asif", self.id)
+    }
+}
+
+/// Snapshots of one image, kept in `<image stem>.snapshots/` next to the image
+///
+/// Snapshots are clones of the image file: `std::fs::copy` uses clonefile on APFS
+/// and copy_file_range (reflink where supported) on Linux, so they only cost the
+/// blocks that later diverge.
+#[derive(Debug)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl SnapshotStore {
+    /// Directory holding the snapshots of `image`
+    pub fn dir_for<P: AsRef<Path>>(image: P) -> PathBuf {
+        image.as_ref().with_extension("snapshots")
+    }
+
+    /// Open the snapshot store of `image`, empty if none were taken yet
+    pub fn open<P: AsRef<Path>>(image: P) -> Result<Self> {
+        let dir = Self::dir_for(image);
+        let manifest = dir.join(MANIFEST);
+        let snapshots = if manifest.exists() {
+            let data = std::fs::read_to_string(&manifest)?;
+            serde_json::from_str(&data)
+                .map_err(|e| DiskImageError::Registry(format!("{}: {}", manifest.display(), e)))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { dir, snapshots })
+    }
+
+    pub fn path(&self, snapshot: &Snapshot) -> PathBuf {
+        self.dir.join(snapshot.file_name())
+    }
+
+    /// Clone `image` into a new snapshot
+    pub fn create<P: AsRef<Path>>(&mut self, image: P, label: Option<&str>) -> Result<Snapshot> {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let base_id = format_timestamp(created)
+            .replace(['-', ':'], "")
+            .replace(' ', "-");
+        let mut id = base_id.clone();
+        let mut n = 1;
+        while self.snapshots.iter().any(|s| s.id == id) {
+            n += 1;
+            id = format!("{}.{}", base_id, n);
+        }
+
+        let snapshot = Snapshot {
+            id,
+            created,
+            label: label.map(str::to_string),
+        };
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::copy(image.as_ref(), self.path(&snapshot))?;
+        self.snapshots.push(snapshot.clone());
+        self.save()?;
+        Ok(snapshot)
+    }
+
+    /// Find a snapshot by id or label, the most recent one when `query` is `None`
+    pub fn find(&self, query: Option<&str>) -> Option<&Snapshot> {
+        match query {
+            None => self.snapshots.iter().max_by_key(|s| s.created),
+            Some(query) => self
+                .snapshots
+                .iter()
+                .rev()
+                .find(|s| s.id == query || s.label.as_deref() == Some(query)),
+        }
+    }
+
+    /// Replace `image` with a clone of `snapshot`, keeping the snapshot
+    pub fn restore<P: AsRef<Path>>(&self, snapshot: &Snapshot, image: P) -> Result<()> {
+        let image = image.as_ref();
+        let tmp = image.with_extension("asif.rollback");
+        std::fs::copy(self.path(snapshot), &tmp)?;
+        std::fs::rename(&tmp, image)?;
+        Ok(())
+    }
+
+    /// Delete all but the `keep` most recent snapshots, returning the deleted ones
+    ///
+    /// The most recent snapshot is always kept, even with `keep` 0.
+    pub fn prune(&mut self, keep: usize) -> Result<Vec<Snapshot>> {
+        self.snapshots.sort_by_key(|s| s.created);
+        let excess = self.snapshots.len().saturating_sub(keep.max(1));
+        let removed: Vec<Snapshot> = self.snapshots.drain(..excess).collect();
+        for snapshot in &removed {
+            let path = self.path(snapshot);
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        self.save()?;
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let data = serde_json::to_string_pretty(&self.snapshots)
+            .map_err(|e| DiskImageError::Registry(e.to_string()))?;
+        std::fs::write(self.dir.join(MANIFEST), data)?;
+        Ok(())
+    }
+}
+
+/// Format seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS` (UTC)
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Civil-from-days (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1792330570), "2026-10-18 13:36:10");
+    }
+
+    #[test]
+    fn test_create_restore_prune() {
+        let dir = TempDir::new("snapshot");
+        let image = dir.join("node_modules.asif");
+        std::fs::write(&image, b"v1").unwrap();
+
+        let mut store = SnapshotStore::open(&image).unwrap();
+        let first = store.create(&image, Some("before-upgrade")).unwrap();
+        std::fs::write(&image, b"v2").unwrap();
+        let second = store.create(&image, None).unwrap();
+        assert_ne!(first.id, second.id);
+
+        let store = SnapshotStore::open(&image).unwrap();
+        assert_eq!(store.snapshots.len(), 2);
+        let found = store.find(Some("before-upgrade")).unwrap();
+        store.restore(found, &image).unwrap();
+        assert_eq!(std::fs::read(&image).unwrap(), b"v1");
+
+        let mut store = SnapshotStore::open(&image).unwrap();
+        let removed = store.prune(1).unwrap();
+        assert_eq!(removed, vec![first]);
+        assert_eq!(store.find(None), Some(&second));
+        assert!(store.prune(0).unwrap().is_empty());
+        assert!(store.path(&second).exists());
+    }
+}
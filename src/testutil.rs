@@ -0,0 +1,113 @@
+//! Fixtures shared by the unit tests
+
+use crate::diskimage::{CommandRunner, Result};
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// Empty scratch directory under the system temp dir, removed on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `afpack-<name>-<pid>`, wiped if a previous run left it behind
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("afpack-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+type Reply = Box<dyn Fn(&str, &[String], Option<&str>) -> Result<String>>;
+
+/// Records every command instead of running it, answering with empty output
+/// unless built with `replying`
+pub struct FakeRunner {
+    calls: RefCell<Vec<(Vec<String>, Option<String>)>>,
+    reply: Reply,
+}
+
+impl Default for FakeRunner {
+    fn default() -> Self {
+        Self::replying(|_, _, _| Ok(String::new()))
+    }
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers each command with `reply(program, args, stdin)`
+    pub fn replying(
+        reply: impl Fn(&str, &[String], Option<&str>) -> Result<String> + 'static,
+    ) -> Self {
+        FakeRunner {
+            calls: RefCell::new(Vec::new()),
+            reply: Box::new(reply),
+        }
+    }
+
+    /// Every command so far, program first
+    pub fn argv(&self) -> Vec<Vec<String>> {
+        self.calls
+            .borrow()
+            .iter()
+            .map(|(argv, _)| argv.clone())
+            .collect()
+    }
+
+    /// Every command so far as a single line
+    pub fn calls(&self) -> Vec<String> {
+        self.argv().iter().map(|argv| argv.join(" ")).collect()
+    }
+
+    /// The most recent command as a single line
+    pub fn last(&self) -> Option<String> {
+        self.calls().pop()
+    }
+
+    /// What each command so far was given on stdin
+    pub fn stdin(&self) -> Vec<Option<String>> {
+        self.calls
+            .borrow()
+            .iter()
+            .map(|(_, stdin)| stdin.clone())
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.calls.borrow_mut().clear();
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run(&self, program: &str, args: &[String], stdin: Option<&str>) -> Result<String> {
+        let mut argv = vec![program.to_string()];
+        argv.extend(args.iter().cloned());
+        self.calls
+            .borrow_mut()
+            .push((argv, stdin.map(str::to_string)));
+        (self.reply)(program, args, stdin)
+    }
+}
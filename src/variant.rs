@@ -0,0 +1,198 @@
+use crate::diskimage::{DiskImageError, Result};
+use crate::ecosystem::{project_dir, Ecosystem};
+use crate::git;
+use crate::registry::VariantRecord;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// How many commits to walk back when looking for an ancestor lockfile image
+const ANCESTOR_SEARCH_DEPTH: usize = 200;
+
+/// What selects the image attached at an artifact directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VariantBy {
+    /// One image per git branch
+    #[default]
+    Branch,
+    /// One image per lockfile content hash
+    Lockfile,
+}
+
+impl std::fmt::Display for VariantBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VariantBy::Branch => write!(f, "branch"),
+            VariantBy::Lockfile => write!(f, "lockfile"),
+        }
+    }
+}
+
+/// Image selected for the current checkout
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    /// Filesystem-safe key used in the image file name
+    pub key: String,
+    /// Human readable origin of the key (branch name or lockfile hash)
+    pub label: String,
+}
+
+impl Variant {
+    /// Resolve the variant for the current checkout of `afdir`
+    pub fn current<P: AsRef<Path>>(afdir: P, by: VariantBy) -> Result<Self> {
+        let afdir = afdir.as_ref();
+        match by {
+            VariantBy::Branch => {
+                let branch = git::current_branch(afdir)?
+                    .ok_or_else(|| DiskImageError::DetachedHead(project_dir(afdir)))?;
+                Ok(Variant {
+                    key: sanitize_key(&branch),
+                    label: branch,
+                })
+            }
+            VariantBy::Lockfile => {
+                let ecosystem = Ecosystem::detect(afdir);
+                let mut contents = Vec::new();
+                for lockfile in ecosystem.find_lockfiles(afdir) {
+                    contents.extend(std::fs::read(&lockfile)?);
+                }
+                if contents.is_empty() {
+                    return Err(DiskImageError::InvalidPath(format!(
+                        "no {} lockfile next to {}",
+                        ecosystem,
+                        afdir.display()
+                    )));
+                }
+                let hash = lockfile_hash(&contents);
+                Ok(Variant {
+                    key: hash.clone(),
+                    label: hash,
+                })
+            }
+        }
+    }
+}
+
+/// Image path of a variant: `node_modules` + `main` -> `node_modules@main.asif`
+pub fn image_path<P: AsRef<Path>>(afdir: P, key: &str) -> PathBuf {
+    let afdir = afdir.as_ref();
+    let name = afdir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    afdir.with_file_name(format!("{}@{}.asif", name, key))
+}
+
+/// Find the existing variant image closest to the current checkout
+pub fn nearest_ancestor<P: AsRef<Path>>(
+    afdir: P,
+    by: VariantBy,
+    known: &BTreeMap<String, VariantRecord>,
+) -> Result<Option<PathBuf>> {
+    let afdir = afdir.as_ref();
+    match by {
+        VariantBy::Branch => {
+            let mut best: Option<(usize, &Path)> = None;
+            for record in known.values().filter(|r| r.image.exists()) {
+                let Some(distance) = git::distance_from_head(afdir, &record.label)? else {
+                    continue;
+                };
+                if best.is_none_or(|(d, _)| distance < d) {
+                    best = Some((distance, &record.image));
+                }
+            }
+            Ok(best.map(|(_, image)| image.to_path_buf()))
+        }
+        VariantBy::Lockfile => {
+            let ecosystem = Ecosystem::detect(afdir);
+            let lockfiles = ecosystem.find_lockfiles(afdir);
+            for rev in git::rev_list(afdir, ANCESTOR_SEARCH_DEPTH)? {
+                let mut contents = Vec::new();
+                for lockfile in &lockfiles {
+                    if let Some(data) = git::show_file(afdir, &rev, lockfile)? {
+                        contents.extend(data);
+                    }
+                }
+                if contents.is_empty() {
+                    continue;
+                }
+                if let Some(record) = known.get(&lockfile_hash(&contents)) {
+                    if record.image.exists() {
+                        return Ok(Some(record.image.clone()));
+                    }
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Make a branch name usable in a file name
+///
+/// Names that had to be changed get a hash of the original, so that
+/// `feature/a` and `feature-a` do not share an image.
+pub fn sanitize_key(name: &str) -> String {
+    let key: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    if key == name {
+        key
+    } else {
+        format!("{}-{:08x}", key, fnv1a(name.as_bytes()) >> 32)
+    }
+}
+
+/// Stable short hash of lockfile contents
+pub fn lockfile_hash(data: &[u8]) -> String {
+    format!("{:016x}", fnv1a(data))
+}
+
+/// FNV-1a, 64 bit
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_key() {
+        assert_eq!(sanitize_key("main"), "main");
+        assert_eq!(sanitize_key("v1.2_rc-1"), "v1.2_rc-1");
+        let key = sanitize_key("feature/login form");
+        assert!(key.starts_with("feature-login-form-"));
+        assert_eq!(key.len(), "feature-login-form-".len() + 8);
+        assert_eq!(sanitize_key("feature/a"), sanitize_key("feature/a"));
+        assert_ne!(sanitize_key("feature/a"), "feature-a");
+        assert_ne!(sanitize_key("feature/a"), sanitize_key("feature a"));
+    }
+
+    #[test]
+    fn test_image_path() {
+        assert_eq!(
+            image_path("web/node_modules", "main"),
+            PathBuf::from("web/node_modules@main.asif")
+        );
+    }
+
+    #[test]
+    fn test_lockfile_hash_is_stable() {
+        assert_eq!(lockfile_hash(b""), "cbf29ce484222325");
+        assert_eq!(lockfile_hash(b"a"), "af63dc4c8601ec8c");
+        assert_ne!(lockfile_hash(b"lock v1"), lockfile_hash(b"lock v2"));
+    }
+}
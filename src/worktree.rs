@@ -0,0 +1,117 @@
+use crate::diskimage::Result;
+use crate::ecosystem::project_dir;
+use crate::git;
+use crate::registry::Entry;
+use crate::variant;
+use std::path::{Path, PathBuf};
+
+/// Base image shared by every worktree for a lockfile hash
+///
+/// Lives in the common git dir: `.git/afpack/<afdir relative to worktree>@<key>.asif`.
+pub fn base_image_path<P: AsRef<Path>>(afdir: P, key: &str) -> Result<PathBuf> {
+    let afdir = afdir.as_ref();
+    let common = git::common_dir(afdir)?;
+    let relative = relative_to_worktree(afdir)?;
+    Ok(variant::image_path(
+        common.join("afpack").join(relative),
+        key,
+    ))
+}
+
+/// Shadow file private to the worktree containing `afdir`
+///
+/// Lives in the worktree git dir (`.git/worktrees/<name>/afpack/...`) so it never
+/// shows up in `git status`. The key is part of the name because a shadow is only
+/// valid on top of the base image it was created with.
+pub fn shadow_path<P: AsRef<Path>>(afdir: P, key: &str) -> Result<PathBuf> {
+    let afdir = afdir.as_ref();
+    let git_dir = git::git_dir(afdir)?;
+    let relative = relative_to_worktree(afdir)?;
+    Ok(variant::image_path(git_dir.join("afpack").join(relative), key).with_extension("shadow"))
+}
+
+fn relative_to_worktree(afdir: &Path) -> Result<PathBuf> {
+    let toplevel = git::toplevel(afdir)?;
+    Ok(afdir
+        .strip_prefix(&toplevel)
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|_| afdir.file_name().map(PathBuf::from).unwrap_or_default()))
+}
+
+/// Whether the worktree owning a registry entry still exists
+pub fn worktree_exists(entry: &Entry) -> bool {
+    project_dir(&entry.afdir).exists()
+}
+
+/// Shadow files that can be deleted without losing attached data
+///
+/// That is the shadows of worktrees that no longer exist, and shadows left next to
+/// a live one after its lockfile changed.
+pub fn stale_shadows(entries: &[Entry]) -> Vec<PathBuf> {
+    let mut stale = Vec::new();
+    for entry in entries {
+        let Some(shadow) = &entry.shadow else {
+            continue;
+        };
+        if !worktree_exists(entry) {
+            if shadow.exists() {
+                stale.push(shadow.clone());
+            }
+            continue;
+        }
+
+        let (Some(dir), Some(name)) = (shadow.parent(), entry.afdir.file_name()) else {
+            continue;
+        };
+        let prefix = format!("{}@", name.to_string_lossy());
+        let Ok(read_dir) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for file in read_dir.flatten() {
+            let path = file.path();
+            let file_name = file.file_name().to_string_lossy().to_string();
+            if file_name.starts_with(&prefix)
+                && path.extension().is_some_and(|ext| ext == "shadow")
+                && path != *shadow
+            {
+                stale.push(path);
+            }
+        }
+    }
+    stale.sort();
+    stale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_stale_shadows() {
+        let dir = TempDir::new("worktree");
+        let shadows = dir.join("git").join("afpack");
+        std::fs::create_dir_all(&shadows).unwrap();
+        std::fs::create_dir_all(dir.join("wt")).unwrap();
+        for name in [
+            "node_modules@new.shadow",
+            "node_modules@old.shadow",
+            "target@x.shadow",
+        ] {
+            std::fs::write(shadows.join(name), b"").unwrap();
+        }
+
+        let mut live = Entry::new(dir.join("wt/node_modules"), "base.asif", "10G");
+        live.shadow = Some(shadows.join("node_modules@new.shadow"));
+        let mut gone = Entry::new(dir.join("removed/target"), "base.asif", "10G");
+        gone.shadow = Some(shadows.join("target@x.shadow"));
+
+        assert_eq!(
+            stale_shadows(&[live, gone]),
+            vec![
+                shadows.join("node_modules@old.shadow"),
+                shadows.join("target@x.shadow"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use afpack::daemon::{Backend, Client, Daemon, Event};
+use afpack::registry::{Entry, Registry};
+use afpack::{CommandError, DiskImageError};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Backend keeping the set of mounted directories in memory
+#[derive(Clone, Default)]
+struct FakeBackend {
+    mounted: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Volumes with open files
+    busy: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Volumes whose open files cannot be listed
+    unreadable: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Backend for FakeBackend {
+    fn attach(&self, entry: &Entry) -> afpack::Result<()> {
+        if !entry.image.exists() {
+            return Err(DiskImageError::InvalidPath(
+                entry.image.display().to_string(),
+            ));
+        }
+        self.mounted.lock().unwrap().insert(entry.afdir.clone());
+        Ok(())
+    }
+
+    fn detach(&self, entry: &Entry) -> afpack::Result<()> {
+        self.mounted.lock().unwrap().remove(&entry.afdir);
+        Ok(())
+    }
+
+    fn is_mounted(&self, afdir: &Path) -> bool {
+        self.mounted.lock().unwrap().contains(afdir)
+    }
+
+    fn is_busy(&self, afdir: &Path) -> afpack::Result<bool> {
+        if self.unreadable.lock().unwrap().contains(afdir) {
+            return Err(CommandError::new("lsof", &[], None, "", "").into());
+        }
+        Ok(self.busy.lock().unwrap().contains(afdir))
+    }
+}
+
+struct Harness {
+    dir: PathBuf,
+    registry: PathBuf,
+    socket: PathBuf,
+    backend: FakeBackend,
+    daemon: Arc<Daemon>,
+}
+
+impl Harness {
+    fn start(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("afpackd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let registry = dir.join("registry.json");
+        let mut entries = Registry::default();
+        for name in ["node_modules", "target"] {
+            let image = dir.join(format!("{}.asif", name));
+            std::fs::write(&image, b"").unwrap();
+            entries.upsert(Entry::new(dir.join(name), image, "10G"));
+        }
+        entries.save_to(&registry).unwrap();
+
+        let backend = FakeBackend::default();
+        let daemon = Arc::new(
+            Daemon::new(&registry, Box::new(backend.clone()))
+                .with_event_timeout(Duration::from_millis(100)),
+        );
+        let socket = dir.join("afpackd.sock");
+        let listener = Daemon::bind(&socket).unwrap();
+        let server = Arc::clone(&daemon);
+        std::thread::spawn(move || server.serve(listener));
+        Self {
+            dir,
+            registry,
+            socket,
+            backend,
+            daemon,
+        }
+    }
+
+    fn client(&self) -> Client {
+        let client = Client::connect(&self.socket).unwrap();
+        client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        client
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn attach_detach_and_status() {
+    let harness = Harness::start("attach");
+    let afdir = harness.dir.join("node_modules");
+    let mut client = harness.client();
+
+    client.attach(&afdir).unwrap();
+    assert!(harness.backend.is_mounted(&afdir));
+    let registry = Registry::load_from(&harness.registry).unwrap();
+    assert!(registry.get(&afdir).unwrap().attached);
+
+    let status = client.status(None).unwrap();
+    assert_eq!(status.len(), 2);
+    let status = client.status(Some(&afdir)).unwrap();
+    assert!(status[0].attached && status[0].mounted);
+
+    client.detach(&afdir).unwrap();
+    assert!(!harness.backend.is_mounted(&afdir));
+    let registry = Registry::load_from(&harness.registry).unwrap();
+    assert!(!registry.get(&afdir).unwrap().attached);
+
+    let err = client.attach(&harness.dir.join("vendor")).unwrap_err();
+    assert!(err.to_string().contains("not managed"), "{}", err);
+    let err = client.call("format", serde_json::Value::Null).unwrap_err();
+    assert!(err.to_string().contains("unknown method"), "{}", err);
+}
+
+#[test]
+fn events_and_remount() {
+    let harness = Harness::start("events");
+    let afdir = harness.dir.join("target");
+    let mut subscriber = harness.client();
+    subscriber.subscribe().unwrap();
+
+    let mut client = harness.client();
+    client.attach(&afdir).unwrap();
+    assert_eq!(
+        subscriber.next_event().unwrap(),
+        Event::Attached {
+            afdir: afdir.clone()
+        }
+    );
+
+    // The volume disappears behind the daemon's back, e.g. across sleep
+    harness.backend.mounted.lock().unwrap().clear();
+    harness.daemon.check().unwrap();
+    assert_eq!(
+        subscriber.next_event().unwrap(),
+        Event::Vanished {
+            afdir: afdir.clone()
+        }
+    );
+    assert_eq!(
+        subscriber.next_event().unwrap(),
+        Event::Remounted {
+            afdir: afdir.clone()
+        }
+    );
+    assert!(harness.backend.is_mounted(&afdir));
+
+    // A missing image cannot be remounted and is reported
+    harness.backend.mounted.lock().unwrap().clear();
+    std::fs::remove_file(harness.dir.join("target.asif")).unwrap();
+    harness.daemon.check().unwrap();
+    assert!(matches!(
+        subscriber.next_event().unwrap(),
+        Event::Vanished { .. }
+    ));
+    assert!(matches!(
+        subscriber.next_event().unwrap(),
+        Event::Failed { .. }
+    ));
+}
+
+#[test]
+fn stalled_subscriber_is_dropped() {
+    let harness = Harness::start("stalled");
+    let afdir = harness.dir.join("node_modules");
+    let mut stalled = UnixStream::connect(&harness.socket).unwrap();
+    stalled
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"subscribe\"}\n")
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(stalled.try_clone().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert!(line.contains("subscribed"), "{}", line);
+
+    // Enough events to fill the socket buffer of a subscriber that never reads
+    let mut client = harness.client();
+    for _ in 0..2000 {
+        client.attach(&afdir).unwrap();
+        client.detach(&afdir).unwrap();
+    }
+    assert!(!client.status(Some(&afdir)).unwrap()[0].attached);
+}
+
+#[test]
+fn refuses_second_daemon() {
+    let harness = Harness::start("bind");
+    assert!(Daemon::bind(&harness.socket).is_err());
+}
+
+#[test]
+fn idle_detach() {
+    let harness = Harness::start("idle");
+    let afdir = harness.dir.join("node_modules");
+    let mut client = harness.client();
+    client.attach(&afdir).unwrap();
+
+    // Last used long ago, but a process still has files open
+    let mut registry = Registry::load_from(&harness.registry).unwrap();
+    let entry = registry.get_mut(&afdir).unwrap();
+    entry.detach_after = Some(60);
+    entry.last_used = Some(0);
+    registry.save_to(&harness.registry).unwrap();
+    harness.backend.busy.lock().unwrap().insert(afdir.clone());
+    harness.daemon.check().unwrap();
+    assert!(harness.backend.is_mounted(&afdir));
+
+    let mut registry = Registry::load_from(&harness.registry).unwrap();
+    registry.get_mut(&afdir).unwrap().last_used = Some(0);
+    registry.save_to(&harness.registry).unwrap();
+    harness.backend.busy.lock().unwrap().clear();
+    harness.daemon.check().unwrap();
+    assert!(!harness.backend.is_mounted(&afdir));
+    let registry = Registry::load_from(&harness.registry).unwrap();
+    let entry = registry.get(&afdir).unwrap();
+    assert!(!entry.attached && entry.idle_detached);
+}
+
+#[test]
+fn failing_entry_does_not_stop_check() {
+    let harness = Harness::start("failing");
+    let node_modules = harness.dir.join("node_modules");
+    let target = harness.dir.join("target");
+    let mut subscriber = harness.client();
+    subscriber.subscribe().unwrap();
+    let mut client = harness.client();
+    client.attach(&node_modules).unwrap();
+    client.attach(&target).unwrap();
+
+    let mut registry = Registry::load_from(&harness.registry).unwrap();
+    for entry in &mut registry.entries {
+        entry.detach_after = Some(60);
+        entry.last_used = Some(0);
+    }
+    registry.save_to(&harness.registry).unwrap();
+    harness
+        .backend
+        .unreadable
+        .lock()
+        .unwrap()
+        .insert(node_modules.clone());
+    harness.daemon.check().unwrap();
+
+    let events: Vec<Event> = (0..4).map(|_| subscriber.next_event().unwrap()).collect();
+    assert!(events.contains(&Event::Failed {
+        afdir: node_modules.clone(),
+        message: "Command failed: `lsof` was killed".into()
+    }));
+    assert!(events.contains(&Event::IdleDetached {
+        afdir: target.clone()
+    }));
+    let registry = Registry::load_from(&harness.registry).unwrap();
+    assert!(registry.get(&node_modules).unwrap().attached);
+    assert!(registry.get(&target).unwrap().idle_detached);
+}